@@ -0,0 +1,31 @@
+/// One bit per piece, highest bit of the first byte is piece 0 (same layout as the `bitfield` message)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitfield(pub Vec<u8>);
+
+impl Bitfield {
+    pub fn new(pieces: usize) -> Self {
+        Self(vec![0; pieces.div_ceil(8)])
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        self.0
+            .get(index / 8)
+            .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+    }
+
+    pub fn set(&mut self, index: usize) {
+        if let Some(byte) = self.0.get_mut(index / 8) {
+            *byte |= 0x80 >> (index % 8);
+        }
+    }
+
+    pub fn unset(&mut self, index: usize) {
+        if let Some(byte) = self.0.get_mut(index / 8) {
+            *byte &= !(0x80 >> (index % 8));
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+}
@@ -0,0 +1,130 @@
+use std::{path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+use anyhow::{Context, Result};
+use sha1::{Digest, Sha1};
+use crate::{torrent::{File, Info, Keys, PieceHashes, Torrent, UrlList}, verify::{in_parallel, ContentReader}};
+
+
+const PIECE_LENGTH_MIN: usize = 1 << 14;
+const PIECE_LENGTH_MAX: usize = 1 << 24;
+
+/// Automatic piece length aims for about this many pieces
+const PIECES_TARGET: usize = 1500;
+
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    /// Tracker URLs, each one in its own tier. The first one also goes into `announce`
+    pub trackers: Vec<String>,
+
+    /// Piece length in bytes, picked from the total size when `None`
+    pub piece_length: Option<usize>,
+
+    pub private: bool,
+
+    pub comment: Option<String>,
+
+    pub webseeds: Vec<String>,
+}
+
+/// Power of two between 16 KiB and 16 MiB that splits `total_length` into roughly 1500 pieces
+pub fn auto_piece_length(total_length: usize) -> usize {
+    (total_length / PIECES_TARGET)
+        .next_power_of_two()
+        .clamp(PIECE_LENGTH_MIN, PIECE_LENGTH_MAX)
+}
+
+/// Builds a v1 metainfo for a file or a directory, hashing pieces on all CPU cores
+pub fn create_torrent(path: &Path, options: &CreateOptions) -> Result<Torrent> {
+    let announce = options.trackers.first().context("at least one tracker URL is required")?.clone();
+    let name = path
+        .canonicalize()
+        .context(format!("resolve {}", path.display()))?
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context("path must end with a UTF-8 file or directory name")?
+        .to_string();
+
+    // Files are read from where they are, not from the sanitised paths they would be downloaded to
+    let metadata = std::fs::metadata(path).context(format!("read metadata of {}", path.display()))?;
+    let (keys, locations) = if metadata.is_dir() {
+        let walked = walk(path)?;
+        let files = walked
+            .iter()
+            .map(|(relative, length)| {
+                let path = relative
+                    .components()
+                    .map(|part| part.as_os_str().to_str().map(String::from).context("file names must be UTF-8"))
+                    .collect::<Result<Vec<_>>>()?;
+
+                Ok(File { length: *length, path })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        (Keys::MultiFile { files }, walked.into_iter().map(|(relative, _)| path.join(relative)).collect())
+    } else {
+        (Keys::SingleFile { length: metadata.len() as usize }, vec![path.to_path_buf()])
+    };
+
+    let mut torrent = Torrent {
+        announce,
+        announce_list: Some(options.trackers.iter().map(|tracker| vec![tracker.clone()]).collect()),
+        creation_date: Some(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64),
+        created_by: Some(format!("bittorrent/{}", env!("CARGO_PKG_VERSION"))),
+        comment: options.comment.clone(),
+        url_list: (!options.webseeds.is_empty()).then(|| UrlList::Many(options.webseeds.clone())),
+        info: Info {
+            name,
+            piece_length: 0,
+            pieces: PieceHashes(Vec::new()),
+            private: options.private.then_some(1),
+            keys,
+        },
+    };
+
+    let total_length = torrent.file_length();
+    anyhow::ensure!(total_length > 0, "there is nothing to hash, {} is empty", path.display());
+
+    let piece_length = options.piece_length.unwrap_or_else(|| auto_piece_length(total_length));
+    anyhow::ensure!(piece_length.is_power_of_two() && piece_length >= PIECE_LENGTH_MIN, "piece length must be a power of two and at least 16 KiB");
+
+    // Placeholder hashes give `ContentReader` the piece count to size the last piece
+    torrent.info.piece_length = piece_length;
+    torrent.info.pieces = PieceHashes(vec![[0; 20]; total_length.div_ceil(piece_length)]);
+
+    let reader = ContentReader::at(&torrent, locations);
+    let pieces = (0..torrent.info.pieces.0.len()).collect::<Vec<_>>();
+    let hashes = in_parallel(&pieces, |index| {
+        let data = reader.read_piece(&torrent, index).context(format!("read piece {index}"))?;
+        Ok(<[u8; 20]>::from(Sha1::digest(&data)))
+    })?;
+
+    torrent.info.pieces = PieceHashes(hashes.into_iter().map(|(_, hash)| hash).collect());
+
+    Ok(torrent)
+}
+
+/// Every regular file under `directory` with its length, paths relative to `directory` in sorted order
+///
+/// Symbolic links are skipped, a link to a parent directory would make the walk go on forever
+fn walk(directory: &Path) -> Result<Vec<(PathBuf, usize)>> {
+    let mut files = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+
+    while let Some(relative) = pending.pop() {
+        let absolute = directory.join(&relative);
+        for entry in std::fs::read_dir(&absolute).context(format!("read directory {}", absolute.display()))? {
+            let entry = entry.context(format!("read entry of {}", absolute.display()))?;
+            let file_type = entry.file_type().context(format!("read file type of {}", entry.path().display()))?;
+
+            if file_type.is_dir() {
+                pending.push(relative.join(entry.file_name()));
+            } else if file_type.is_file() {
+                let metadata = entry.metadata().context(format!("read metadata of {}", entry.path().display()))?;
+                files.push((relative.join(entry.file_name()), metadata.len() as usize));
+            }
+        }
+    }
+
+    files.sort();
+
+    Ok(files)
+}
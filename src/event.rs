@@ -0,0 +1,65 @@
+use std::net::SocketAddr;
+use tokio::sync::broadcast;
+use crate::{peer_id::Client, session::TorrentState};
+
+
+/// Events kept for subscribers that fall behind, older ones are skipped
+pub const CAPACITY: usize = 1024;
+
+/// Something that happened to a torrent of a session
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub info_hash: [u8; 20],
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventKind {
+    StateChanged(TorrentState),
+
+    /// Storage check found `verified` of `wanted` selected pieces on disk
+    Checked { verified: usize, wanted: usize },
+
+    /// Handshake with the peer succeeded, either side may have connected
+    PeerConnected { address: SocketAddr, client: Option<Client> },
+
+    /// Connection ended, with the reason if it failed
+    PeerDisconnected { address: SocketAddr, error: Option<String> },
+
+    /// Peer misbehaved, its IP is refused by the torrent from now on
+    PeerBanned { address: SocketAddr, reason: String },
+
+    PieceVerified(usize),
+
+    /// Piece didn't match its hash and will be downloaded again
+    PieceFailed(usize),
+
+    /// Tracker answered with this many peers
+    Announced { peers: usize },
+
+    AnnounceFailed(String),
+
+    /// Every selected piece is verified
+    Finished,
+
+    /// Torrent stopped because of the error, until it is resumed
+    Error(String),
+}
+
+/// Sends events of one torrent to every subscriber of its session
+#[derive(Debug, Clone)]
+pub struct Events {
+    info_hash: [u8; 20],
+    sender: broadcast::Sender<Event>,
+}
+
+impl Events {
+    pub fn new(info_hash: [u8; 20], sender: broadcast::Sender<Event>) -> Self {
+        Self { info_hash, sender }
+    }
+
+    /// Events nobody is subscribed to are dropped
+    pub fn send(&self, kind: EventKind) {
+        let _ = self.sender.send(Event { info_hash: self.info_hash, kind });
+    }
+}
@@ -0,0 +1,147 @@
+use std::{collections::BTreeSet, ops::Range, path::PathBuf, str::FromStr};
+use anyhow::{Context, Result};
+use regex::Regex;
+use crate::torrent::{Keys, Torrent};
+
+
+/// A single file of the torrent, placed within the concatenated byte stream of all files
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    /// Position of the file in the `files` list (always 0 for single file torrents)
+    pub index: usize,
+
+    /// Path relative to the download directory, starting with `info.name`
+    pub path: PathBuf,
+
+    /// Path inside the torrent, `/` separated, without `info.name` for multi-file torrents
+    pub torrent_path: String,
+
+    /// Length of the file in bytes
+    pub length: usize,
+
+    /// Offset of the first byte of the file within the torrent
+    pub offset: usize,
+}
+
+impl FileEntry {
+    pub fn end(&self) -> usize {
+        self.offset + self.length
+    }
+
+    /// Indexes of all pieces that contain at least one byte of this file
+    pub fn pieces(&self, piece_length: usize) -> Range<usize> {
+        if self.length == 0 {
+            return 0..0;
+        }
+
+        (self.offset / piece_length)..((self.end() - 1) / piece_length + 1)
+    }
+}
+
+impl Torrent {
+    /// Files of the torrent in the order they are concatenated into pieces
+    ///
+    /// [`FileEntry::path`] is always safe to join to the download directory, see [`Torrent::safe_paths`]
+    pub fn files(&self) -> Vec<FileEntry> {
+        let paths = self.safe_paths().into_iter().map(|(path, _)| path);
+
+        match &self.info.keys {
+            Keys::SingleFile { length } => paths
+                .map(|path| FileEntry {
+                    index: 0,
+                    path,
+                    torrent_path: self.info.name.clone(),
+                    length: *length,
+                    offset: 0,
+                })
+                .collect(),
+
+            Keys::MultiFile { files } => {
+                let mut offset = 0;
+                files
+                    .iter()
+                    .zip(paths)
+                    .enumerate()
+                    .map(|(index, (file, path))| {
+                        let entry = FileEntry {
+                            index,
+                            path,
+                            torrent_path: file.path.join("/"),
+                            length: file.length,
+                            offset,
+                        };
+                        offset += file.length;
+                        entry
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Indexes of pieces that have to be downloaded to get every selected file
+    pub fn wanted_pieces(&self, selection: &FileSelection) -> BTreeSet<usize> {
+        self.files()
+            .iter()
+            .filter(|file| selection.selects(file))
+            .flat_map(|file| file.pieces(self.info.piece_length))
+            .collect()
+    }
+}
+
+/// Picks a file either by its index in the file list or by a glob pattern over its path
+///
+/// Patterns without a `/` are matched against the file name only, others against the whole
+/// path inside the torrent. `*` and `?` never match `/`, `**` matches anything
+#[derive(Debug, Clone)]
+pub enum FileSelector {
+    Index(usize),
+    Glob(Regex),
+}
+
+impl FileSelector {
+    pub fn matches(&self, file: &FileEntry) -> bool {
+        match self {
+            FileSelector::Index(index) => file.index == *index,
+            FileSelector::Glob(pattern) => pattern.is_match(&file.torrent_path),
+        }
+    }
+}
+
+impl FromStr for FileSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        if let Ok(index) = value.parse::<usize>() {
+            return Ok(FileSelector::Index(index));
+        }
+
+        // Patterns of file names may be preceded by any directories
+        let mut pattern = String::from(if value.contains('/') { "^" } else { "^(.*/)?" });
+        let mut chars = value.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    pattern.push_str(".*");
+                }
+                '*' => pattern.push_str("[^/]*"),
+                '?' => pattern.push_str("[^/]"),
+                c => pattern.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        pattern.push('$');
+
+        let pattern = Regex::new(&pattern).context(format!("compile file pattern {value:?}"))?;
+        Ok(FileSelector::Glob(pattern))
+    }
+}
+
+/// Set of files to download. Empty selection means every file
+#[derive(Debug, Clone, Default)]
+pub struct FileSelection(pub Vec<FileSelector>);
+
+impl FileSelection {
+    pub fn selects(&self, file: &FileEntry) -> bool {
+        self.0.is_empty() || self.0.iter().any(|selector| selector.matches(file))
+    }
+}
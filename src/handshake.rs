@@ -1,6 +1,6 @@
 use anyhow::{Context, Result};
 use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
-use crate::torrent::Torrent;
+use crate::{peer_id::PeerId, torrent::Torrent};
 
 
 #[repr(C)]
@@ -14,13 +14,13 @@ pub struct Handshake {
 }
 
 impl Handshake {
-    pub fn new(torrent: &Torrent) -> Result<Self> {
+    pub fn new(torrent: &Torrent, peer_id: PeerId) -> Result<Self> {
         Ok(Self {
             length: 19,
             bittorrent: *b"BitTorrent protocol",
             reserved: [0; 8],
             info_hash: torrent.info_hash()?,
-            peer_id: torrent.peer_id(),
+            peer_id: peer_id.0,
         })
     }
 
@@ -40,6 +40,31 @@ impl Handshake {
         Ok(self)
     }
 
+    /// Reads the handshake of a peer that connected to us, before replying with ours
+    pub async fn receive(socket: &mut TcpStream) -> Result<Self> {
+        let mut handshake = Self {
+            length: 0,
+            bittorrent: [0; 19],
+            reserved: [0; 8],
+            info_hash: [0; 20],
+            peer_id: [0; 20],
+        };
+
+        socket
+            .read_exact(handshake.as_bytes_mut())
+            .await
+            .context("read handshake from socket")?;
+
+        Ok(handshake)
+    }
+
+    pub async fn send(mut self, socket: &mut TcpStream) -> Result<()> {
+        socket
+            .write_all(self.as_bytes_mut())
+            .await
+            .context("write handshake to socket")
+    }
+
     fn as_bytes_mut(&mut self) -> &mut [u8] {
         let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
         let bytes: &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *bytes };
@@ -3,4 +3,22 @@ pub mod tracker;
 pub mod handshake;
 pub mod message;
 pub mod piece;
-pub mod peer_connection;
\ No newline at end of file
+pub mod peer_connection;
+pub mod peer_id;
+pub mod picker;
+pub mod files;
+pub mod paths;
+pub mod validate;
+pub mod bitfield;
+pub mod resume;
+pub mod verify;
+pub mod create;
+pub mod storage;
+pub mod session;
+pub mod event;
+pub mod progress;
+pub mod transfer;
+pub mod limit;
+pub mod swarm;
+pub mod smart_ban;
+pub mod lsd;
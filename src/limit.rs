@@ -0,0 +1,166 @@
+use std::{future::Future, io, pin::Pin, sync::{Arc, Mutex}, task::{ready, Context, Poll}, time::{Duration, Instant}};
+use tokio::{io::{AsyncRead, AsyncWrite, ReadBuf}, time::Sleep};
+
+
+/// Token bucket limiting bytes per second, shared by every connection it applies to.
+/// The rate can be changed while connections use it
+///
+/// Transfers take tokens after the fact and may leave the bucket in debt, connections
+/// then wait until it is paid back. Everybody waits for the same bucket, so peers get
+/// roughly equal shares of the rate
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes per second, unlimited when `None`
+    rate: Option<usize>,
+
+    /// Bytes that can be transferred right away, at most one second worth of the rate
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<usize>) -> Self {
+        let tokens = rate.unwrap_or_default() as f64;
+        Self { bucket: Mutex::new(Bucket { rate, tokens, refilled: Instant::now() }) }
+    }
+
+    pub fn rate(&self) -> Option<usize> {
+        self.lock().rate
+    }
+
+    /// Bytes per second from now on, `None` removes the limit
+    pub fn set_rate(&self, rate: Option<usize>) {
+        let mut bucket = self.lock();
+        bucket.refill();
+        bucket.rate = rate;
+        bucket.tokens = bucket.tokens.min(rate.unwrap_or_default() as f64);
+    }
+
+    /// Takes `bytes` that were just transferred out of the bucket
+    pub fn consume(&self, bytes: usize) {
+        let mut bucket = self.lock();
+        if bucket.rate.is_some() {
+            bucket.refill();
+            bucket.tokens -= bytes as f64;
+        }
+    }
+
+    /// How long to wait until the bucket is out of debt
+    pub fn delay(&self) -> Duration {
+        let mut bucket = self.lock();
+        let Some(rate) = bucket.rate else {
+            return Duration::ZERO;
+        };
+
+        bucket.refill();
+        match bucket.tokens {
+            tokens if tokens >= 0.0 => Duration::ZERO,
+            _ if rate == 0 => Duration::MAX,
+            tokens => Duration::from_secs_f64(-tokens / rate as f64),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Bucket> {
+        self.bucket.lock().expect("rate limiter lock is poisoned")
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let rate = self.rate.unwrap_or_default() as f64;
+        self.tokens = (self.tokens + now.duration_since(self.refilled).as_secs_f64() * rate).min(rate);
+        self.refilled = now;
+    }
+}
+
+/// Rate limiters a connection is subject to, typically the session and its torrent
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    pub download: Vec<Arc<RateLimiter>>,
+    pub upload: Vec<Arc<RateLimiter>>,
+}
+
+/// Stream that reads and writes no faster than its [`Limits`] allow
+pub struct Throttled<S> {
+    inner: S,
+    limits: Limits,
+    read_wait: Option<Pin<Box<Sleep>>>,
+    write_wait: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, limits: Limits) -> Self {
+        Self { inner, limits, read_wait: None, write_wait: None }
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(poll_wait(&mut this.read_wait, &this.limits.download, cx));
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        consume(&this.limits.download, buf.filled().len() - filled);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(poll_wait(&mut this.write_wait, &this.limits.upload, cx));
+
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        consume(&this.limits.upload, written);
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Ready once none of `limiters` is in debt
+fn poll_wait(wait: &mut Option<Pin<Box<Sleep>>>, limiters: &[Arc<RateLimiter>], cx: &mut Context<'_>) -> Poll<()> {
+    loop {
+        if let Some(sleep) = wait {
+            ready!(sleep.as_mut().poll(cx));
+            *wait = None;
+        }
+
+        let delay = limiters.iter().map(|limiter| limiter.delay()).max().unwrap_or_default();
+        if delay.is_zero() {
+            return Poll::Ready(());
+        }
+
+        // A limit of zero pauses the transfer, checked again now and then in case it is raised
+        *wait = Some(Box::pin(tokio::time::sleep(delay.min(Duration::from_secs(1)))));
+    }
+}
+
+fn consume(limiters: &[Arc<RateLimiter>], bytes: usize) {
+    for limiter in limiters {
+        limiter.consume(bytes);
+    }
+}
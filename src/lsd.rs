@@ -0,0 +1,198 @@
+use std::{net::{Ipv4Addr, Ipv6Addr, SocketAddr}, str::FromStr};
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+
+/// Multicast groups of Local Service Discovery, see BEP 14
+pub const IPV4_GROUP: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub const IPV6_GROUP: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+pub const PORT: u16 = 6771;
+
+/// Info hashes sent in one message, keeps it well below the MTU
+const INFO_HASHES_MAX: usize = 20;
+
+/// Largest message accepted
+const MESSAGE_MAX: usize = 1400;
+
+/// `BT-SEARCH` message a peer multicasts to tell which torrents it has on which port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LsdAnnounce {
+    /// Port the peer accepts connections on
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+
+    /// Lets a peer recognize its own announces coming back
+    pub cookie: Option<String>,
+}
+
+impl LsdAnnounce {
+    /// Message for the multicast group `host`
+    pub fn to_bytes(&self, host: SocketAddr) -> Vec<u8> {
+        let mut message = format!("BT-SEARCH * HTTP/1.1\r\nHost: {host}\r\nPort: {}\r\n", self.port);
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {cookie}\r\n"));
+        }
+        message.push_str("\r\n\r\n");
+
+        message.into_bytes()
+    }
+
+    /// Header names are case insensitive, unknown headers and invalid info hashes are skipped
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let message = std::str::from_utf8(bytes).context("message is not text")?;
+        let mut lines = message.split("\r\n");
+        anyhow::ensure!(lines.next() == Some("BT-SEARCH * HTTP/1.1"), "not a BT-SEARCH message");
+
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = Some(u16::from_str(value).context("invalid port")?),
+                "infohash" => info_hashes.extend(hex::decode(value).ok().and_then(|info_hash| <[u8; 20]>::try_from(info_hash).ok())),
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        let port = port.filter(|&port| port != 0).context("message has no port")?;
+        anyhow::ensure!(!info_hashes.is_empty(), "message has no info hash");
+
+        Ok(Self { port, info_hashes, cookie })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LsdOptions {
+    /// Interface to multicast on, the system picks one when unspecified
+    pub interface: Ipv4Addr,
+
+    /// Also use the IPv6 group, on every interface
+    pub ipv6: bool,
+
+    /// Port of the multicast groups, only differs from [`PORT`] to keep tests apart
+    pub port: u16,
+}
+
+impl Default for LsdOptions {
+    fn default() -> Self {
+        Self { interface: Ipv4Addr::UNSPECIFIED, ipv6: true, port: PORT }
+    }
+}
+
+/// Member of the Local Service Discovery groups, announcing torrents to peers on the
+/// same network and hearing about theirs
+pub struct Lsd {
+    v4: UdpSocket,
+
+    /// Unless IPv6 is turned off or unavailable
+    v6: Option<UdpSocket>,
+
+    port: u16,
+    cookie: String,
+}
+
+impl Lsd {
+    /// Joins the groups. Other programs can listen on the same port, a missing IPv6
+    /// group is only logged
+    pub fn bind(options: &LsdOptions) -> Result<Self> {
+        let v4 = multicast_socket(SocketAddr::from((Ipv4Addr::UNSPECIFIED, options.port)))?;
+        v4.join_multicast_v4(IPV4_GROUP, options.interface).context("join IPv4 multicast group")?;
+        socket2::SockRef::from(&v4).set_multicast_if_v4(&options.interface).context("pick multicast interface")?;
+
+        let v6 = match options.ipv6 {
+            true => multicast_socket(SocketAddr::from((Ipv6Addr::UNSPECIFIED, options.port)))
+                .and_then(|v6| {
+                    v6.join_multicast_v6(&IPV6_GROUP, 0).context("join IPv6 multicast group")?;
+                    Ok(v6)
+                })
+                .inspect_err(|error| tracing::debug!("local service discovery without IPv6: {error:#}"))
+                .ok(),
+            false => None,
+        };
+
+        let cookie = format!("{:08x}", fastrand::u32(..));
+        Ok(Self { v4, v6, port: options.port, cookie })
+    }
+
+    /// Tells peers on the network that torrents with these info hashes are served on `port`
+    pub async fn announce(&self, port: u16, info_hashes: &[[u8; 20]]) -> Result<()> {
+        for info_hashes in info_hashes.chunks(INFO_HASHES_MAX) {
+            let announce = LsdAnnounce { port, info_hashes: info_hashes.to_vec(), cookie: Some(self.cookie.clone()) };
+
+            let group = SocketAddr::from((IPV4_GROUP, self.port));
+            self.v4.send_to(&announce.to_bytes(group), group).await.context("send announce to IPv4 group")?;
+
+            if let Some(v6) = &self.v6 {
+                let group = SocketAddr::from((IPV6_GROUP, self.port));
+                if let Err(error) = v6.send_to(&announce.to_bytes(group), group).await {
+                    tracing::debug!("send announce to IPv6 group: {error}");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Next announce of another peer, with the address it accepts connections on.
+    /// Our own announces and invalid messages are skipped
+    pub async fn recv(&self) -> Result<(SocketAddr, LsdAnnounce)> {
+        let mut v4_buffer = [0; MESSAGE_MAX];
+        let mut v6_buffer = [0; MESSAGE_MAX];
+
+        loop {
+            let (buffer, received) = tokio::select! {
+                received = self.v4.recv_from(&mut v4_buffer) => (&v4_buffer, received),
+                received = recv_from(self.v6.as_ref(), &mut v6_buffer) => (&v6_buffer, received),
+            };
+            let (length, source) = received.context("receive from multicast group")?;
+
+            let announce = match LsdAnnounce::parse(&buffer[..length]) {
+                Ok(announce) => announce,
+                Err(error) => {
+                    tracing::trace!(%source, "invalid local service discovery message: {error:#}");
+                    continue;
+                }
+            };
+
+            // Keeps the scope id, link-local IPv6 peers can't be dialled without it
+            if announce.cookie.as_ref() != Some(&self.cookie) {
+                let mut address = source;
+                address.set_port(announce.port);
+                return Ok((address, announce));
+            }
+        }
+    }
+}
+
+/// UDP socket on `address` that other sockets can bind as well
+fn multicast_socket(address: SocketAddr) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(address), Type::DGRAM, Some(Protocol::UDP)).context("create UDP socket")?;
+    socket.set_reuse_address(true).context("share UDP port")?;
+    if address.is_ipv6() {
+        socket.set_only_v6(true).context("restrict socket to IPv6")?;
+    }
+    socket.set_nonblocking(true).context("make UDP socket non-blocking")?;
+    socket.bind(&address.into()).context(format!("bind {address}"))?;
+
+    UdpSocket::from_std(socket.into()).context("register UDP socket")
+}
+
+/// Receives from `socket` if there is one, waits forever otherwise
+async fn recv_from(socket: Option<&UdpSocket>, buffer: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buffer).await,
+        None => std::future::pending().await,
+    }
+}
+
@@ -1,49 +1,326 @@
+use bittorrent::create::{create_torrent, CreateOptions};
+use bittorrent::files::{FileSelection, FileSelector};
+use bittorrent::lsd::LsdOptions;
+use bittorrent::paths::PathPolicy;
 use bittorrent::peer_connection::PeerConnection;
+use bittorrent::peer_id::PeerId;
+use bittorrent::progress::ProgressView;
+use bittorrent::event::EventKind;
+use bittorrent::session::{AddTorrentOptions, Session, SessionOptions};
+use bittorrent::tracker::scrape::{scrape, ScrapeStats};
+use bittorrent::tracker::server::{ServerOptions, TrackerServer};
 use bittorrent::torrent::*;
+use bittorrent::validate::validate_bytes;
+use bittorrent::verify::verify;
 use anyhow::Context;
-use std::net::SocketAddrV4;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::IsTerminal;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::str::FromStr;
-use clap::{Parser, Subcommand};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tracing_subscriber::EnvFilter;
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use serde::Serialize;
 
 
+/// How often `download` redraws its progress on a terminal
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often `download` prints a progress line when stdout is not a terminal
+const PROGRESS_INTERVAL_PLAIN: Duration = Duration::from_secs(10);
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     #[command(subcommand)]
     command: Commands,
+
+    /// Start of the peer id instead of `-RByyyy-`, the rest is filled with random characters
+    #[arg(long, global = true)]
+    peer_id_prefix: Option<String>,
+
+    /// Log more: `-v` for info, `-vv` for debug, `-vvv` for every message exchanged with peers.
+    /// `RUST_LOG` takes precedence when set
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Log errors only
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Format of log lines written to stderr
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum LogFormat {
+    Text,
+
+    /// One JSON object per line, with the torrent and peer spans as fields
+    Json,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
     Decode { value: String },
 
-    Info { torrent: PathBuf },
+    Info {
+        torrent: PathBuf,
+
+        /// Print machine-readable JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    Peers {
+        torrent: PathBuf,
 
-    Peers { torrent: PathBuf },
+        /// Print machine-readable JSON
+        #[arg(long)]
+        json: bool,
+    },
 
     Handshake { torrent: PathBuf, peer: String },
 
-    DownloadPiece { torrent: PathBuf },
+    /// Ask trackers for swarm sizes without announcing
+    Scrape {
+        /// Torrents sharing a tracker are scraped in a single request
+        #[arg(required = true)]
+        torrents: Vec<PathBuf>,
+
+        /// Print machine-readable JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    #[command(alias = "download-piece")]
+    Download {
+        torrent: PathBuf,
+
+        /// Download only files with this index or matching this glob, can be repeated
+        #[arg(short, long = "file")]
+        files: Vec<FileSelector>,
+
+        /// Directory to save the download into, created if missing
+        #[arg(short, long, default_value = "./downloads")]
+        output: PathBuf,
+
+        /// Refuse torrents with file names that are unsafe to use as paths, instead of renaming them
+        #[arg(long)]
+        strict_paths: bool,
+
+        /// Port to accept peer connections on, announced to trackers
+        #[arg(long, default_value_t = 6881)]
+        port: u16,
+
+        /// Most KiB per second to download, unlimited by default
+        #[arg(long, value_name = "KIB_PER_SECOND")]
+        download_limit: Option<usize>,
+
+        /// Most KiB per second to upload, unlimited by default
+        #[arg(long, value_name = "KIB_PER_SECOND")]
+        upload_limit: Option<usize>,
+
+        /// Peer to connect to besides those trackers return, can be repeated
+        #[arg(long = "peer", value_name = "ADDRESS")]
+        peers: Vec<SocketAddr>,
+
+        /// Don't announce to or look for peers on the local network
+        #[arg(long)]
+        no_lsd: bool,
+    },
+
+    /// Run an HTTP tracker keeping swarms in memory
+    ServeTracker {
+        /// Address to listen on
+        #[arg(long, default_value = "0.0.0.0:6969")]
+        bind: SocketAddr,
+
+        /// Seconds between announces asked of clients
+        #[arg(long, default_value_t = 1800)]
+        interval: u64,
+
+        /// Serve only this torrent, given as a hex info hash or a .torrent file. Can be repeated
+        #[arg(long = "allow")]
+        allowed: Vec<String>,
+    },
+
+    /// Check data on disk against piece hashes of the torrent
+    Verify {
+        torrent: PathBuf,
+
+        /// The downloaded file, or the directory holding files of a multi-file torrent
+        path: PathBuf,
+
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Check a torrent file for errors and suspicious values
+    Lint { torrent: PathBuf },
+
+    /// Build a .torrent file for a file or a directory
+    Create {
+        path: PathBuf,
+
+        /// Tracker announce URL, can be repeated
+        #[arg(short, long = "tracker", required = true)]
+        trackers: Vec<String>,
+
+        /// Piece length in bytes or `auto`
+        #[arg(long, default_value = "auto")]
+        piece_length: String,
+
+        /// Allow peers only from the listed trackers
+        #[arg(long)]
+        private: bool,
+
+        #[arg(long)]
+        comment: Option<String>,
+
+        /// Web seed URL, can be repeated
+        #[arg(long = "webseed")]
+        webseeds: Vec<String>,
+
+        /// Where to write the torrent, `<name>.torrent` by default
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+
+/// `info --json` output
+#[derive(Serialize)]
+struct InfoOutput {
+    info_hash: String,
+    info_hash_base32: String,
+    name: String,
+    total_size: usize,
+    piece_length: usize,
+    piece_count: usize,
+    files: Vec<FileOutput>,
+    trackers: Vec<String>,
 }
 
+#[derive(Serialize)]
+struct FileOutput {
+    path: String,
+    size: usize,
+    offset: usize,
+}
+
+/// `peers --json` output
+#[derive(Serialize)]
+struct PeersOutput {
+    peers: Vec<String>,
+    interval: usize,
+    seeders: Option<usize>,
+    leechers: Option<usize>,
+}
+
+/// `scrape --json` output, one per tracker
+#[derive(Serialize)]
+struct ScrapeOutput {
+    tracker: String,
+    torrents: Vec<ScrapeTorrentOutput>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ScrapeTorrentOutput {
+    name: String,
+    info_hash: String,
+
+    /// `None` if the tracker doesn't know the torrent
+    stats: Option<ScrapeStats>,
+}
+
+/// `download` output for events of the torrent
+fn event_line(event: &EventKind) -> Option<String> {
+    match event {
+        EventKind::Checked { verified, wanted } => Some(format!("{verified} of {wanted} selected pieces are already verified")),
+        EventKind::PeerConnected { address, client: Some(client) } => Some(format!("Connected to peer {address}: {client}")),
+        EventKind::PeerConnected { address, client: None } => Some(format!("Connected to peer {address}")),
+        EventKind::PeerDisconnected { address, error: Some(error) } => Some(format!("Warning: peer {address}: {error}")),
+        EventKind::PeerBanned { address, reason } => Some(format!("Banned peer {address}: {reason}")),
+        EventKind::PieceVerified(index) => Some(format!("Piece {index} verified")),
+        EventKind::PieceFailed(index) => Some(format!("Piece {index} failed hash check, requesting it again")),
+        EventKind::AnnounceFailed(error) => Some(format!("Warning: {error}")),
+        EventKind::Finished => Some(String::from("Download complete")),
+        _ => None,
+    }
+}
+
+/// Logs of the crate go to stderr at the level picked by `-v` and `-q`, other crates log warnings only
+fn init_logging(args: &Args) {
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => "error",
+        (false, 0) => "warn",
+        (false, 1) => "info",
+        (false, 2) => "debug",
+        (false, _) => "trace",
+    };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(format!("warn,bittorrent={level}")));
+
+    let logs = tracing_subscriber::fmt().with_env_filter(filter).with_writer(std::io::stderr);
+    match args.log_format {
+        LogFormat::Text => logs.init(),
+        LogFormat::Json => logs.json().init(),
+    }
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    match Args::parse().command {
+    let args = Args::parse();
+    init_logging(&args);
+
+    // Commands that talk to trackers or peers without a session use this id
+    let peer_id = match &args.peer_id_prefix {
+        Some(prefix) => PeerId::with_prefix(prefix)?,
+        None => PeerId::generate(),
+    };
+
+    match args.command {
         Commands::Decode { value: _value } => {
             // let decoded: serde_json::Value = serde_bencode::from_str(&value)?;
             unimplemented!("serde_bencode -> serde_json::Value doesn't work")
         }
 
-        Commands::Info { torrent } => {
+        Commands::Info { torrent, json } => {
             let torrent = Torrent::try_from(torrent)?;
+
+            if json {
+                let output = InfoOutput {
+                    info_hash: hex::encode(torrent.info_hash()?),
+                    info_hash_base32: torrent.info_hash_base32()?,
+                    name: torrent.info.name.clone(),
+                    total_size: torrent.file_length(),
+                    piece_length: torrent.info.piece_length,
+                    piece_count: torrent.info.pieces.0.len(),
+                    files: torrent
+                        .files()
+                        .into_iter()
+                        .map(|file| FileOutput { path: file.torrent_path, size: file.length, offset: file.offset })
+                        .collect(),
+                    trackers: torrent.trackers(),
+                };
+
+                println!("{}", serde_json::to_string_pretty(&output)?);
+                return Ok(());
+            }
+
             println!("Tracker URL: {}", torrent.announce);
             println!("Length: {}", torrent.file_length());
 
             let piece_hashes = torrent.info.pieces.0
                 .iter()
-                .map(|sha1| format!("\n{}", hex::encode(&sha1)))
+                .map(|sha1| format!("\n{}", hex::encode(sha1)))
                 .collect::<String>();
 
             println!("Info hash: {}", hex::encode(torrent.info_hash()?));
@@ -52,33 +329,237 @@ async fn main() -> anyhow::Result<()> {
 
         },
 
-        Commands::Peers { torrent } => {
+        Commands::Peers { torrent, json } => {
             let torrent = Torrent::try_from(torrent)?;
-            let response = torrent.tracker_info().await?;
+            let response = torrent.tracker_info(&mut torrent.tracker_client(), peer_id).await?;
+            if let Some(warning) = &response.warning_message {
+                eprintln!("Tracker warning: {warning}");
+            }
+
+            if json {
+                let output = PeersOutput {
+                    peers: response.addresses().iter().map(ToString::to_string).collect(),
+                    interval: response.interval,
+                    seeders: response.complete,
+                    leechers: response.incomplete,
+                };
 
-            for peer in &response.peers.0 {
+                println!("{}", serde_json::to_string_pretty(&output)?);
+                return Ok(());
+            }
+
+            for peer in response.addresses() {
                 println!("{peer}");
             }
         },
 
+        Commands::Scrape { torrents, json } => {
+            let mut by_tracker = BTreeMap::<String, Vec<(String, [u8; 20])>>::new();
+            for path in torrents {
+                let torrent = Torrent::try_from(path)?;
+                let info_hash = torrent.info_hash()?;
+                for tracker in torrent.trackers() {
+                    by_tracker.entry(tracker).or_default().push((torrent.info.name.clone(), info_hash));
+                }
+            }
+
+            let mut outputs = Vec::new();
+            for (tracker, torrents) in by_tracker {
+                let info_hashes = torrents.iter().map(|(_, info_hash)| *info_hash).collect::<Vec<_>>();
+                let (stats, error) = match scrape(&tracker, &info_hashes).await {
+                    Ok(stats) => (stats, None),
+                    Err(error) => (HashMap::new(), Some(format!("{error:#}"))),
+                };
+
+                let torrents = torrents
+                    .into_iter()
+                    .map(|(name, info_hash)| ScrapeTorrentOutput {
+                        name,
+                        info_hash: hex::encode(info_hash),
+                        stats: stats.get(&info_hash).copied(),
+                    })
+                    .collect();
+
+                outputs.push(ScrapeOutput { tracker, torrents, error });
+            }
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&outputs)?);
+            } else {
+                for output in &outputs {
+                    println!("{}", output.tracker);
+                    if let Some(error) = &output.error {
+                        println!("  error: {error}");
+                        continue;
+                    }
+
+                    for torrent in &output.torrents {
+                        match torrent.stats {
+                            Some(stats) => println!(
+                                "  {} {}: {} seeders, {} leechers, {} downloads",
+                                torrent.info_hash, torrent.name, stats.complete, stats.incomplete, stats.downloaded,
+                            ),
+                            None => println!("  {} {}: unknown to tracker", torrent.info_hash, torrent.name),
+                        }
+                    }
+                }
+            }
+
+            let failed = outputs.iter().filter(|output| output.error.is_some()).count();
+            anyhow::ensure!(failed == 0, "{failed} of {} trackers could not be scraped", outputs.len());
+        },
+
+        Commands::ServeTracker { bind, interval, allowed } => {
+            let whitelist = if allowed.is_empty() {
+                None
+            } else {
+                let info_hashes = allowed
+                    .iter()
+                    .map(|allowed| match <[u8; 20]>::try_from(hex::decode(allowed).unwrap_or_default()) {
+                        Ok(info_hash) => Ok(info_hash),
+                        Err(_) => Torrent::try_from(PathBuf::from(allowed))?.info_hash(),
+                    })
+                    .collect::<anyhow::Result<HashSet<_>>>()?;
+                Some(info_hashes)
+            };
+
+            let options = ServerOptions { interval: Duration::from_secs(interval), whitelist };
+            let listener = TcpListener::bind(bind).await.context(format!("bind tracker to {bind}"))?;
+            println!("Tracker listening on http://{}/announce", listener.local_addr()?);
+
+            Arc::new(TrackerServer::new(options)).serve(listener).await;
+        },
+
         Commands::Handshake { torrent, peer } => {
             let torrent = Torrent::try_from(torrent)?;
 
-            let peer_address = SocketAddrV4::from_str(&peer).context("parse peer address to IPV4")?;
-            PeerConnection::new(&torrent, &peer_address).await?;
+            let peer_address = SocketAddr::from_str(&peer).context("parse peer address")?;
+            let peer_id = PeerConnection::new(&torrent, &peer_address, peer_id).await?.peer_id();
+            match peer_id.client() {
+                Some(client) => println!("Connected to peer {peer_address}: {client} ({peer_id})"),
+                None => println!("Connected to peer {peer_address}: {peer_id}"),
+            }
         },
 
-        Commands::DownloadPiece { torrent } => {
+        Commands::Download { torrent, files, output, strict_paths, port, download_limit, upload_limit, peers, no_lsd } => {
+            let policy = if strict_paths { PathPolicy::Reject } else { PathPolicy::Rewrite };
+            let (torrent, changes) = Torrent::load(&torrent, policy)?;
+            for change in &changes {
+                eprintln!("Warning: renamed {change}");
+            }
+
+            let options = SessionOptions {
+                listen: SocketAddr::from(([0, 0, 0, 0], port)),
+                download_directory: output,
+                download_limit: download_limit.map(|limit| limit * 1024),
+                upload_limit: upload_limit.map(|limit| limit * 1024),
+                lsd: (!no_lsd).then(LsdOptions::default),
+                peer_id_prefix: args.peer_id_prefix,
+                ..Default::default()
+            };
+            let session = Session::new(options).await?;
+            let mut events = session.subscribe();
+            let name = torrent.info.name.clone();
+            let handle = session.add(torrent, AddTorrentOptions { selection: FileSelection(files), ..Default::default() })?;
+            handle.add_peers(peers);
+
+            // Terminals get a view redrawn in place, anything else a plain line now and then
+            let interactive = std::io::stdout().is_terminal();
+            let mut view = ProgressView::new(std::io::stdout(), interactive);
+            let mut ticks = tokio::time::interval(if interactive { PROGRESS_INTERVAL } else { PROGRESS_INTERVAL_PLAIN });
+
+            let finished = handle.finished();
+            let interrupted = tokio::signal::ctrl_c();
+            tokio::pin!(finished, interrupted);
+
+            let result = loop {
+                tokio::select! {
+                    result = &mut finished => break result,
+                    _ = &mut interrupted => break Err(anyhow::anyhow!("interrupted")),
+                    _ = ticks.tick() => view.update(&name, &handle.status(), &handle.peers())?,
+                    event = events.recv() => match event {
+                        // Connected peers and verified pieces are what the interactive view shows already
+                        Ok(event) if interactive && matches!(event.kind, EventKind::PeerConnected { .. } | EventKind::PieceVerified(_)) => {}
+                        Ok(event) => if let Some(line) = event_line(&event.kind) {
+                            view.message(&line)?;
+                        },
+                        Err(RecvError::Lagged(_) | RecvError::Closed) => {}
+                    },
+                }
+            };
+
+            while let Ok(event) = events.try_recv() {
+                if let Some(line) = event_line(&event.kind) {
+                    view.message(&line)?;
+                }
+            }
+            view.update(&name, &handle.status(), &handle.peers())?;
+
+            session.shutdown().await;
+            result?;
+        }
+
+        Commands::Verify { torrent, path, json } => {
             let torrent = Torrent::try_from(torrent)?;
-            let tracker_info = torrent.tracker_info().await?;
-            let mut peer = PeerConnection::new(&torrent, &tracker_info.peers.0[0]).await?;
+            let report = verify(&torrent, &path)?;
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                println!("Pieces: {} total, {} valid, {} bad, {} missing", report.pieces, report.valid, report.bad, report.missing);
 
-            peer.recv_bitfield().await?;
-            peer.send_interested().await?;
-            peer.recv_unchoke().await?;
-            peer.download().await?;
+                for file in &report.files {
+                    let state = if file.exists { "" } else { " (does not exist)" };
+                    println!("{}{}", file.path.display(), state);
+
+                    if !file.bad_pieces.is_empty() {
+                        println!("  bad pieces: {:?}", file.bad_pieces);
+                    }
+                    if !file.missing_pieces.is_empty() {
+                        println!("  missing pieces: {:?}", file.missing_pieces);
+                    }
+                }
+            }
+
+            if !report.is_complete() {
+                anyhow::bail!("{} of {} pieces are bad or missing", report.bad + report.missing, report.pieces);
+            }
+        }
+
+        Commands::Lint { torrent } => {
+            let bytes = std::fs::read(&torrent).context("read torrent file")?;
+            let (_, report) = validate_bytes(&bytes);
+
+            for problem in &report.problems {
+                println!("{problem}");
+            }
+
+            let errors = report.errors().count();
+            let warnings = report.warnings().count();
+            println!("{errors} errors, {warnings} warnings");
+
+            if errors > 0 {
+                anyhow::bail!("{} is not a valid torrent", torrent.display());
+            }
+        }
+
+        Commands::Create { path, trackers, piece_length, private, comment, webseeds, output } => {
+            let piece_length = match piece_length.as_str() {
+                "auto" => None,
+                value => Some(value.parse::<usize>().context("parse piece length")?),
+            };
+
+            let options = CreateOptions { trackers, piece_length, private, comment, webseeds };
+            let torrent = create_torrent(&path, &options)?;
+
+            let output = output.unwrap_or_else(|| PathBuf::from(format!("{}.torrent", torrent.info.name)));
+            let bytes = serde_bencode::to_bytes(&torrent).context("encode torrent")?;
+            std::fs::write(&output, bytes).context(format!("write {}", output.display()))?;
+
+            println!("Created {} ({} pieces of {} bytes)", output.display(), torrent.info.pieces.0.len(), torrent.info.piece_length);
+            println!("Info hash: {}", hex::encode(torrent.info_hash()?));
         }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
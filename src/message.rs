@@ -29,7 +29,7 @@ impl TryFrom<u8> for MessageTag {
             6 => Ok(MessageTag::Request),
             7 => Ok(MessageTag::Piece),
             8 => Ok(MessageTag::Cancel),
-            tag => return Err(std::io::Error::new(
+            tag => Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 format!("Message tag {} is not supported", tag)
             )),
@@ -61,7 +61,7 @@ impl Decoder for MessageFramer {
             return Ok(None);
         }
 
-        let payload_len = read_u32(&src) as usize;
+        let payload_len = read_u32(src) as usize;
         let overall_len = MESSAGE_LENGTH + payload_len;
         let contains_payload = buffer_len > MESSAGE_TAG_AND_LENGTH;
 
@@ -137,6 +137,19 @@ impl Encoder<Message> for MessageFramer {
     }
 }
 
+/// Empty message without a tag, tells the peer the connection is still in use
+#[derive(Debug, Clone, Copy)]
+pub struct KeepAlive;
+
+impl Encoder<KeepAlive> for MessageFramer {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, _: KeepAlive, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&[0; MESSAGE_LENGTH]);
+        Ok(())
+    }
+}
+
 fn read_u32(bytes: &[u8]) -> u32 {
     let mut length_bytes = [0u8; 4];
     length_bytes.copy_from_slice(&bytes[..4]);
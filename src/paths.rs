@@ -0,0 +1,290 @@
+use std::{collections::{HashMap, HashSet}, fmt, path::{Path, PathBuf}};
+use anyhow::Result;
+use serde::Serialize;
+use crate::torrent::{Keys, Torrent};
+
+
+/// Longest file name, in bytes, most file systems accept
+const NAME_MAX: usize = 255;
+
+/// Characters that are not allowed in file names on at least one common platform
+const RESERVED_CHARACTERS: &[char] = &['<', '>', ':', '"', '|', '?', '*'];
+
+/// Device names that can't be used as file names on Windows, with or without an extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Reason a name from metainfo can't be used on disk as it is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PathIssue {
+    /// `.` or `..` component
+    Relative,
+
+    /// `/` or `\` inside a component, would create extra directories or an absolute path
+    Separator,
+
+    /// NUL or another control character
+    Control,
+
+    /// Characters or device names reserved on some platforms, trailing dots and spaces
+    Reserved,
+
+    /// Empty component, or a file without any path at all
+    Empty,
+
+    /// Component is longer than 255 bytes
+    TooLong,
+
+    /// Another file ends up at the same path once names are sanitised
+    Duplicate,
+}
+
+impl fmt::Display for PathIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PathIssue::Relative => "relative component",
+            PathIssue::Separator => "path separator",
+            PathIssue::Control => "control character",
+            PathIssue::Reserved => "reserved name or character",
+            PathIssue::Empty => "empty component",
+            PathIssue::TooLong => "name too long",
+            PathIssue::Duplicate => "duplicate path",
+        })
+    }
+}
+
+/// Everything that's wrong with a single path component
+pub fn component_issues(name: &str) -> Vec<PathIssue> {
+    let mut issues = Vec::new();
+    let relative = name == "." || name == "..";
+
+    if relative {
+        issues.push(PathIssue::Relative);
+    }
+    if name.contains(['/', '\\']) {
+        issues.push(PathIssue::Separator);
+    }
+    if name.chars().any(char::is_control) {
+        issues.push(PathIssue::Control);
+    }
+    if !relative && (name.contains(RESERVED_CHARACTERS) || name.ends_with(['.', ' ']) || is_reserved_name(name)) {
+        issues.push(PathIssue::Reserved);
+    }
+    if name.is_empty() {
+        issues.push(PathIssue::Empty);
+    }
+    if name.len() > NAME_MAX {
+        issues.push(PathIssue::TooLong);
+    }
+
+    issues
+}
+
+fn is_reserved_name(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or_default();
+    RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem))
+}
+
+/// Turns a name from metainfo into a single safe path component, so that a hostile
+/// torrent can't escape the download directory. Separators, control and reserved
+/// characters become `_`, `.`, `..` and empty names become `_`, long names are
+/// shortened to 255 bytes keeping the extension
+pub fn sanitize_component(name: &str) -> String {
+    let mut sanitized = name
+        .chars()
+        .map(|c| if c.is_control() || c == '/' || c == '\\' || RESERVED_CHARACTERS.contains(&c) { '_' } else { c })
+        .collect::<String>()
+        .trim_end_matches(['.', ' '])
+        .to_string();
+
+    if sanitized.is_empty() || is_reserved_name(&sanitized) {
+        sanitized.insert(0, '_');
+    }
+
+    if sanitized.len() > NAME_MAX {
+        let extension = match sanitized.rfind('.') {
+            Some(dot) if sanitized.len() - dot <= 32 => sanitized[dot..].to_string(),
+            _ => String::new(),
+        };
+
+        let mut end = NAME_MAX - extension.len();
+        while !sanitized.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        sanitized = format!("{}{}", &sanitized[..end], extension);
+    }
+
+    sanitized
+}
+
+/// Appends `_{suffix}` to the name, before its extension
+fn disambiguate(name: &str, suffix: usize) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => format!("{stem}_{suffix}.{extension}"),
+        _ => format!("{name}_{suffix}"),
+    }
+}
+
+/// Name from metainfo that is stored under a different path than the torrent says
+#[derive(Debug, Clone, Serialize)]
+pub struct PathChange {
+    /// Index in the `files` list, `None` for `info.name`
+    pub file: Option<usize>,
+
+    /// Name as it is in metainfo, path components joined with `/`
+    pub original: String,
+
+    /// Path it's stored at, relative to the download directory for files
+    pub sanitized: PathBuf,
+
+    pub issues: Vec<PathIssue>,
+}
+
+impl fmt::Display for PathChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.file {
+            Some(index) => write!(f, "file {index}")?,
+            None => write!(f, "name")?,
+        }
+
+        let issues = self.issues.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ");
+        write!(f, " {:?} -> {:?} ({issues})", self.original, self.sanitized.display().to_string())
+    }
+}
+
+/// What to do with a torrent whose names are not safe to use as paths
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PathPolicy {
+    /// Store such files under sanitised paths
+    #[default]
+    Rewrite,
+
+    /// Refuse to load the torrent
+    Reject,
+}
+
+impl Torrent {
+    /// Safe path of every file relative to the download directory, together with the issues
+    /// found in its original name. For multi-file torrents issues of `info.name` are not repeated
+    /// for every file, see [`Torrent::path_changes`]
+    pub fn safe_paths(&self) -> Vec<(PathBuf, Vec<PathIssue>)> {
+        let name = sanitize_component(&self.info.name);
+
+        let files = match &self.info.keys {
+            Keys::SingleFile { .. } => return vec![(PathBuf::from(name), component_issues(&self.info.name))],
+            Keys::MultiFile { files } => files,
+        };
+
+        // Paths are compared case-insensitively, a file may not share its path with
+        // another file or directory, and none of its directories may be a file
+        let mut seen_files = HashSet::new();
+        let mut seen_directories = HashSet::new();
+        let key = |parts: &[String]| parts.join("/").to_lowercase();
+
+        // Renamed directories by the key they had, so that later files in them stay together
+        let mut renamed_directories = HashMap::new();
+
+        files
+            .iter()
+            .enumerate()
+            .map(|(index, file)| {
+                let mut issues = file.path.iter().flat_map(|part| component_issues(part)).collect::<Vec<_>>();
+                let mut parts = file.path
+                    .iter()
+                    .filter(|part| !part.is_empty())
+                    .map(|part| sanitize_component(part))
+                    .collect::<Vec<_>>();
+
+                if parts.is_empty() {
+                    issues.push(PathIssue::Empty);
+                    parts.push(String::from("_"));
+                }
+
+                let last = parts.len() - 1;
+                for position in 0..parts.len() {
+                    let before = key(&parts[..=position]);
+                    if let Some(renamed) = renamed_directories.get(&before).filter(|_| position < last) {
+                        issues.push(PathIssue::Duplicate);
+                        parts[position] = String::clone(renamed);
+                        continue;
+                    }
+
+                    let taken = seen_files.contains(&before)
+                        || (position == last && seen_directories.contains(&key(&parts)));
+
+                    if taken {
+                        // Renamed names may be taken as well, by files named like that in metainfo
+                        issues.push(PathIssue::Duplicate);
+                        let original = parts[position].clone();
+                        for suffix in index.. {
+                            parts[position] = disambiguate(&original, suffix);
+                            let key = key(&parts[..=position]);
+                            if !seen_files.contains(&key) && !seen_directories.contains(&key) {
+                                break;
+                            }
+                        }
+
+                        if position < last {
+                            renamed_directories.insert(before, parts[position].clone());
+                        }
+                    }
+                }
+
+                for position in 0..last {
+                    seen_directories.insert(key(&parts[..=position]));
+                }
+                seen_files.insert(key(&parts));
+
+                issues.sort_by_key(|issue| *issue as u8);
+                issues.dedup();
+
+                let path = parts.iter().fold(PathBuf::from(&name), |path, part| path.join(part));
+                (path, issues)
+            })
+            .collect()
+    }
+
+    /// Every name from metainfo that is stored under a different path
+    pub fn path_changes(&self) -> Vec<PathChange> {
+        let mut changes = Vec::new();
+
+        let issues = component_issues(&self.info.name);
+        if !issues.is_empty() {
+            changes.push(PathChange {
+                file: None,
+                original: self.info.name.clone(),
+                sanitized: PathBuf::from(sanitize_component(&self.info.name)),
+                issues,
+            });
+        }
+
+        if let Keys::MultiFile { files } = &self.info.keys {
+            for (index, (file, (sanitized, issues))) in files.iter().zip(self.safe_paths()).enumerate() {
+                if !issues.is_empty() {
+                    changes.push(PathChange { file: Some(index), original: file.path.join("/"), sanitized, issues });
+                }
+            }
+        }
+
+        changes
+    }
+
+    /// Reads a torrent file and checks its names. With [`PathPolicy::Reject`] any unsafe
+    /// name is an error, otherwise changed paths are returned alongside the torrent
+    pub fn load(path: &Path, policy: PathPolicy) -> Result<(Self, Vec<PathChange>)> {
+        let torrent = Torrent::try_from(path.to_path_buf())?;
+        let changes = torrent.path_changes();
+
+        if policy == PathPolicy::Reject && !changes.is_empty() {
+            let changes = changes.iter().map(|change| format!("\n  {change}")).collect::<String>();
+            anyhow::bail!("torrent has unsafe paths:{changes}");
+        }
+
+        Ok((torrent, changes))
+    }
+}
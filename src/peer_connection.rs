@@ -1,163 +1,476 @@
-use std::{collections::VecDeque, net::SocketAddrV4, os::unix::fs::FileExt};
+use std::{collections::{HashMap, VecDeque}, net::{IpAddr, SocketAddr}, sync::Arc, time::Duration};
 use anyhow::{Context, Result};
-use tokio::net::TcpStream;
+use tokio::{net::TcpStream, time::Instant};
 use tokio_util::codec::Framed;
 use futures_util::{SinkExt, StreamExt};
-use crate::{handshake::{Handshake, Request}, message::{Message, MessageFramer, MessageTag}, piece::Piece, torrent::Torrent};
+use crate::{bitfield::Bitfield, event::{EventKind, Events}, handshake::{Handshake, Request}, limit::{Limits, Throttled}, message::{KeepAlive, Message, MessageFramer, MessageTag}, peer_id::PeerId, picker::PiecePicker, piece::{Piece, BLOCK_MAX}, smart_ban::{block_digest, BlockDigest, SmartBan}, storage::Storage, torrent::Torrent, transfer::Transfer};
+
+/// Number of block requests kept in flight
+const PIPELINE_LENGTH: usize = 5;
+
+/// Peer broke the protocol or keeps sending corrupt data, it shouldn't be connected to again
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("peer misbehaved: {0}")]
+pub struct Misbehavior(pub String);
+
+/// Like `anyhow::ensure!`, failing with [`Misbehavior`]
+macro_rules! ensure_behaves {
+    ($condition:expr, $($message:tt)+) => {
+        if !$condition {
+            return Err(Misbehavior(format!($($message)+)).into());
+        }
+    };
+}
+
+/// How long connections wait for the other side, see [`PeerConnection::connect`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerTimeouts {
+    /// Establishing the TCP connection
+    pub connect: Duration,
+
+    /// Exchanging handshakes once connected
+    pub handshake: Duration,
+
+    /// Waiting for a requested block. A peer that sends none for this long while we
+    /// are unchoked is snubbing us, and the connection is dropped
+    pub request: Duration,
+
+    /// Connections the peer sends no messages over for this long are dropped,
+    /// keep-alives don't count
+    pub idle: Duration,
+
+    /// A keep-alive is sent after this long without sending anything else
+    pub keep_alive: Duration,
+}
+
+impl Default for PeerTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            handshake: Duration::from_secs(10),
+            request: Duration::from_secs(60),
+            idle: Duration::from_secs(5 * 60),
+            keep_alive: Duration::from_secs(2 * 60),
+        }
+    }
+}
 
 
 pub struct PeerConnection<'a> {
-    socket: Framed<TcpStream, MessageFramer>,
+    socket: Framed<Throttled<TcpStream>, MessageFramer>,
     torrent: &'a Torrent,
+
+    peer_id: PeerId,
+
+    /// Pieces the peer announced with `bitfield` and `have` messages
+    bitfield: Bitfield,
+
+    /// Where verified and failed pieces are reported
+    events: Option<Events>,
+
+    /// Where blocks of verified and failed pieces are reported, to find peers sending corrupt data
+    smart_ban: Option<Arc<SmartBan>>,
+
+    /// Bytes exchanged over this connection only
+    transfer: Arc<Transfer>,
+
+    timeouts: PeerTimeouts,
+    last_sent: Instant,
+    last_received: Instant,
 }
 
 impl<'a> PeerConnection<'a> {
-    pub async fn new(torrent: &'a Torrent, address: &SocketAddrV4) -> Result<PeerConnection<'a>> {
-        let mut stream = TcpStream::
-            connect(address)
+    /// Connects and introduces ourselves with `own` peer id
+    pub async fn new(torrent: &'a Torrent, address: &SocketAddr, own: PeerId) -> Result<PeerConnection<'a>> {
+        Self::connect(torrent, address, own, PeerTimeouts::default()).await
+    }
+
+    /// Connects and exchanges handshakes, giving up after the connect and handshake timeouts
+    pub async fn connect(torrent: &'a Torrent, address: &SocketAddr, own: PeerId, timeouts: PeerTimeouts) -> Result<PeerConnection<'a>> {
+        let mut stream = tokio::time::timeout(timeouts.connect, TcpStream::connect(address))
             .await
+            .context("connecting to peer timed out")?
             .context("connecting to peer address")?;
 
-        let handshake = Handshake::
-            new(torrent)?
-            .establish(&mut stream)
-            .await?;
+        let handshake = tokio::time::timeout(timeouts.handshake, Handshake::new(torrent, own)?.establish(&mut stream))
+            .await
+            .context("peer didn't send a handshake in time")??;
 
-        assert_eq!(handshake.length, 19);
-        assert_eq!(handshake.bittorrent, *b"BitTorrent protocol");
+        anyhow::ensure!(handshake.length == 19 && handshake.bittorrent == *b"BitTorrent protocol", "peer doesn't speak BitTorrent");
+        anyhow::ensure!(handshake.info_hash == torrent.info_hash()?, "peer replied with a different info hash");
 
-        println!("Connected to peer: {}", hex::encode(handshake.peer_id));
+        let peer_id = PeerId(handshake.peer_id);
+        tracing::trace!(%address, %peer_id, "handshake complete");
 
-        Ok(PeerConnection {
+        Ok(Self::accept(torrent, stream, peer_id).with_timeouts(timeouts))
+    }
+
+    /// Wraps a connection once handshakes are exchanged, used for peers that connected to us
+    pub fn accept(torrent: &'a Torrent, stream: TcpStream, peer_id: PeerId) -> PeerConnection<'a> {
+        PeerConnection {
             socket: Framed::new(
-                stream,
+                Throttled::new(stream, Limits::default()),
                 MessageFramer
             ),
             torrent,
-        })
+            peer_id,
+            bitfield: Bitfield::new(torrent.info.pieces.0.len()),
+            events: None,
+            smart_ban: None,
+            transfer: Arc::new(Transfer::default()),
+            timeouts: PeerTimeouts::default(),
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+        }
+    }
+
+    pub fn with_timeouts(mut self, timeouts: PeerTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Reports pieces this connection downloads to `events`
+    pub fn with_events(mut self, events: Events) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Reports blocks of downloaded pieces to `smart_ban`, the download fails with
+    /// [`Misbehavior`] once it convicts the peer
+    pub fn with_smart_ban(mut self, smart_ban: Arc<SmartBan>) -> Self {
+        self.smart_ban = Some(smart_ban);
+        self
+    }
+
+    /// Reads and writes no faster than `limits` allow
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.socket.get_mut().set_limits(limits);
+        self
+    }
+
+    /// Peer id from the peer's handshake
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// Bytes exchanged over this connection, unlike the torrent counters passed to
+    /// [`PeerConnection::download`] and [`PeerConnection::serve`]
+    pub fn transfer(&self) -> Arc<Transfer> {
+        self.transfer.clone()
     }
 
     pub async fn recv_bitfield(&mut self) -> Result<Message> {
-        let bitfield = self.socket
-            .next()
-            .await
-            .expect("peer always sends a bitfields")
-            .context("peer message was invalid")?;
+        let bitfield = self.recv().await?;
 
-        assert_eq!(bitfield.tag, MessageTag::Bitfield);
+        anyhow::ensure!(bitfield.tag == MessageTag::Bitfield, "expected bitfield, peer sent {:?}", bitfield.tag);
+        self.bitfield = Bitfield(bitfield.payload.clone());
 
         Ok(bitfield)
     }
 
     pub async fn send_interested(&mut self) -> Result<()> {
-        self.socket
-            .send(Message {
-                tag: MessageTag::Interested,
-                payload: Vec::new(),
-            })
+        self.send(MessageTag::Interested, Vec::new())
             .await
-            .context("send interested message")?;
-
-        Ok(())
+            .context("send interested message")
     }
 
     pub async fn recv_unchoke(&mut self) -> Result<Message> {
-        let unchocke = self.socket
-            .next()
-            .await
-            .expect("peer always sends a unchoke")
-            .context("peer message was invalid")?;
+        let unchocke = self.recv().await?;
 
-        assert_eq!(unchocke.tag, MessageTag::Unchoke);
-        assert!(unchocke.payload.is_empty());
+        anyhow::ensure!(unchocke.tag == MessageTag::Unchoke, "expected unchoke, peer sent {:?}", unchocke.tag);
+        ensure_behaves!(unchocke.payload.is_empty(), "unchoke message must be empty");
 
         Ok(unchocke)
     }
 
+    /// Tells the peer we are interested and waits until it unchokes us,
+    /// remembering the pieces it announces meanwhile
+    pub async fn request_unchoke(&mut self) -> Result<()> {
+        self.send_interested().await?;
+        self.wait_unchoke().await
+    }
+
     pub async fn send_request(&mut self, request: &mut Request) -> Result<()> {
-        println!("[send] index: {}; begin: {:05}; length: {}", request.index(), request.begin(), request.length());
-        self.socket
-            .send(Message {
-                tag: MessageTag::Request,
-                payload: request.as_bytes_mut().to_vec(),
-            })
+        tracing::trace!(index = request.index(), begin = request.begin(), length = request.length(), "request sent");
+        self.send(MessageTag::Request, request.as_bytes_mut().to_vec())
             .await
-            .context(format!("send request"))?;
-
-        Ok(())
+            .context("send request")
     }
 
     pub async fn recv_piece(&mut self) -> Result<Piece> {
-        let piece = self.socket
-            .next()
-            .await
-            .expect("peer always sends a piece")
-            .context("peer message was invalid")?;
+        let piece = self.recv().await?;
 
-        assert_eq!(piece.tag, MessageTag::Piece);
-        assert!(!piece.payload.is_empty());
+        anyhow::ensure!(piece.tag == MessageTag::Piece, "expected piece, peer sent {:?}", piece.tag);
+        ensure_behaves!(piece.payload.len() >= 8, "piece message is too short");
 
         let piece = Piece::from(&piece.payload);
         Ok(piece)
     }
 
-    /// Downloads whole file. Breaks file into pieces and 
-    /// each piece into blocks with constant size
-    /// 
+    /// Downloads pieces handed out by `picker` into `storage`, for as long as the peer has
+    /// any of them. Each piece is broken into blocks with constant size
+    ///
     /// Requests are pipelined, meaning stream always have N pending requests
-    /// for N blocks. After receiving response for block - it is written 
-    /// into file at offset that block corresponds to
-    /// 
+    /// for N blocks. After receiving response for block - it is written
+    /// into storage at offset that block corresponds to. Once all blocks of
+    /// a piece are written, the piece is verified against its hash, pieces
+    /// that fail the check go back to the picker
+    ///
     /// Current implementation N = 5 (always 5 pending requests)
-    pub async fn download(&mut self) -> Result<()> {
-        let output = self.torrent.output_file()?;
-        let (mut pipeline, mut remain) = self.block_requests(5);
+    ///
+    /// Transfer counters are updated as blocks arrive and pieces pass verification
+    pub async fn download(&mut self, storage: &dyn Storage, picker: &PiecePicker, transfer: &Transfer) -> Result<()> {
+        let address = self.socket.get_ref().get_ref().peer_addr().context("read peer address")?;
+        let mut picked = Picked { picker, ip: address.ip(), blocks_left: HashMap::new() };
+        let mut remain = VecDeque::new();
+        let mut pipeline = Vec::with_capacity(PIPELINE_LENGTH);
+        self.fill_pipeline(&mut pipeline, &mut remain, &mut picked).await?;
 
-        for request in &mut pipeline {
-            self.send_request(request).await?;
-        }
+        // Peer is snubbing us if no requested block arrives in time
+        let mut last_block = Instant::now();
+
+        // Offset and digest of the blocks received, by piece
+        let mut digests = HashMap::<usize, Vec<(usize, BlockDigest)>>::new();
 
         while !pipeline.is_empty() {
-            let block = self.recv_piece().await?;
+            let message = self
+                .next(Some(last_block + self.timeouts.request))
+                .await?
+                .context("peer closed the connection")?;
+
+            match message.tag {
+                MessageTag::Piece => {
+                    ensure_behaves!(message.payload.len() >= 8, "piece message is too short");
+                    last_block = Instant::now();
+                }
+                MessageTag::Choke => {
+                    tracing::debug!(pending = pipeline.len(), "choked");
+                    // Peer discards pending requests while choking, they are sent again once unchoked
+                    for request in pipeline.drain(..).rev() {
+                        remain.push_front(request);
+                    }
+                    self.wait_unchoke().await?;
+                    self.fill_pipeline(&mut pipeline, &mut remain, &mut picked).await?;
+                    last_block = Instant::now();
+                    continue;
+                }
+                _ => continue,
+            }
 
-            let reqeust_index = pipeline
+            let block = Piece::from(&message.payload);
+            let request_index = pipeline
                 .iter()
-                .enumerate()
-                .find(|(_, a)| a.index() == block.index())
-                .map(|(i, _)| i)
-                .expect("find request that corresponds to block received");
-
-            match remain.pop_front() {
-                None => {
-                    pipeline.remove(reqeust_index);
-                },
-                Some(mut request) => {
-                    self.send_request(&mut request).await?;
-                    pipeline[reqeust_index] = request;
+                .position(|request: &Request| request.index() == block.index() && request.begin() == block.begin())
+                .ok_or_else(|| Misbehavior(format!("peer sent block {} at {} that was not requested", block.index(), block.begin())))?;
+            let request = pipeline.swap_remove(request_index);
+            ensure_behaves!(
+                block.block().len() == request.length() as usize,
+                "peer sent {} bytes for block {} at {}, {} were requested",
+                block.block().len(),
+                block.index(),
+                block.begin(),
+                request.length(),
+            );
+
+            let index = block.index() as usize;
+            tracing::trace!(index, begin = block.begin(), length = block.block().len(), "block received");
+
+            storage.write_block(index, block.begin() as usize, block.block())?;
+            if self.smart_ban.is_some() {
+                digests.entry(index).or_default().push((block.begin() as usize, block_digest(block.block())));
+            }
+            transfer.add_downloaded(block.block().len());
+            self.transfer.add_downloaded(block.block().len());
+
+            let left = picked.blocks_left.get_mut(&index).context("block of a piece that was not requested")?;
+            *left -= 1;
+            if *left == 0 {
+                picked.blocks_left.remove(&index);
+                let blocks = digests.remove(&index).unwrap_or_default();
+
+                if storage.verify_piece(index)? {
+                    if let Some(smart_ban) = &self.smart_ban {
+                        smart_ban.piece_passed(index, &blocks);
+                    }
+                    storage.piece_verified(index)?;
+                    picker.done(index);
+                    transfer.add_verified(storage.layout().piece_size(index));
+                    tracing::debug!(index, "piece verified");
+                    self.report(EventKind::PieceVerified(index));
+                } else {
+                    tracing::warn!(index, "piece failed hash check");
+                    picker.failed(index, address.ip());
+                    self.report(EventKind::PieceFailed(index));
+                    if let Some(smart_ban) = &self.smart_ban {
+                        smart_ban.piece_failed(index, blocks.into_iter().map(|(begin, digest)| (begin, address, digest)).collect());
+                    }
+                }
+
+                // Convicted by this piece, or by another connection finding out about an earlier one
+                if let Some(reason) = self.smart_ban.as_ref().and_then(|smart_ban| smart_ban.convicted(address.ip())) {
+                    return Err(Misbehavior(reason).into());
+                }
+            }
+
+            self.fill_pipeline(&mut pipeline, &mut remain, &mut picked).await?;
+        }
+
+        storage.flush()
+    }
+
+    /// Uploads verified pieces to the peer until it disconnects. Interested peers are always unchoked
+    pub async fn serve(&mut self, storage: &dyn Storage, picker: &PiecePicker, transfer: &Transfer) -> Result<()> {
+        self.send(MessageTag::Bitfield, picker.have().0).await?;
+
+        while let Some(message) = self.next(None).await? {
+            match message.tag {
+                MessageTag::Interested => self.send(MessageTag::Unchoke, Vec::new()).await?,
+                MessageTag::Request => {
+                    let payload: [u8; 12] = message
+                        .payload
+                        .as_slice()
+                        .try_into()
+                        .map_err(|_| Misbehavior(String::from("request message must be 12 bytes")))?;
+                    let number = |at: usize| u32::from_be_bytes(payload[at..at + 4].try_into().expect("always 4 bytes")) as usize;
+                    let (index, begin, length) = (number(0), number(4), number(8));
+
+                    ensure_behaves!(picker.has(index), "peer requested piece {index} we don't have");
+                    ensure_behaves!(
+                        length <= BLOCK_MAX && begin + length <= storage.layout().piece_size(index),
+                        "peer requested {length} bytes at {begin} of piece {index}, out of bounds",
+                    );
+
+                    tracing::trace!(index, begin, length, "block sent");
+                    let mut piece = payload[..8].to_vec();
+                    piece.extend(storage.read_block(index, begin, length)?);
+                    self.send(MessageTag::Piece, piece).await?;
+                    transfer.add_uploaded(length);
+                    self.transfer.add_uploaded(length);
                 }
+                _ => {}
             }
+        }
 
-            let offset = (block.index() * self.torrent.info.piece_length as u32) + block.begin();
-            println!("[received] index: {}, begin: {:05}; offset: {:05}; length: {}", block.index(), block.begin(), offset, block.block().len());
+        Ok(())
+    }
 
-            output
-                .write_at(&block.block(), offset as u64)
-                .context(format!("write block into file at offset {}", offset))?;
+    fn report(&self, kind: EventKind) {
+        if let Some(events) = &self.events {
+            events.send(kind);
         }
+    }
 
+    async fn send(&mut self, tag: MessageTag, payload: Vec<u8>) -> Result<()> {
+        self.socket
+            .send(Message { tag, payload })
+            .await
+            .context("send message to peer")?;
+
+        self.last_sent = Instant::now();
         Ok(())
     }
 
-    fn block_requests(&self, split_at: usize) -> (Vec<Request>, VecDeque<Request>) {
-        let block_requests = self.torrent
-            .pieces_chunked()
-            .map(|piece_chunked| piece_chunked.block_requests().collect::<Vec<_>>())            
-            .flatten()
-            .collect::<Vec<Request>>();
+    /// Next message, fails if the peer closes the connection
+    async fn recv(&mut self) -> Result<Message> {
+        self.next(None).await?.context("peer closed the connection")
+    }
 
-        let pipeline = block_requests[..split_at].to_vec();
-        let remain = VecDeque::from(block_requests[split_at..].to_vec());
+    /// Next message, or `None` once the peer closes the connection. Keep-alives are sent
+    /// while waiting. Fails if the peer stays idle for too long, or if `deadline` passes
+    ///
+    /// Pieces announced with `bitfield` and `have` are recorded on the way
+    async fn next(&mut self, deadline: Option<Instant>) -> Result<Option<Message>> {
+        let message = loop {
+            let keep_alive = self.last_sent + self.timeouts.keep_alive;
+            let idle = self.last_received + self.timeouts.idle;
 
-        (pipeline, remain)
-    } 
-}
\ No newline at end of file
+            tokio::select! {
+                message = self.socket.next() => break message,
+                _ = tokio::time::sleep_until(keep_alive) => {}
+                _ = tokio::time::sleep_until(idle) => {
+                    anyhow::bail!("peer sent nothing for {}s", self.timeouts.idle.as_secs());
+                }
+                _ = tokio::time::sleep_until(deadline.unwrap_or(idle)), if deadline.is_some() => {
+                    tracing::debug!("snubbed");
+                    anyhow::bail!("peer is snubbing us, no block arrived for {}s", self.timeouts.request.as_secs());
+                }
+            }
+
+            tracing::trace!("keep-alive sent");
+            self.socket.send(KeepAlive).await.context("send keep-alive")?;
+            self.last_sent = Instant::now();
+        };
+
+        let Some(message) = message else {
+            return Ok(None);
+        };
+        let message = match message {
+            Ok(message) => message,
+            Err(error) if error.kind() == std::io::ErrorKind::InvalidData => return Err(Misbehavior(error.to_string()).into()),
+            Err(error) => return Err(error).context("receive message from peer"),
+        };
+        self.last_received = Instant::now();
+
+        match message.tag {
+            MessageTag::Bitfield => self.bitfield = Bitfield(message.payload.clone()),
+            MessageTag::Have => {
+                let index: [u8; 4] = message
+                    .payload
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| Misbehavior(String::from("have message must be 4 bytes")))?;
+                self.bitfield.set(u32::from_be_bytes(index) as usize);
+            }
+            _ => {}
+        }
+
+        Ok(Some(message))
+    }
+
+    async fn wait_unchoke(&mut self) -> Result<()> {
+        while self.recv().await?.tag != MessageTag::Unchoke {}
+        Ok(())
+    }
+
+    /// Tops up in-flight requests, taking the next piece from the picker once blocks of picked pieces run out
+    async fn fill_pipeline(&mut self, pipeline: &mut Vec<Request>, remain: &mut VecDeque<Request>, picked: &mut Picked<'_>) -> Result<()> {
+        while pipeline.len() < PIPELINE_LENGTH {
+            if remain.is_empty() {
+                let Some(index) = picked.picker.pick(picked.ip, &self.bitfield) else {
+                    break;
+                };
+
+                let piece_chunked = self.torrent.pieces_chunked().nth(index).expect("piece index is within torrent");
+                picked.blocks_left.insert(index, piece_chunked.number_of_blocks);
+                remain.extend(piece_chunked.block_requests());
+            }
+
+            let Some(mut request) = remain.pop_front() else {
+                break;
+            };
+
+            self.send_request(&mut request).await?;
+            pipeline.push(request);
+        }
+
+        Ok(())
+    }
+}
+
+/// Pieces a connection took from the picker, with the number of blocks still to receive.
+/// They go back to the picker if the connection ends before they are verified
+struct Picked<'a> {
+    picker: &'a PiecePicker,
+
+    /// IP of the peer, failed pieces are handed to another one first
+    ip: IpAddr,
+    blocks_left: HashMap<usize, usize>,
+}
+
+impl Drop for Picked<'_> {
+    fn drop(&mut self) {
+        for &index in self.blocks_left.keys() {
+            self.picker.release(index);
+        }
+    }
+}
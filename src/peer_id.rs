@@ -0,0 +1,119 @@
+use std::fmt;
+use anyhow::Result;
+
+
+/// Azureus-style client code of this crate
+pub const CLIENT_CODE: &str = "RB";
+
+const RANDOM_CHARACTERS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Azureus-style client codes of common clients
+const CLIENTS: &[(&str, &str)] = &[
+    (CLIENT_CODE, "bittorrent"),
+    ("AZ", "Vuze"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "libTorrent"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("TR", "Transmission"),
+    ("UT", "µTorrent"),
+    ("UW", "µTorrent Web"),
+    ("WW", "WebTorrent"),
+];
+
+/// 20 bytes identifying a client to trackers and other peers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerId(pub [u8; 20]);
+
+impl PeerId {
+    /// `-RByyyy-` with the crate version, followed by random characters
+    pub fn generate() -> Self {
+        Self::with_prefix(&default_prefix()).expect("default prefix is valid")
+    }
+
+    /// Fills the rest of the id after `prefix` with random alphanumeric characters
+    pub fn with_prefix(prefix: &str) -> Result<Self> {
+        anyhow::ensure!(prefix.is_ascii(), "peer id prefix must be ASCII");
+        anyhow::ensure!(prefix.len() <= 20, "peer id prefix is longer than 20 bytes");
+
+        let mut id = [0; 20];
+        id[..prefix.len()].copy_from_slice(prefix.as_bytes());
+        for byte in &mut id[prefix.len()..] {
+            *byte = RANDOM_CHARACTERS[fastrand::usize(..RANDOM_CHARACTERS.len())];
+        }
+
+        Ok(Self(id))
+    }
+
+    /// Client software that produced the id, if it follows a known convention
+    pub fn client(&self) -> Option<Client> {
+        let id = &self.0;
+
+        // Azureus-style: `-XXyyyy-`, client code and four version characters
+        if id[0] == b'-' && id[7] == b'-' {
+            let code = std::str::from_utf8(&id[1..3]).ok()?;
+            let version = id[3..7].iter().map(|&c| version_digit(c)).collect::<Option<Vec<_>>>()?;
+
+            let name = CLIENTS.iter().find(|(known, _)| *known == code).map_or(code, |(_, name)| name);
+            return Some(Client { name: name.to_string(), version: join_version(&version) });
+        }
+
+        // Mainline: `M4-3-6--`, major, minor and patch separated by dashes
+        if id[0] == b'M' {
+            let prefix = std::str::from_utf8(&id[1..8]).ok()?;
+            let version = prefix.trim_end_matches('-').split('-').map(|part| part.parse().ok()).collect::<Option<Vec<u32>>>()?;
+
+            return Some(Client { name: String::from("BitTorrent"), version: join_version(&version) });
+        }
+
+        None
+    }
+}
+
+impl fmt::Display for PeerId {
+    /// Printable characters as they are, anything else escaped
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0.escape_ascii().to_string())
+    }
+}
+
+/// Client software of a peer, as recognised from its peer id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Client {
+    pub name: String,
+    pub version: String,
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.name, self.version)
+    }
+}
+
+/// `-RByyyy-`, where the version characters are major, minor and patch of the crate version
+pub fn default_prefix() -> String {
+    let version = [env!("CARGO_PKG_VERSION_MAJOR"), env!("CARGO_PKG_VERSION_MINOR"), env!("CARGO_PKG_VERSION_PATCH")]
+        .map(|part| part.parse::<u32>().map_or('0', |part| char::from_digit(part.min(35), 36).expect("below 36").to_ascii_uppercase()));
+
+    format!("-{CLIENT_CODE}{}{}{}0-", version[0], version[1], version[2])
+}
+
+/// Version characters are digits, or letters for numbers above 9
+fn version_digit(c: u8) -> Option<u32> {
+    (c as char).to_digit(36)
+}
+
+/// `4.2.5` for `[4, 2, 5, 0]`, trailing zeros beyond major and minor are left out
+fn join_version(parts: &[u32]) -> String {
+    let mut end = parts.len();
+    while end > 2 && parts[end - 1] == 0 {
+        end -= 1;
+    }
+
+    parts[..end].iter().map(ToString::to_string).collect::<Vec<_>>().join(".")
+}
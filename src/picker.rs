@@ -0,0 +1,101 @@
+use std::{collections::{BTreeSet, HashMap, HashSet}, net::IpAddr, sync::Mutex};
+use crate::bitfield::Bitfield;
+
+
+/// Hands out missing pieces to peer connections of a torrent, so that no two
+/// connections download the same piece at once
+///
+/// A piece that failed the hash check is downloaded again by a single connection,
+/// preferably from a peer that sent none of it, so that [`crate::smart_ban::SmartBan`]
+/// can tell the good data apart from the bad
+#[derive(Debug)]
+pub struct PiecePicker {
+    state: Mutex<PickerState>,
+}
+
+#[derive(Debug)]
+struct PickerState {
+    /// Wanted pieces that are not verified and not being downloaded
+    missing: BTreeSet<usize>,
+
+    /// Pieces some connection is downloading right now
+    in_progress: HashSet<usize>,
+
+    /// Pieces that failed the hash check and are not verified yet, with the IPs that sent them
+    failed: HashMap<usize, HashSet<IpAddr>>,
+
+    /// Verified pieces
+    have: Bitfield,
+}
+
+impl PiecePicker {
+    pub fn new(missing: BTreeSet<usize>, have: Bitfield) -> Self {
+        Self { state: Mutex::new(PickerState { missing, in_progress: HashSet::new(), failed: HashMap::new(), have }) }
+    }
+
+    /// Lowest missing piece the peer at `ip` has, marked as in progress until it is
+    /// [`PiecePicker::done`], [`PiecePicker::failed`] or [`PiecePicker::release`]d. Failed
+    /// pieces go first to peers that sent none of them, and back to those that did only
+    /// once they have nothing else to pick
+    pub fn pick(&self, ip: IpAddr, peer: &Bitfield) -> Option<usize> {
+        let mut state = self.lock();
+        let sent = |index: &usize| state.failed.get(index).is_some_and(|senders| senders.contains(&ip));
+        let retry = state.failed.keys().copied().filter(|index| state.missing.contains(index) && peer.has(*index) && !sent(index)).min();
+        let index = retry
+            .or_else(|| state.missing.iter().copied().find(|&index| peer.has(index) && !sent(&index)))
+            .or_else(|| state.missing.iter().copied().find(|&index| peer.has(index)))?;
+
+        state.missing.remove(&index);
+        state.in_progress.insert(index);
+        Some(index)
+    }
+
+    /// Piece was downloaded and verified
+    pub fn done(&self, index: usize) {
+        let mut state = self.lock();
+        state.in_progress.remove(&index);
+        state.failed.remove(&index);
+        state.have.set(index);
+    }
+
+    /// Piece downloaded from the peer at `ip` failed verification, it can be picked again
+    pub fn failed(&self, index: usize, ip: IpAddr) {
+        let mut state = self.lock();
+        if state.in_progress.remove(&index) {
+            state.missing.insert(index);
+            state.failed.entry(index).or_default().insert(ip);
+        }
+    }
+
+    /// Connection downloading the piece was lost, it can be picked again
+    pub fn release(&self, index: usize) {
+        let mut state = self.lock();
+        if state.in_progress.remove(&index) {
+            state.missing.insert(index);
+        }
+    }
+
+    /// True once every wanted piece is verified
+    pub fn is_complete(&self) -> bool {
+        let state = self.lock();
+        state.missing.is_empty() && state.in_progress.is_empty()
+    }
+
+    /// Whether any piece is still waiting for a connection to pick it
+    pub fn has_missing(&self) -> bool {
+        !self.lock().missing.is_empty()
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        self.lock().have.has(index)
+    }
+
+    /// Verified pieces, as sent in the `bitfield` message
+    pub fn have(&self) -> Bitfield {
+        self.lock().have.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, PickerState> {
+        self.state.lock().expect("piece picker lock is poisoned")
+    }
+}
@@ -53,8 +53,7 @@ impl PieceChunked {
     }
 
     pub fn block_requests(&self) -> impl Iterator<Item = Request> + use<'_> {
-        (0..self. number_of_blocks)
-            .into_iter()
+        (0..self.number_of_blocks)
             .map(|index| {
                 let begin = index * BLOCK_MAX;
                 let last = index == self.number_of_blocks - 1;
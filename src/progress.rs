@@ -0,0 +1,111 @@
+use std::{io::{self, Write}, time::Duration};
+use crate::session::{PeerStatus, TorrentStatus};
+
+
+const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+/// Status of a torrent on a terminal. Interactive views redraw a summary and a line
+/// per peer in place, others print the summary as a plain line on every update
+pub struct ProgressView<W: Write> {
+    out: W,
+    interactive: bool,
+
+    /// Lines shown at the bottom of an interactive view
+    lines: Vec<String>,
+}
+
+impl<W: Write> ProgressView<W> {
+    pub fn new(out: W, interactive: bool) -> Self {
+        Self { out, interactive, lines: Vec::new() }
+    }
+
+    pub fn update(&mut self, name: &str, status: &TorrentStatus, peers: &[PeerStatus]) -> io::Result<()> {
+        let summary = summary(name, status);
+        if !self.interactive {
+            return writeln!(self.out, "{summary}");
+        }
+
+        self.clear()?;
+        self.lines = std::iter::once(summary).chain(peers.iter().map(peer_line)).collect();
+        self.draw()
+    }
+
+    /// Prints `line` above an interactive view, so it stays on screen once the view is redrawn
+    pub fn message(&mut self, line: &str) -> io::Result<()> {
+        self.clear()?;
+        writeln!(self.out, "{line}")?;
+        self.draw()
+    }
+
+    /// Erases lines drawn before, the cursor goes back to where the first one started
+    fn clear(&mut self) -> io::Result<()> {
+        if self.interactive && !self.lines.is_empty() {
+            write!(self.out, "\x1b[{}A\x1b[J", self.lines.len())?;
+        }
+        Ok(())
+    }
+
+    fn draw(&mut self) -> io::Result<()> {
+        if self.interactive {
+            for line in &self.lines {
+                writeln!(self.out, "{line}")?;
+            }
+        }
+        self.out.flush()
+    }
+}
+
+/// `pack: downloading 45.3% of 117.2 KiB, down 1.2 MiB/s, up 0 B/s, ETA 12s, peers: 3 connected, 10 known`
+pub fn summary(name: &str, status: &TorrentStatus) -> String {
+    let percent = match status.wanted {
+        0 => 100.0,
+        wanted => status.done as f64 * 100.0 / wanted as f64,
+    };
+    let eta = status.eta.map_or_else(|| String::from("unknown"), format_duration);
+
+    format!(
+        "{name}: {} {percent:.1}% of {}, down {}/s, up {}/s, ETA {eta}, peers: {} connected, {} known",
+        status.state,
+        format_bytes(status.wanted as f64),
+        format_bytes(status.download_rate),
+        format_bytes(status.upload_rate),
+        status.peers,
+        status.known_peers,
+    )
+}
+
+/// `  127.0.0.1:7001 qBittorrent 4.2.5: down 1.2 MiB/s, up 0 B/s`
+pub fn peer_line(peer: &PeerStatus) -> String {
+    let client = peer.client.as_ref().map(|client| format!(" {client}")).unwrap_or_default();
+    format!(
+        "  {}{client}: down {}/s, up {}/s",
+        peer.address,
+        format_bytes(peer.download_rate),
+        format_bytes(peer.upload_rate),
+    )
+}
+
+/// Binary units with one decimal, `117.2 KiB`
+pub fn format_bytes(bytes: f64) -> String {
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{value:.0} B"),
+        _ => format!("{value:.1} {}", UNITS[unit]),
+    }
+}
+
+/// `12s`, `3m05s` or `2h07m`
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..60 => format!("{seconds}s"),
+        60..3600 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
@@ -0,0 +1,151 @@
+use std::{path::{Path, PathBuf}, time::UNIX_EPOCH};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use crate::{bitfield::Bitfield, storage::DiskStorage};
+
+
+/// Progress of a download, stored next to it so that a restarted download
+/// skips pieces that were already verified
+///
+/// Files are only trusted if they were flushed and left alone since the data was
+/// saved: the data is saved clean on every flush and marked dirty before the next
+/// write, so an interrupted download is only rechecked if it wasn't shut down cleanly
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumeData {
+    /// Info hash of the torrent this data belongs to
+    #[serde(rename = "info hash", with = "serde_bytes")]
+    pub info_hash: Vec<u8>,
+
+    /// Bitfield of pieces whose hash was verified
+    #[serde(with = "serde_bytes")]
+    pub pieces: Vec<u8>,
+
+    /// State of files on disk at the moment the data was saved, empty unless it is clean
+    pub files: Vec<ResumeFile>,
+
+    /// 1 if files were flushed when the data was saved and have not been written since
+    #[serde(default)]
+    pub clean: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeFile {
+    pub path: String,
+
+    pub length: u64,
+
+    /// Modification time in nanoseconds since UNIX epoch
+    pub mtime: u64,
+}
+
+impl ResumeFile {
+    fn read(path: &Path) -> Result<Self> {
+        let metadata = std::fs::metadata(path).context(format!("read metadata of {}", path.display()))?;
+        let mtime = metadata
+            .modified()
+            .context("read file modification time")?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+
+        Ok(Self {
+            path: path.display().to_string(),
+            length: metadata.len(),
+            mtime,
+        })
+    }
+}
+
+impl ResumeData {
+    /// Data of flushed files, their sizes and modification times are recorded
+    pub fn capture(info_hash: [u8; 20], verified: &Bitfield, storage: &DiskStorage) -> Result<Self> {
+        Ok(Self {
+            info_hash: info_hash.to_vec(),
+            pieces: verified.0.clone(),
+            files: storage.paths().map(ResumeFile::read).collect::<Result<_>>()?,
+            clean: 1,
+        })
+    }
+
+    /// Data of files that are being written, never trusted on its own
+    pub fn dirty(info_hash: [u8; 20], verified: &Bitfield) -> Self {
+        Self { info_hash: info_hash.to_vec(), pieces: verified.0.clone(), files: Vec::new(), clean: 0 }
+    }
+
+    /// Reads resume data, `None` if there is no resume file or it can't be parsed
+    pub fn load(path: &Path) -> Option<Self> {
+        let bytes = std::fs::read(path).ok()?;
+        serde_bencode::from_bytes(&bytes).ok()
+    }
+
+    /// Writes resume data into a temporary file first, so a crash never leaves a half written resume file
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let bytes = serde_bencode::to_bytes(self).context("encode resume data")?;
+        let temporary = path.with_extension("resume.tmp");
+        std::fs::write(&temporary, bytes).context(format!("write {}", temporary.display()))?;
+        std::fs::rename(&temporary, path).context(format!("replace {}", path.display()))?;
+
+        Ok(())
+    }
+
+    /// Resume data can be trusted only if it is for the same torrent, was saved clean
+    /// and no file changed size or modification time since
+    pub fn is_current(&self, info_hash: [u8; 20], storage: &DiskStorage) -> bool {
+        self.clean == 1
+            && self.info_hash == info_hash
+            && storage
+                .paths()
+                .map(|path| ResumeFile::read(path).ok())
+                .eq(self.files.iter().map(|file| Some(file.clone())))
+    }
+}
+
+/// Tracks verified pieces of a download and keeps the resume file up to date
+pub struct Resume {
+    path: PathBuf,
+    info_hash: [u8; 20],
+    pub verified: Bitfield,
+
+    /// Whether the resume file is saved clean, it has to be marked dirty before files are written
+    clean: bool,
+}
+
+impl Resume {
+    pub fn new(path: PathBuf, info_hash: [u8; 20], pieces: usize) -> Self {
+        Self { path, info_hash, verified: Bitfield::new(pieces), clean: false }
+    }
+
+    /// Takes verified pieces from the resume file if it's current. Returns whether it was
+    pub fn load(&mut self, storage: &DiskStorage) -> bool {
+        match ResumeData::load(&self.path) {
+            Some(data) if data.is_current(self.info_hash, storage) && data.pieces.len() == self.verified.0.len() => {
+                self.verified = Bitfield(data.pieces);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.clean
+    }
+
+    /// Saves verified pieces with the state of files, which must be flushed
+    pub fn save_clean(&mut self, storage: &DiskStorage) -> Result<()> {
+        ResumeData::capture(self.info_hash, &self.verified, storage)?.save(&self.path)?;
+        self.clean = true;
+
+        Ok(())
+    }
+
+    /// Marks the resume file dirty before files are written again, so that an
+    /// interrupted download is rechecked even if modification times didn't change
+    pub fn mark_dirty(&mut self) -> Result<()> {
+        if self.clean {
+            ResumeData::dirty(self.info_hash, &self.verified).save(&self.path)?;
+            self.clean = false;
+        }
+
+        Ok(())
+    }
+}
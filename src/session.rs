@@ -0,0 +1,1072 @@
+use std::{collections::HashMap, fmt, future::Future, net::{IpAddr, SocketAddr}, path::PathBuf, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, Weak}, time::Duration};
+use anyhow::{Context, Result};
+use tokio::{net::{TcpListener, TcpStream}, sync::{broadcast, mpsc, watch, Semaphore}, task::{JoinHandle, JoinSet}, time::Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+use crate::{
+    bitfield::Bitfield,
+    event::{self, Event, EventKind, Events},
+    limit::{Limits, RateLimiter},
+    files::FileSelection,
+    handshake::Handshake,
+    lsd::{Lsd, LsdOptions},
+    peer_connection::{Misbehavior, PeerConnection, PeerTimeouts},
+    peer_id::{Client, PeerId},
+    picker::PiecePicker,
+    smart_ban::SmartBan,
+    storage::{missing_pieces, DiskStorage, Storage},
+    swarm::{PeerSource, Swarm},
+    torrent::Torrent,
+    tracker::session::TrackerSession,
+    transfer::{Rates, Transfer},
+};
+
+
+/// Wait after accepting an incoming connection failed
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How often transfer rates are sampled
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often running torrents are announced to the local network, newly started ones
+/// go out within a second
+const LSD_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Local network announces of a peer for a torrent are heard at most this often
+const LSD_HEARD_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct SessionOptions {
+    /// Address peers connect to, its port is announced to trackers
+    pub listen: SocketAddr,
+
+    /// Where torrents are saved unless added with their own directory
+    pub download_directory: PathBuf,
+
+    /// Torrents downloading at once, the rest wait in the queue
+    pub max_active_downloads: usize,
+
+    /// Complete torrents seeding at once
+    pub max_active_seeds: usize,
+
+    /// Peer connections of all torrents together, incoming ones included
+    pub max_connections: usize,
+
+    /// Peer connections of a single torrent, incoming ones included
+    pub max_connections_per_torrent: usize,
+
+    /// Bytes per second received by all torrents together, unlimited when `None`
+    pub download_limit: Option<usize>,
+
+    /// Bytes per second sent by all torrents together, unlimited when `None`
+    pub upload_limit: Option<usize>,
+
+    pub timeouts: PeerTimeouts,
+
+    /// Finds peers on the local network without a tracker, turned off when `None`
+    pub lsd: Option<LsdOptions>,
+
+    /// Start of the peer id generated for the session, [`crate::peer_id::default_prefix`] when `None`
+    pub peer_id_prefix: Option<String>,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([0, 0, 0, 0], 6881)),
+            download_directory: PathBuf::from("./downloads"),
+            max_active_downloads: 3,
+            max_active_seeds: 5,
+            max_connections: 200,
+            max_connections_per_torrent: 50,
+            download_limit: None,
+            upload_limit: None,
+            timeouts: PeerTimeouts::default(),
+            lsd: Some(LsdOptions::default()),
+            peer_id_prefix: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AddTorrentOptions {
+    /// Overrides [`SessionOptions::download_directory`]
+    pub directory: Option<PathBuf>,
+
+    pub selection: FileSelection,
+
+    /// Add without starting it
+    pub paused: bool,
+
+    /// Bytes per second received by this torrent, on top of the session limit
+    pub download_limit: Option<usize>,
+
+    /// Bytes per second sent by this torrent, on top of the session limit
+    pub upload_limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    /// Waiting for a free download or seed slot
+    Queued,
+
+    /// Finding out which pieces are already on disk
+    Checking,
+
+    Downloading,
+
+    /// Every selected piece is verified, uploading to peers that ask
+    Seeding,
+
+    Paused,
+
+    /// Stopped by an error, until it is resumed
+    Error(String),
+}
+
+impl fmt::Display for TorrentState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Queued => f.write_str("queued"),
+            Self::Checking => f.write_str("checking"),
+            Self::Downloading => f.write_str("downloading"),
+            Self::Seeding => f.write_str("seeding"),
+            Self::Paused => f.write_str("paused"),
+            Self::Error(error) => write!(f, "error: {error}"),
+        }
+    }
+}
+
+/// Client session running any number of torrents: owns the listener for incoming
+/// peers, the peer id and limits shared by all torrents. Torrents beyond
+/// [`SessionOptions::max_active_downloads`] and [`SessionOptions::max_active_seeds`]
+/// are queued and start in the order they were added
+///
+/// Peers come from trackers, local service discovery, incoming connections and
+/// [`TorrentHandle::add_peers`]
+pub struct Session {
+    inner: Arc<SessionInner>,
+    listener: JoinHandle<()>,
+    lsd: Option<JoinHandle<()>>,
+}
+
+struct SessionInner {
+    options: SessionOptions,
+
+    /// Sent to trackers and peers by every torrent of the session
+    peer_id: PeerId,
+
+    /// Bound listener address, differs from the options if port 0 was asked for
+    listen: SocketAddr,
+
+    /// Budget of peer connections shared by all torrents
+    connections: Arc<Semaphore>,
+
+    /// Rate limits shared by all torrents
+    download_limit: Arc<RateLimiter>,
+    upload_limit: Arc<RateLimiter>,
+
+    /// Torrents in the order they were added, which is also the queue order
+    torrents: Mutex<Vec<Entry>>,
+
+    /// Set by [`Session::shutdown`], so that stopped torrents don't get started again
+    shutting_down: AtomicBool,
+
+    events: broadcast::Sender<Event>,
+}
+
+struct Entry {
+    shared: Arc<TorrentShared>,
+    task: Option<Task>,
+}
+
+/// Running torrent, cancelling it makes the task announce `stopped` and end
+struct Task {
+    cancel: CancellationToken,
+    handle: JoinHandle<()>,
+}
+
+struct TorrentShared {
+    torrent: Arc<Torrent>,
+    info_hash: [u8; 20],
+
+    /// Our peer id, the session's
+    peer_id: PeerId,
+
+    directory: PathBuf,
+    selection: FileSelection,
+    transfer: Arc<Transfer>,
+    state: watch::Sender<TorrentState>,
+    paused: AtomicBool,
+    events: Events,
+
+    /// Bytes of selected pieces
+    wanted: usize,
+
+    /// Sampled while the torrent runs
+    rates: Mutex<Rates>,
+
+    /// Connected peers, incoming ones included
+    connections: Mutex<HashMap<SocketAddr, Connection>>,
+
+    /// Addresses to dial, established connections and bans, kept while the torrent is in the session
+    swarm: Mutex<Swarm>,
+
+    /// Finds peers that sent pieces failing the hash check
+    smart_ban: Arc<SmartBan>,
+
+    download_limit: Arc<RateLimiter>,
+    upload_limit: Arc<RateLimiter>,
+
+    /// Known once the storage was checked
+    complete: AtomicBool,
+
+    /// Storage and pieces while the torrent runs, for serving peers that connect to us
+    active: Mutex<Option<Active>>,
+}
+
+struct Connection {
+    client: Option<Client>,
+    transfer: Arc<Transfer>,
+    rates: Rates,
+
+    /// Drops the connection once its peer is banned
+    cancel: CancellationToken,
+}
+
+#[derive(Clone)]
+struct Active {
+    storage: Arc<DiskStorage>,
+    picker: Arc<PiecePicker>,
+}
+
+impl Session {
+    /// Starts listening for incoming peers
+    pub async fn new(options: SessionOptions) -> Result<Self> {
+        let peer_id = match &options.peer_id_prefix {
+            Some(prefix) => PeerId::with_prefix(prefix)?,
+            None => PeerId::generate(),
+        };
+
+        let listener = TcpListener::bind(options.listen).await.context(format!("listen on {}", options.listen))?;
+        let inner = Arc::new(SessionInner {
+            peer_id,
+            listen: listener.local_addr().context("read listener address")?,
+            connections: Arc::new(Semaphore::new(options.max_connections)),
+            download_limit: Arc::new(RateLimiter::new(options.download_limit)),
+            upload_limit: Arc::new(RateLimiter::new(options.upload_limit)),
+            torrents: Mutex::new(Vec::new()),
+            shutting_down: AtomicBool::new(false),
+            events: broadcast::Sender::new(event::CAPACITY),
+            options,
+        });
+
+        let listener = tokio::spawn(accept(listener, Arc::downgrade(&inner)));
+
+        // The network may not allow multicast, which is no reason to fail
+        let lsd = inner.options.lsd.as_ref().and_then(|options| match Lsd::bind(options) {
+            Ok(lsd) => Some(tokio::spawn(discover(lsd, Arc::downgrade(&inner)))),
+            Err(error) => {
+                tracing::warn!("local service discovery is off: {error:#}");
+                None
+            }
+        });
+
+        Ok(Self { inner, listener, lsd })
+    }
+
+    pub fn listen_address(&self) -> SocketAddr {
+        self.inner.listen
+    }
+
+    /// Peer id sent to trackers and peers, see [`SessionOptions::peer_id_prefix`] to choose its start
+    pub fn peer_id(&self) -> PeerId {
+        self.inner.peer_id
+    }
+
+    /// Changes the session-wide download limit, connections already open follow it right away
+    pub fn set_download_limit(&self, bytes_per_second: Option<usize>) {
+        self.inner.download_limit.set_rate(bytes_per_second);
+    }
+
+    /// Changes the session-wide upload limit, connections already open follow it right away
+    pub fn set_upload_limit(&self, bytes_per_second: Option<usize>) {
+        self.inner.upload_limit.set_rate(bytes_per_second);
+    }
+
+    /// Events of every torrent from now on. Subscribers that fall behind by more than
+    /// [`event::CAPACITY`] events miss the oldest ones
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.inner.events.subscribe()
+    }
+
+    /// Adds a torrent, it starts right away if there is a free slot
+    pub fn add(&self, torrent: Torrent, options: AddTorrentOptions) -> Result<TorrentHandle> {
+        let wanted = torrent.wanted_pieces(&options.selection);
+        anyhow::ensure!(!wanted.is_empty(), "selection does not match any file");
+        let wanted = wanted.into_iter().map(|index| torrent.piece_size(index)).sum();
+
+        let info_hash = torrent.info_hash()?;
+        anyhow::ensure!(self.get(&info_hash).is_none(), "torrent {} is already added", hex::encode(info_hash));
+
+        let state = if options.paused { TorrentState::Paused } else { TorrentState::Queued };
+        let shared = Arc::new(TorrentShared {
+            torrent: Arc::new(torrent),
+            info_hash,
+            directory: options.directory.unwrap_or_else(|| self.inner.options.download_directory.clone()),
+            selection: options.selection,
+            transfer: Arc::new(Transfer::new(wanted)),
+            state: watch::Sender::new(state),
+            paused: AtomicBool::new(options.paused),
+            events: Events::new(info_hash, self.inner.events.clone()),
+            wanted,
+            rates: Mutex::new(Rates::default()),
+            connections: Mutex::new(HashMap::new()),
+            peer_id: self.inner.peer_id,
+            swarm: Mutex::new(Swarm::new(self.inner.peer_id)),
+            smart_ban: Arc::new(SmartBan::new()),
+            download_limit: Arc::new(RateLimiter::new(options.download_limit)),
+            upload_limit: Arc::new(RateLimiter::new(options.upload_limit)),
+            complete: AtomicBool::new(false),
+            active: Mutex::new(None),
+        });
+
+        self.inner.lock().push(Entry { shared: shared.clone(), task: None });
+        self.inner.schedule();
+
+        Ok(TorrentHandle { shared, session: Arc::downgrade(&self.inner) })
+    }
+
+    /// Stops the torrent and forgets it, downloaded files are kept
+    pub fn remove(&self, info_hash: &[u8; 20]) -> bool {
+        let removed = {
+            let mut torrents = self.inner.lock();
+            let position = torrents.iter().position(|entry| entry.shared.info_hash == *info_hash);
+            position.map(|position| torrents.remove(position))
+        };
+
+        let Some(mut entry) = removed else {
+            return false;
+        };
+
+        entry.stop();
+        self.inner.schedule();
+        true
+    }
+
+    pub fn get(&self, info_hash: &[u8; 20]) -> Option<TorrentHandle> {
+        self.torrents().into_iter().find(|handle| handle.shared.info_hash == *info_hash)
+    }
+
+    /// Every torrent in queue order
+    pub fn torrents(&self) -> Vec<TorrentHandle> {
+        self.inner
+            .lock()
+            .iter()
+            .map(|entry| TorrentHandle { shared: entry.shared.clone(), session: Arc::downgrade(&self.inner) })
+            .collect()
+    }
+
+    /// Stops every torrent, waiting for their `stopped` announces
+    pub async fn shutdown(self) {
+        self.listener.abort();
+        if let Some(lsd) = &self.lsd {
+            lsd.abort();
+        }
+        self.inner.shutting_down.store(true, Ordering::Relaxed);
+
+        let tasks = self.inner.lock().iter_mut().filter_map(|entry| entry.task.take()).collect::<Vec<_>>();
+        for task in &tasks {
+            task.cancel.cancel();
+        }
+        for task in tasks {
+            let _ = task.handle.await;
+        }
+    }
+}
+
+impl SessionInner {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Entry>> {
+        self.torrents.lock().expect("session lock is poisoned")
+    }
+
+    /// Starts and stops torrents so that active downloads and seeds stay within limits
+    fn schedule(self: &Arc<Self>) {
+        if self.shutting_down.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut torrents = self.lock();
+        let mut downloads = 0;
+        let mut seeds = 0;
+
+        for entry in torrents.iter_mut() {
+            if entry.task.as_ref().is_some_and(|task| task.handle.is_finished()) {
+                entry.task = None;
+            }
+
+            let shared = entry.shared.clone();
+            if shared.paused.load(Ordering::Relaxed) {
+                entry.stop();
+                shared.set_state(TorrentState::Paused);
+                continue;
+            }
+
+            if matches!(*shared.state.borrow(), TorrentState::Error(_)) {
+                continue;
+            }
+
+            let (active, limit) = if shared.complete.load(Ordering::Relaxed) {
+                (&mut seeds, self.options.max_active_seeds)
+            } else {
+                (&mut downloads, self.options.max_active_downloads)
+            };
+
+            // A task still stopping takes the slot, its end schedules the torrent again
+            if *active < limit {
+                *active += 1;
+                if entry.task.is_none() {
+                    entry.task = Some(self.start(shared));
+                }
+            } else {
+                entry.stop();
+                shared.set_state(TorrentState::Queued);
+            }
+        }
+    }
+
+    fn start(self: &Arc<Self>, shared: Arc<TorrentShared>) -> Task {
+        let cancel = CancellationToken::new();
+        let session = Arc::downgrade(self);
+        let connections = self.connections.clone();
+        let options = self.options.clone();
+        let port = self.listen.port();
+        let limits = self.limits(&shared);
+        let span = shared.span();
+
+        let handle = tokio::spawn({
+            let cancel = cancel.clone();
+            async move {
+                let state = match run(&shared, &session, &cancel, connections, &options, port, limits).await {
+                    Err(error) => {
+                        let error = format!("{error:#}");
+                        shared.events.send(EventKind::Error(error.clone()));
+                        TorrentState::Error(error)
+                    }
+                    Ok(()) if shared.paused.load(Ordering::Relaxed) => TorrentState::Paused,
+                    Ok(()) => TorrentState::Queued,
+                };
+                shared.set_state(state);
+
+                *shared.active.lock().expect("active torrent lock is poisoned") = None;
+                shared.rates.lock().expect("rates lock is poisoned").reset();
+                if let Some(session) = session.upgrade() {
+                    // Only now may the torrent start again, with its storage flushed and trackers told
+                    if let Some(entry) = session.lock().iter_mut().find(|entry| Arc::ptr_eq(&entry.shared, &shared)) {
+                        entry.task = None;
+                    }
+                    session.schedule();
+                }
+            }
+            .instrument(span)
+        });
+
+        Task { cancel, handle }
+    }
+
+    /// Connections of the torrent are subject to both session and torrent limits
+    fn limits(&self, shared: &TorrentShared) -> Limits {
+        Limits {
+            download: vec![self.download_limit.clone(), shared.download_limit.clone()],
+            upload: vec![self.upload_limit.clone(), shared.upload_limit.clone()],
+        }
+    }
+
+    /// Hands a peer that connected to us over to the torrent it asks for
+    async fn serve(&self, mut stream: TcpStream) -> Result<()> {
+        let handshake = tokio::time::timeout(self.options.timeouts.handshake, Handshake::receive(&mut stream))
+            .await
+            .context("peer didn't send a handshake in time")??;
+        anyhow::ensure!(handshake.length == 19 && handshake.bittorrent == *b"BitTorrent protocol", "peer doesn't speak BitTorrent");
+
+        let shared = self
+            .lock()
+            .iter()
+            .find(|entry| entry.shared.info_hash == handshake.info_hash)
+            .map(|entry| entry.shared.clone())
+            .context("peer asked for a torrent that is not in the session")?;
+
+        let active = shared.active.lock().expect("active torrent lock is poisoned").clone().context("torrent is not active")?;
+
+        let address = stream.peer_addr().context("read peer address")?;
+        {
+            let swarm = shared.swarm();
+            anyhow::ensure!(!swarm.is_banned(address.ip()), "peer is banned");
+            anyhow::ensure!(swarm.connections() < self.options.max_connections_per_torrent, "torrent has enough peers");
+        }
+
+        Handshake::new(&shared.torrent, shared.peer_id)?.send(&mut stream).await?;
+
+        let peer_id = PeerId(handshake.peer_id);
+        let span = tracing::info_span!(parent: &shared.span(), "peer", %address, incoming = true);
+        async {
+            let mut peer = PeerConnection::accept(&shared.torrent, stream, peer_id)
+                .with_limits(self.limits(&shared))
+                .with_timeouts(self.options.timeouts);
+            let connected = ConnectedPeer::new(&shared, address, &peer)?;
+            let result = connected.run(peer.serve(active.storage.as_ref(), &active.picker, &shared.transfer)).await;
+            connected.finish(result);
+            anyhow::Ok(())
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+impl TorrentShared {
+    /// Changes the state, reporting it to subscribers unless it stays the same
+    fn set_state(&self, state: TorrentState) {
+        let changed = self.state.send_if_modified(|current| {
+            let changed = *current != state;
+            *current = state.clone();
+            changed
+        });
+
+        if changed {
+            tracing::info!(?state, "state changed");
+            self.events.send(EventKind::StateChanged(state));
+        }
+    }
+
+    fn connections(&self) -> std::sync::MutexGuard<'_, HashMap<SocketAddr, Connection>> {
+        self.connections.lock().expect("connections lock is poisoned")
+    }
+
+    fn swarm(&self) -> std::sync::MutexGuard<'_, Swarm> {
+        self.swarm.lock().expect("swarm lock is poisoned")
+    }
+
+    /// Refuses the peer's IP from now on and drops its connections, reporting it unless it was banned already
+    fn ban(&self, address: SocketAddr, reason: &str) {
+        if !self.swarm().ban(address.ip(), reason.to_string()) {
+            return;
+        }
+
+        tracing::warn!(%address, %reason, "peer banned");
+        self.events.send(EventKind::PeerBanned { address, reason: reason.to_string() });
+        for (connected, connection) in self.connections().iter() {
+            if connected.ip() == address.ip() {
+                connection.cancel.cancel();
+            }
+        }
+    }
+
+    /// Span every log line of the torrent belongs to
+    fn span(&self) -> tracing::Span {
+        tracing::info_span!("torrent", torrent = %self.torrent.info.name, info_hash = %hex::encode(self.info_hash))
+    }
+}
+
+/// Counts a peer connection of a torrent and reports it to subscribers, until dropped
+struct ConnectedPeer<'a> {
+    shared: &'a TorrentShared,
+    address: SocketAddr,
+    cancel: CancellationToken,
+    error: Option<String>,
+}
+
+impl<'a> ConnectedPeer<'a> {
+    /// Fails for peers the torrent is connected to already, banned ones and ourselves
+    fn new(shared: &'a TorrentShared, address: SocketAddr, peer: &PeerConnection) -> Result<Self> {
+        let peer_id = peer.peer_id();
+        shared.swarm().connected(address, peer_id)?;
+
+        let client = peer_id.client();
+        tracing::debug!(%peer_id, client = client.as_ref().map(tracing::field::display), "peer connected");
+
+        let cancel = CancellationToken::new();
+        let connection = Connection { client: client.clone(), transfer: peer.transfer(), rates: Rates::default(), cancel: cancel.clone() };
+        shared.connections().insert(address, connection);
+        shared.events.send(EventKind::PeerConnected { address, client });
+        Ok(Self { shared, address, cancel, error: None })
+    }
+
+    /// Runs the exchange with the peer until it ends or the peer is banned
+    async fn run(&self, exchange: impl Future<Output = Result<()>>) -> Result<()> {
+        tokio::select! {
+            result = exchange => result,
+            _ = self.cancel.cancelled() => Err(anyhow::anyhow!("peer is banned")),
+        }
+    }
+
+    /// Connection ended, with the reason if it failed. Peers that misbehaved are banned
+    fn finish(mut self, result: Result<()>) {
+        let Err(error) = result else {
+            return;
+        };
+
+        if let Some(Misbehavior(reason)) = error.downcast_ref::<Misbehavior>() {
+            self.shared.ban(self.address, reason);
+        }
+
+        self.error = Some(format!("{error:#}"));
+    }
+}
+
+impl Drop for ConnectedPeer<'_> {
+    fn drop(&mut self) {
+        match &self.error {
+            Some(error) => tracing::debug!(%error, "peer disconnected"),
+            None => tracing::debug!("peer disconnected"),
+        }
+
+        self.shared.connections().remove(&self.address);
+        self.shared.swarm().disconnected(self.address);
+        self.shared.events.send(EventKind::PeerDisconnected { address: self.address, error: self.error.take() });
+    }
+}
+
+/// Address taken from [`Swarm::dial`], handed back once the attempt ends, however it ends
+struct Dial<'a> {
+    shared: &'a TorrentShared,
+    address: SocketAddr,
+    failed: bool,
+}
+
+impl Drop for Dial<'_> {
+    fn drop(&mut self) {
+        self.shared.swarm().dialled(self.address, self.failed);
+    }
+}
+
+impl Entry {
+    /// Cancels the task, which stays in the entry until it has finished so that the
+    /// torrent never runs twice at once
+    fn stop(&mut self) {
+        if let Some(task) = &self.task {
+            task.cancel.cancel();
+        }
+    }
+
+    /// Running and not stopping
+    fn is_running(&self) -> bool {
+        self.task.as_ref().is_some_and(|task| !task.cancel.is_cancelled())
+    }
+}
+
+async fn accept(listener: TcpListener, session: Weak<SessionInner>) {
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                // Such as running out of file descriptors, which retrying right away won't fix
+                tracing::warn!("accepting peer failed: {error}");
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let Some(session) = session.upgrade() else {
+            break;
+        };
+        let Ok(permit) = session.connections.clone().try_acquire_owned() else {
+            continue;
+        };
+
+        tokio::spawn(async move {
+            let _permit = permit;
+            if let Err(error) = session.serve(stream).await {
+                tracing::debug!(%address, "incoming peer rejected: {error:#}");
+            }
+        });
+    }
+}
+
+/// Announces running torrents to the local network and adds peers heard about to their swarms.
+/// Announces of a torrent go out at most every [`LSD_INTERVAL`], all due ones in a single message.
+/// Private torrents are left out both ways
+async fn discover(lsd: Lsd, session: Weak<SessionInner>) {
+    let mut announced = HashMap::<[u8; 20], Instant>::new();
+    let mut heard = HashMap::<(IpAddr, [u8; 20]), Instant>::new();
+    let mut ticks = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {
+                let Some(session) = session.upgrade() else {
+                    break;
+                };
+
+                let now = Instant::now();
+                let due = session
+                    .lock()
+                    .iter()
+                    .filter(|entry| entry.is_running() && !entry.shared.torrent.is_private())
+                    .map(|entry| entry.shared.info_hash)
+                    .filter(|info_hash| announced.get(info_hash).is_none_or(|at| now - *at >= LSD_INTERVAL))
+                    .collect::<Vec<_>>();
+                heard.retain(|_, at| now - *at < LSD_HEARD_INTERVAL);
+
+                if due.is_empty() {
+                    continue;
+                }
+
+                // Failures count as announced too, so that they are not retried every second
+                match lsd.announce(session.listen.port(), &due).await {
+                    Ok(()) => tracing::debug!(torrents = due.len(), "announced to local network"),
+                    Err(error) => tracing::warn!("local service discovery: {error:#}"),
+                }
+                announced.extend(due.into_iter().map(|info_hash| (info_hash, now)));
+            }
+
+            received = lsd.recv() => {
+                let (address, announce) = match received {
+                    Ok(received) => received,
+                    Err(error) => {
+                        tracing::debug!("local service discovery: {error:#}");
+                        continue;
+                    }
+                };
+                let Some(session) = session.upgrade() else {
+                    break;
+                };
+
+                let now = Instant::now();
+                for info_hash in announce.info_hashes {
+                    if heard.insert((address.ip(), info_hash), now).is_some() {
+                        continue;
+                    }
+
+                    let shared = session
+                        .lock()
+                        .iter()
+                        .find(|entry| entry.shared.info_hash == info_hash && !entry.shared.torrent.is_private())
+                        .map(|entry| entry.shared.clone());
+                    if let Some(shared) = shared {
+                        if shared.swarm().add(address, PeerSource::Lsd, None) {
+                            tracing::debug!(parent: &shared.span(), %address, "peer found on local network");
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Checks the storage, then downloads from the peers of its [`Swarm`] until cancelled.
+/// Once complete, the torrent keeps running to serve incoming peers
+async fn run(
+    shared: &Arc<TorrentShared>,
+    session: &Weak<SessionInner>,
+    cancel: &CancellationToken,
+    connections: Arc<Semaphore>,
+    options: &SessionOptions,
+    port: u16,
+    limits: Limits,
+) -> Result<()> {
+    shared.set_state(TorrentState::Checking);
+
+    let (storage, have, missing) = tokio::task::spawn_blocking({
+        let shared = shared.clone();
+        move || -> Result<_> {
+            let storage = DiskStorage::create(&shared.torrent, &shared.directory, &shared.selection)?;
+            let wanted = shared.torrent.wanted_pieces(&shared.selection);
+            let missing = missing_pieces(&storage, &wanted)?;
+
+            let mut have = Bitfield::new(shared.torrent.info.pieces.0.len());
+            for &index in wanted.difference(&missing) {
+                have.set(index);
+            }
+
+            Ok((Arc::new(storage), have, missing))
+        }
+    })
+    .await
+    .context("storage check panicked")??;
+
+    tracing::info!(verified = have.count(), missing = missing.len(), "storage checked");
+    shared.events.send(EventKind::Checked { verified: have.count(), wanted: have.count() + missing.len() });
+
+    let torrent = shared.torrent.clone();
+    let transfer = shared.transfer.clone();
+    transfer.set_left(missing.iter().map(|&index| torrent.piece_size(index)).sum());
+
+    let picker = Arc::new(PiecePicker::new(missing, have));
+    *shared.active.lock().expect("active torrent lock is poisoned") = Some(Active { storage: storage.clone(), picker: picker.clone() });
+
+    let mut complete = picker.is_complete();
+    set_complete(shared, session, complete);
+
+    let (announces_sender, mut announces) = mpsc::unbounded_channel();
+    let tracker = TrackerSession::new(&torrent, shared.peer_id, transfer.clone(), port)?.spawn(announces_sender);
+
+    let mut downloads = JoinSet::new();
+    let mut ticks = tokio::time::interval(RATE_INTERVAL);
+
+    loop {
+        let now = tokio::time::Instant::now();
+        let (next_dial, peers) = {
+            let swarm = shared.swarm();
+            (swarm.next_dial(), swarm.connections())
+        };
+        let wants_peer = !complete && peers < options.max_connections_per_torrent && picker.has_missing();
+
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+
+            Some(announce) = announces.recv() => match announce {
+                Ok(peers) => {
+                    shared.events.send(EventKind::Announced { peers: peers.len() });
+                    let mut swarm = shared.swarm();
+                    for (address, peer_id) in peers {
+                        swarm.add(address, PeerSource::Tracker, peer_id);
+                    }
+                }
+                Err(error) => {
+                    tracing::warn!("{error:#}");
+                    shared.events.send(EventKind::AnnounceFailed(format!("{error:#}")));
+                }
+            },
+
+            _ = ticks.tick() => {
+                shared.rates.lock().expect("rates lock is poisoned").sample(&transfer);
+                for connection in shared.connections().values_mut() {
+                    connection.rates.sample(&connection.transfer);
+                }
+
+                // Culprits of failed pieces may be found by another connection than theirs, or after they left
+                for (address, reason) in shared.smart_ban.take_convicted() {
+                    shared.ban(address, &reason);
+                }
+            }
+
+            // Addresses in backoff become due while nothing else happens
+            _ = tokio::time::sleep_until(next_dial.unwrap_or(now)), if wants_peer && next_dial.is_some_and(|at| at > now) => {}
+
+            permit = connections.clone().acquire_owned(), if wants_peer && next_dial.is_some_and(|at| at <= now) => {
+                let permit = permit.context("connection budget is closed")?;
+                let Some(address) = shared.swarm().dial() else {
+                    continue;
+                };
+                let (shared, storage, picker, limits) = (shared.clone(), storage.clone(), picker.clone(), limits.clone());
+                let timeouts = options.timeouts;
+
+                downloads.spawn(async move {
+                    let _permit = permit;
+                    let mut dial = Dial { shared: &shared, address, failed: true };
+                    let peer = match PeerConnection::connect(&shared.torrent, &address, shared.peer_id, timeouts).await {
+                        Ok(peer) => peer,
+                        Err(error) => return tracing::debug!("connection failed: {error:#}"),
+                    };
+
+                    let mut peer = peer.with_events(shared.events.clone()).with_smart_ban(shared.smart_ban.clone()).with_limits(limits);
+                    let connected = match ConnectedPeer::new(&shared, address, &peer) {
+                        Ok(connected) => connected,
+                        Err(error) => return tracing::debug!("connection dropped: {error:#}"),
+                    };
+                    let result = connected.run(async {
+                        peer.request_unchoke().await?;
+                        peer.download(storage.as_ref(), &picker, &shared.transfer).await
+                    });
+                    let result = result.await;
+                    dial.failed = result.is_err();
+                    connected.finish(result);
+                }
+                .instrument(tracing::info_span!("peer", %address)));
+            }
+
+            Some(_) = downloads.join_next() => {
+                if !complete && picker.is_complete() {
+                    complete = true;
+                    storage.flush()?;
+                    tracker.completed();
+                    set_complete(shared, session, complete);
+                }
+            }
+        }
+    }
+
+    downloads.shutdown().await;
+    tracker.stop().await?;
+    storage.flush()
+}
+
+/// Moves the torrent into downloading or seeding, rescheduling once it becomes complete
+fn set_complete(shared: &TorrentShared, session: &Weak<SessionInner>, complete: bool) {
+    // Both happen before the state changes, which is what wakes up `TorrentHandle::finished`
+    let became_complete = complete && !shared.complete.swap(true, Ordering::Relaxed);
+    if became_complete {
+        shared.events.send(EventKind::Finished);
+    }
+
+    let state = if complete { TorrentState::Seeding } else { TorrentState::Downloading };
+    shared.set_state(state);
+
+    if became_complete {
+        if let Some(session) = session.upgrade() {
+            session.schedule();
+        }
+    }
+}
+
+/// Controls a torrent of a [`Session`]
+#[derive(Clone)]
+pub struct TorrentHandle {
+    shared: Arc<TorrentShared>,
+    session: Weak<SessionInner>,
+}
+
+impl TorrentHandle {
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.shared.info_hash
+    }
+
+    pub fn torrent(&self) -> &Torrent {
+        &self.shared.torrent
+    }
+
+    pub fn state(&self) -> TorrentState {
+        self.shared.state.borrow().clone()
+    }
+
+    pub fn pause(&self) {
+        self.shared.paused.store(true, Ordering::Relaxed);
+        self.reschedule();
+    }
+
+    /// Changes the download limit of this torrent, the session limit still applies
+    pub fn set_download_limit(&self, bytes_per_second: Option<usize>) {
+        self.shared.download_limit.set_rate(bytes_per_second);
+    }
+
+    /// Changes the upload limit of this torrent, the session limit still applies
+    pub fn set_upload_limit(&self, bytes_per_second: Option<usize>) {
+        self.shared.upload_limit.set_rate(bytes_per_second);
+    }
+
+    /// Dials these peers besides those trackers return, returns how many were new.
+    /// Banned addresses are left out
+    pub fn add_peers(&self, addresses: impl IntoIterator<Item = SocketAddr>) -> usize {
+        let mut swarm = self.shared.swarm();
+        addresses.into_iter().filter(|address| swarm.add(*address, PeerSource::Manual, None)).count()
+    }
+
+    /// Resumes a paused torrent or retries one that failed
+    pub fn resume(&self) {
+        self.shared.paused.store(false, Ordering::Relaxed);
+        if matches!(self.state(), TorrentState::Paused | TorrentState::Error(_)) {
+            self.shared.set_state(TorrentState::Queued);
+        }
+        self.reschedule();
+    }
+
+    pub fn status(&self) -> TorrentStatus {
+        let shared = &self.shared;
+        let left = shared.transfer.left();
+        let (download_rate, upload_rate) = {
+            let rates = shared.rates.lock().expect("rates lock is poisoned");
+            (rates.download(), rates.upload())
+        };
+
+        let eta = match left {
+            0 => Some(Duration::ZERO),
+            _ if download_rate > 0.0 => Some(Duration::from_secs_f64(left as f64 / download_rate)),
+            _ => None,
+        };
+
+        TorrentStatus {
+            state: self.state(),
+            wanted: shared.wanted,
+            done: shared.wanted.saturating_sub(left),
+            downloaded: shared.transfer.downloaded(),
+            uploaded: shared.transfer.uploaded(),
+            download_rate,
+            upload_rate,
+            eta,
+            peers: shared.connections().len(),
+            known_peers: shared.swarm().len(),
+        }
+    }
+
+    /// Connected peers, ordered by address
+    pub fn peers(&self) -> Vec<PeerStatus> {
+        let mut peers = self.shared
+            .connections()
+            .iter()
+            .map(|(address, connection)| PeerStatus {
+                address: *address,
+                client: connection.client.clone(),
+                downloaded: connection.transfer.downloaded(),
+                uploaded: connection.transfer.uploaded(),
+                download_rate: connection.rates.download(),
+                upload_rate: connection.rates.upload(),
+            })
+            .collect::<Vec<_>>();
+
+        peers.sort_by_key(|peer| peer.address);
+        peers
+    }
+
+    /// Waits until every selected piece is verified, fails if the torrent stops with an error
+    pub async fn finished(&self) -> Result<()> {
+        let mut state = self.shared.state.subscribe();
+        let state = state
+            .wait_for(|state| matches!(state, TorrentState::Error(_)) || self.shared.complete.load(Ordering::Relaxed))
+            .await
+            .context("torrent was dropped")?;
+
+        match &*state {
+            TorrentState::Error(error) => anyhow::bail!("{error}"),
+            _ => Ok(()),
+        }
+    }
+
+    fn reschedule(&self) {
+        if let Some(session) = self.session.upgrade() {
+            session.schedule();
+        }
+    }
+}
+
+/// Progress of a torrent at one moment, see [`TorrentHandle::status`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct TorrentStatus {
+    pub state: TorrentState,
+
+    /// Bytes of selected pieces
+    pub wanted: usize,
+
+    /// Bytes of selected pieces that are verified
+    pub done: usize,
+
+    /// Bytes received from and sent to peers since the torrent was added
+    pub downloaded: usize,
+    pub uploaded: usize,
+
+    /// Bytes per second over the last few seconds
+    pub download_rate: f64,
+    pub upload_rate: f64,
+
+    /// Time left at the current download rate, unknown while nothing arrives
+    pub eta: Option<Duration>,
+
+    /// Connected peers, incoming ones included
+    pub peers: usize,
+
+    /// Distinct peer addresses known to dial
+    pub known_peers: usize,
+}
+
+/// Peer connected to a torrent, see [`TorrentHandle::peers`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStatus {
+    pub address: SocketAddr,
+    pub client: Option<Client>,
+
+    /// Bytes received from and sent to the peer over this connection
+    pub downloaded: usize,
+    pub uploaded: usize,
+
+    /// Bytes per second over the last few seconds
+    pub download_rate: f64,
+    pub upload_rate: f64,
+}
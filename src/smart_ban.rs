@@ -0,0 +1,137 @@
+use std::{collections::{hash_map::Entry, HashMap, HashSet, VecDeque}, net::{IpAddr, SocketAddr}, sync::Mutex};
+use sha1::{Digest, Sha1};
+
+
+/// Failed pieces an IP may take part in before it is banned, unless it is found guilty earlier
+const MAX_STRIKES: usize = 3;
+
+/// Failed pieces whose blocks are remembered, the oldest one is forgotten beyond that
+const MAX_FAILED_PIECES: usize = 64;
+
+/// Digest of a block as received, kept for blocks of pieces that failed the hash check
+pub type BlockDigest = [u8; 20];
+
+pub fn block_digest(data: &[u8]) -> BlockDigest {
+    Sha1::digest(data).into()
+}
+
+/// Finds the peers that send corrupt data, shared by every connection of a torrent
+///
+/// Every piece that fails the hash check is a strike against each IP that sent blocks
+/// of it, enough strikes get the IP banned. Digests of those blocks are remembered by
+/// sender: once the piece is downloaded again and passes, the senders of blocks that
+/// differ from the good data are the culprits and get banned right away, while the
+/// others get their strike back
+///
+/// [`crate::picker::PiecePicker`] hands a failed piece to a single connection, preferably
+/// to another peer, so the download that passes comes from a single peer and tells the
+/// good data apart from the bad
+#[derive(Debug, Default)]
+pub struct SmartBan {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Digest of every block of pieces that failed by offset and sender, by piece
+    failed: HashMap<usize, HashMap<(usize, SocketAddr), BlockDigest>>,
+
+    /// Pieces in `failed`, oldest first
+    order: VecDeque<usize>,
+
+    strikes: HashMap<IpAddr, usize>,
+
+    /// Banned IPs with the reason
+    convicted: HashMap<IpAddr, String>,
+
+    /// Convicted since the last [`SmartBan::take_convicted`]
+    reported: Vec<(SocketAddr, String)>,
+}
+
+impl SmartBan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Piece `index` failed the hash check, `blocks` are the offset, sender and digest of every block of it
+    pub fn piece_failed(&self, index: usize, blocks: Vec<(usize, SocketAddr, BlockDigest)>) {
+        let mut inner = self.lock();
+        let senders = blocks.iter().map(|&(_, sender, _)| (sender.ip(), sender)).collect::<HashMap<_, _>>();
+        if !inner.failed.contains_key(&index) {
+            inner.order.push_back(index);
+            if inner.order.len() > MAX_FAILED_PIECES {
+                let oldest = inner.order.pop_front().expect("order is not empty");
+                inner.failed.remove(&oldest);
+            }
+        }
+        inner.failed.entry(index).or_default().extend(blocks.into_iter().map(|(begin, sender, digest)| ((begin, sender), digest)));
+
+        for sender in senders.into_values() {
+            let strikes = inner.strikes.entry(sender.ip()).or_default();
+            *strikes += 1;
+
+            if *strikes >= MAX_STRIKES {
+                let strikes = *strikes;
+                inner.convict(sender, format!("sent {strikes} pieces that failed the hash check"));
+            }
+        }
+    }
+
+    /// Piece `index` passed the hash check, `blocks` are the offset and digest of every
+    /// block of it. Senders of earlier blocks that differ are convicted
+    pub fn piece_passed(&self, index: usize, blocks: &[(usize, BlockDigest)]) {
+        let mut inner = self.lock();
+        let Some(failed) = inner.failed.remove(&index) else {
+            return;
+        };
+        inner.order.retain(|&failed| failed != index);
+
+        let good = blocks.iter().copied().collect::<HashMap<_, _>>();
+        let (guilty, innocent): (Vec<_>, Vec<_>) = failed
+            .into_iter()
+            .map(|((begin, sender), digest)| (sender, good.get(&begin) == Some(&digest)))
+            .partition(|&(_, matches)| !matches);
+
+        let guilty = guilty.into_iter().map(|(sender, _)| sender).collect::<HashSet<_>>();
+        for &sender in &guilty {
+            inner.convict(sender, format!("sent corrupt data for piece {index}"));
+        }
+
+        let innocent = innocent.into_iter().map(|(sender, _)| sender.ip()).collect::<HashSet<_>>();
+        for ip in innocent {
+            if guilty.iter().all(|sender| sender.ip() != ip) {
+                if let Some(strikes) = inner.strikes.get_mut(&ip) {
+                    *strikes = strikes.saturating_sub(1);
+                }
+            }
+        }
+    }
+
+    /// Failed pieces the IP took part in and was not cleared of
+    pub fn strikes(&self, ip: IpAddr) -> usize {
+        self.lock().strikes.get(&ip).copied().unwrap_or_default()
+    }
+
+    /// Reason the IP was found guilty
+    pub fn convicted(&self, ip: IpAddr) -> Option<String> {
+        self.lock().convicted.get(&ip).cloned()
+    }
+
+    /// Peers convicted since the last call, with the reason
+    pub fn take_convicted(&self) -> Vec<(SocketAddr, String)> {
+        std::mem::take(&mut self.lock().reported)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().expect("smart ban lock is poisoned")
+    }
+}
+
+impl Inner {
+    fn convict(&mut self, sender: SocketAddr, reason: String) {
+        if let Entry::Vacant(entry) = self.convicted.entry(sender.ip()) {
+            entry.insert(reason.clone());
+            self.reported.push((sender, reason));
+        }
+    }
+}
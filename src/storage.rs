@@ -0,0 +1,366 @@
+use std::{collections::{BTreeMap, BTreeSet}, ops::Range, os::unix::fs::FileExt, path::{Path, PathBuf}, sync::{Mutex, RwLock, RwLockReadGuard}};
+use anyhow::{Context, Result};
+use sha1::{Digest, Sha1};
+use crate::{bitfield::Bitfield, files::{FileEntry, FileSelection}, paths::sanitize_component, resume::Resume, torrent::Torrent, verify::in_parallel};
+
+
+/// Pieces and files of a torrent, placed within the concatenated byte stream of all files
+#[derive(Debug, Clone)]
+pub struct Layout {
+    pub piece_length: usize,
+    pub total_length: usize,
+    pub piece_hashes: Vec<[u8; 20]>,
+    pub files: Vec<FileEntry>,
+}
+
+impl Layout {
+    pub fn new(torrent: &Torrent) -> Self {
+        Self {
+            piece_length: torrent.info.piece_length,
+            total_length: torrent.file_length(),
+            piece_hashes: torrent.info.pieces.0.clone(),
+            files: torrent.files(),
+        }
+    }
+
+    /// Size of piece at `index`, all pieces have the same size except possibly the last one
+    pub fn piece_size(&self, index: usize) -> usize {
+        if index == self.piece_hashes.len() - 1 {
+            self.total_length - index * self.piece_length
+        } else {
+            self.piece_length
+        }
+    }
+
+    /// Fails unless the block lies within a single piece of the torrent
+    pub fn check_block(&self, index: usize, begin: usize, length: usize) -> Result<()> {
+        anyhow::ensure!(
+            index < self.piece_hashes.len() && begin.checked_add(length).is_some_and(|end| end <= self.piece_size(index)),
+            "block of piece {index} at {begin} with length {length} is out of bounds",
+        );
+
+        Ok(())
+    }
+}
+
+/// Where downloaded blocks are kept. Blocks are addressed the same way as on the wire:
+/// by piece index and offset within the piece
+///
+/// Library users can implement it to keep data anywhere (object storage, a database),
+/// [`DiskStorage`] and [`MemoryStorage`] are provided
+pub trait Storage: Send + Sync {
+    fn layout(&self) -> &Layout;
+
+    fn read_block(&self, index: usize, begin: usize, length: usize) -> Result<Vec<u8>>;
+
+    fn write_block(&self, index: usize, begin: usize, block: &[u8]) -> Result<()>;
+
+    /// Makes sure everything written so far is persisted
+    fn flush(&self) -> Result<()>;
+
+    /// Reads the whole piece back and compares it with its hash from metainfo
+    fn verify_piece(&self, index: usize) -> Result<bool> {
+        let layout = self.layout();
+        let data = self.read_block(index, 0, layout.piece_size(index))?;
+        let hash: [u8; 20] = Sha1::digest(&data).into();
+
+        Ok(hash == layout.piece_hashes[index])
+    }
+
+    /// Finds which of the `wanted` pieces are already stored, before downloading anything.
+    /// By default every wanted piece is verified
+    fn restore(&self, wanted: &BTreeSet<usize>) -> Result<Bitfield> {
+        let wanted = wanted.iter().copied().collect::<Vec<_>>();
+        let mut verified = Bitfield::new(self.layout().piece_hashes.len());
+
+        for (index, valid) in in_parallel(&wanted, |index| self.verify_piece(index))? {
+            if valid {
+                verified.set(index);
+            }
+        }
+
+        Ok(verified)
+    }
+
+    /// Called once a downloaded piece passed verification
+    fn piece_verified(&self, _index: usize) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Restores `wanted` pieces from the storage and returns the ones that still have to be downloaded
+pub fn missing_pieces(storage: &dyn Storage, wanted: &BTreeSet<usize>) -> Result<BTreeSet<usize>> {
+    let verified = storage.restore(wanted)?;
+
+    Ok(wanted.iter().copied().filter(|&index| !verified.has(index)).collect())
+}
+
+/// Keeps the whole torrent in memory, mostly useful for tests
+pub struct MemoryStorage {
+    layout: Layout,
+    data: Mutex<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new(torrent: &Torrent) -> Self {
+        let layout = Layout::new(torrent);
+        let data = Mutex::new(vec![0; layout.total_length]);
+
+        Self { layout, data }
+    }
+
+    /// Content of all files concatenated
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data.into_inner().expect("memory storage lock is poisoned")
+    }
+
+    fn range(&self, index: usize, begin: usize, length: usize) -> Result<Range<usize>> {
+        self.layout.check_block(index, begin, length)?;
+        let start = index * self.layout.piece_length + begin;
+
+        Ok(start..start + length)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn read_block(&self, index: usize, begin: usize, length: usize) -> Result<Vec<u8>> {
+        let range = self.range(index, begin, length)?;
+        Ok(self.data.lock().expect("memory storage lock is poisoned")[range].to_vec())
+    }
+
+    fn write_block(&self, index: usize, begin: usize, block: &[u8]) -> Result<()> {
+        let range = self.range(index, begin, block.len())?;
+        self.data.lock().expect("memory storage lock is poisoned")[range].copy_from_slice(block);
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Files on disk that blocks of the selected pieces are written into
+///
+/// Only selected files are created. Wanted pieces that also span unselected files
+/// still have to be downloaded as a whole, bytes falling into unselected files are
+/// kept in a part-file where each such boundary piece gets its own slot
+///
+/// Existing files are opened without truncating, so data of an interrupted download
+/// is kept, and verified pieces are recorded in a resume file next to the download
+/// whenever files are flushed
+pub struct DiskStorage {
+    layout: Layout,
+    files: Vec<Option<OpenFile>>,
+    part_file: Option<OpenFile>,
+    part_slots: BTreeMap<usize, usize>,
+    fresh: bool,
+
+    /// Writes hold it shared, flushes exclusively so that no write lands after files are recorded clean
+    resume: RwLock<Resume>,
+}
+
+struct OpenFile {
+    path: PathBuf,
+    handle: std::fs::File,
+}
+
+impl OpenFile {
+    /// Opens (or creates) the file and sizes it to `length`. Returns whether it held any data before
+    fn open(path: PathBuf, length: usize) -> Result<(Self, bool)> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).context(format!("create directory {}", parent.display()))?;
+        }
+
+        let handle = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .context(format!("open file {}", path.display()))?;
+
+        let current_length = handle.metadata().context(format!("read metadata of {}", path.display()))?.len();
+        if current_length != length as u64 {
+            handle.set_len(length as u64).context(format!("resize file {}", path.display()))?;
+        }
+
+        Ok((Self { path, handle }, current_length > 0))
+    }
+}
+
+impl DiskStorage {
+    /// Opens selected files of the torrent under `directory`, creating it if it doesn't exist
+    pub fn create(torrent: &Torrent, directory: &Path, selection: &FileSelection) -> Result<Self> {
+        std::fs::create_dir_all(directory).context(format!("create directory {}", directory.display()))?;
+
+        let layout = Layout::new(torrent);
+        let name = sanitize_component(&torrent.info.name);
+        let wanted = torrent.wanted_pieces(selection);
+
+        let mut fresh = true;
+        let mut boundary_pieces = BTreeSet::new();
+        let mut files = Vec::new();
+        for file in &layout.files {
+            let handle = if selection.selects(file) {
+                let (handle, had_data) = OpenFile::open(directory.join(&file.path), file.length)?;
+                fresh &= !had_data;
+                Some(handle)
+            } else {
+                boundary_pieces.extend(file.pieces(layout.piece_length).filter(|piece| wanted.contains(piece)));
+                None
+            };
+
+            files.push(handle);
+        }
+
+        let part_file = if boundary_pieces.is_empty() {
+            None
+        } else {
+            let path = directory.join(format!("{name}.parts"));
+            let (handle, had_data) = OpenFile::open(path, boundary_pieces.len() * layout.piece_length)?;
+            fresh &= !had_data;
+            Some(handle)
+        };
+
+        let resume = Resume::new(
+            directory.join(format!("{name}.resume")),
+            torrent.info_hash()?,
+            layout.piece_hashes.len(),
+        );
+
+        Ok(Self {
+            layout,
+            files,
+            part_file,
+            part_slots: boundary_pieces.into_iter().enumerate().map(|(slot, piece)| (piece, slot)).collect(),
+            fresh,
+            resume: RwLock::new(resume),
+        })
+    }
+
+    /// True if none of the files existed or had any data before they were opened
+    pub fn is_fresh(&self) -> bool {
+        self.fresh
+    }
+
+    /// Paths of every file on disk, including the part-file
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files
+            .iter()
+            .flatten()
+            .chain(&self.part_file)
+            .map(|file| file.path.as_path())
+    }
+
+    /// Shared lock of the resume data once it is marked dirty, to be held while writing
+    fn writable(&self) -> Result<RwLockReadGuard<'_, Resume>> {
+        loop {
+            let resume = self.resume.read().expect("resume lock is poisoned");
+            if !resume.is_clean() {
+                return Ok(resume);
+            }
+
+            drop(resume);
+            self.resume.write().expect("resume lock is poisoned").mark_dirty()?;
+        }
+    }
+
+    /// Splits a block into parts, each with the file it is stored in, offset in that file and range within the block
+    fn locate(&self, index: usize, begin: usize, length: usize) -> Result<Vec<(&OpenFile, u64, Range<usize>)>> {
+        self.layout.check_block(index, begin, length)?;
+        let piece_length = self.layout.piece_length;
+        let start = index * piece_length + begin;
+        let end = start + length;
+
+        self.layout.files
+            .iter()
+            .zip(&self.files)
+            .filter(|(file, _)| file.offset < end && start < file.end())
+            .map(|(file, handle)| {
+                let from = start.max(file.offset);
+                let to = end.min(file.end());
+                let range = from - start..to - start;
+
+                match (handle, &self.part_file, self.part_slots.get(&index)) {
+                    (Some(handle), _, _) => Ok((handle, (from - file.offset) as u64, range)),
+                    (None, Some(part_file), Some(slot)) => {
+                        let offset = slot * piece_length + (from - index * piece_length);
+                        Ok((part_file, offset as u64, range))
+                    }
+                    (None, _, _) => Err(anyhow::anyhow!("piece {index} is not part of the selected files")),
+                }
+            })
+            .collect()
+    }
+}
+
+impl Storage for DiskStorage {
+    fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// Reads `length` bytes starting at `begin` inside piece `index`, collecting them across file boundaries
+    fn read_block(&self, index: usize, begin: usize, length: usize) -> Result<Vec<u8>> {
+        let mut block = vec![0; length];
+        for (file, offset, range) in self.locate(index, begin, length)? {
+            file.handle
+                .read_exact_at(&mut block[range], offset)
+                .context(format!("read block from {} at offset {}", file.path.display(), offset))?;
+        }
+
+        Ok(block)
+    }
+
+    /// Writes block that starts at `begin` inside piece `index`, splitting it across file boundaries
+    fn write_block(&self, index: usize, begin: usize, block: &[u8]) -> Result<()> {
+        let _resume = self.writable()?;
+        for (file, offset, range) in self.locate(index, begin, block.len())? {
+            file.handle
+                .write_all_at(&block[range], offset)
+                .context(format!("write block into {} at offset {}", file.path.display(), offset))?;
+        }
+
+        Ok(())
+    }
+
+    /// Syncs files to disk and saves the resume data clean
+    fn flush(&self) -> Result<()> {
+        let mut resume = self.resume.write().expect("resume lock is poisoned");
+        for file in self.files.iter().flatten().chain(&self.part_file) {
+            file.handle.sync_data().context(format!("flush {}", file.path.display()))?;
+        }
+
+        resume.save_clean(self)
+    }
+
+    /// Takes verified pieces from the resume file, falls back to verifying wanted pieces
+    /// when the resume data is missing, stale or left by a download that was not shut
+    /// down cleanly and files already contain something
+    fn restore(&self, wanted: &BTreeSet<usize>) -> Result<Bitfield> {
+        let mut resume = self.resume.write().expect("resume lock is poisoned");
+
+        if !resume.load(self) && !self.is_fresh() {
+            tracing::info!(pieces = wanted.len(), "resume data is missing or stale, rechecking");
+            for (index, valid) in in_parallel(&wanted.iter().copied().collect::<Vec<_>>(), |index| self.verify_piece(index))? {
+                if valid {
+                    resume.verified.set(index);
+                }
+            }
+        }
+
+        resume.save_clean(self)?;
+
+        Ok(resume.verified.clone())
+    }
+
+    /// Only recorded in memory, the resume file is saved on the next flush
+    fn piece_verified(&self, index: usize) -> Result<()> {
+        self.resume.write().expect("resume lock is poisoned").verified.set(index);
+        Ok(())
+    }
+}
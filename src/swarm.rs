@@ -0,0 +1,223 @@
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, time::Duration};
+use anyhow::Result;
+use tokio::time::Instant;
+use crate::peer_id::PeerId;
+
+
+/// Wait before dialling an address again after its connection failed, doubled with every failure in a row
+const RETRY_BASE: Duration = Duration::from_secs(15);
+
+/// Longest wait between attempts, however often an address failed
+const RETRY_MAX: Duration = Duration::from_secs(30 * 60);
+
+/// Addresses that failed this many times in a row are given up on
+const MAX_FAILURES: u32 = 8;
+
+/// Where a peer address came from. Variants are in order of preference, candidates
+/// from earlier ones are dialled first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PeerSource {
+    /// Added by hand, see [`crate::session::TorrentHandle::add_peers`]
+    Manual,
+
+    /// Announced on the local network, see [`crate::lsd`]
+    Lsd,
+
+    Tracker,
+}
+
+/// Peers of a torrent: addresses to dial with their backoff, established connections
+/// and banned addresses
+///
+/// Connections are deduplicated by address and by peer id, incoming ones included
+#[derive(Debug)]
+pub struct Swarm {
+    /// Our own peer id, connections to it are dropped and the address is never dialled again
+    own: PeerId,
+
+    candidates: HashMap<SocketAddr, Candidate>,
+
+    /// Established connections with the peer id from the handshake
+    connected: HashMap<SocketAddr, PeerId>,
+
+    /// Reason of the ban by IP, so that reconnecting from another port doesn't help
+    banned: HashMap<IpAddr, String>,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    source: PeerSource,
+    status: Status,
+
+    /// Sent along by the tracker, peers we are connected to already are not dialled
+    peer_id: Option<PeerId>,
+
+    /// Failed attempts in a row
+    failures: u32,
+    retry_at: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Idle,
+
+    /// Being dialled or connected
+    Busy,
+
+    /// Turned out to be ourselves
+    Own,
+
+    /// Failed too often. Kept so that trackers returning it again don't start over
+    GaveUp,
+}
+
+impl Swarm {
+    pub fn new(own: PeerId) -> Self {
+        Self { own, candidates: HashMap::new(), connected: HashMap::new(), banned: HashMap::new() }
+    }
+
+    /// Remembers an address to dial with the peer id the source knows it by, `false` if it
+    /// is known already, given up on or banned. Known addresses take the source if it is
+    /// preferred over theirs. Adding an address by hand tries it again after it was given up on
+    pub fn add(&mut self, address: SocketAddr, source: PeerSource, peer_id: Option<PeerId>) -> bool {
+        if self.is_banned(address.ip()) {
+            return false;
+        }
+
+        if let Some(candidate) = self.candidates.get_mut(&address) {
+            candidate.source = candidate.source.min(source);
+            candidate.peer_id = peer_id.or(candidate.peer_id);
+
+            let retry = candidate.status == Status::GaveUp && source == PeerSource::Manual;
+            if retry {
+                *candidate = Candidate { source, status: Status::Idle, peer_id, failures: 0, retry_at: Instant::now() };
+            }
+            return retry;
+        }
+
+        let candidate = Candidate { source, status: Status::Idle, peer_id, failures: 0, retry_at: Instant::now() };
+        self.candidates.insert(address, candidate);
+        true
+    }
+
+    /// Addresses known to dial, apart from those given up on
+    pub fn len(&self) -> usize {
+        self.candidates.values().filter(|candidate| candidate.status != Status::GaveUp).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Connections being dialled or established, incoming ones included
+    pub fn connections(&self) -> usize {
+        let dialling = self
+            .candidates
+            .iter()
+            .filter(|(address, candidate)| candidate.status == Status::Busy && !self.connected.contains_key(address))
+            .count();
+
+        dialling + self.connected.len()
+    }
+
+    /// Takes the preferred address that is due, to be handed back with [`Swarm::dialled`].
+    /// Ties go to the address that failed least, then to the one waiting longest
+    pub fn dial(&mut self) -> Option<SocketAddr> {
+        let now = Instant::now();
+        let address = self
+            .candidates
+            .iter()
+            .filter(|(address, candidate)| candidate.retry_at <= now && self.may_dial(address, candidate))
+            .min_by_key(|(_, candidate)| (candidate.source, candidate.failures, candidate.retry_at))
+            .map(|(address, _)| *address)?;
+
+        self.candidates.get_mut(&address).expect("address is a candidate").status = Status::Busy;
+        Some(address)
+    }
+
+    /// Earliest time an address can be dialled, which may be now already
+    pub fn next_dial(&self) -> Option<Instant> {
+        self.candidates
+            .iter()
+            .filter(|(address, candidate)| self.may_dial(address, candidate))
+            .map(|(_, candidate)| candidate.retry_at)
+            .min()
+    }
+
+    /// Idle and not banned, nor known by its peer id to be ourselves or a peer we are connected to
+    fn may_dial(&self, address: &SocketAddr, candidate: &Candidate) -> bool {
+        let known = candidate
+            .peer_id
+            .is_some_and(|peer_id| peer_id == self.own || self.connected.values().any(|connected| *connected == peer_id));
+
+        candidate.status == Status::Idle && !self.banned.contains_key(&address.ip()) && !known
+    }
+
+    /// Connection to an address from [`Swarm::dial`] ended. Failed addresses are tried
+    /// again after a backoff and given up on once they fail too often
+    pub fn dialled(&mut self, address: SocketAddr, failed: bool) {
+        let Some(candidate) = self.candidates.get_mut(&address) else {
+            return;
+        };
+        if candidate.status != Status::Busy {
+            return;
+        }
+
+        candidate.failures = if failed { candidate.failures + 1 } else { 0 };
+        if candidate.failures >= MAX_FAILURES {
+            candidate.status = Status::GaveUp;
+            return;
+        }
+
+        let backoff = RETRY_BASE.saturating_mul(1 << candidate.failures.saturating_sub(1)).min(RETRY_MAX);
+        candidate.status = Status::Idle;
+        candidate.retry_at = Instant::now() + backoff;
+    }
+
+    /// Registers a connection once handshakes are exchanged. Fails if the peer is
+    /// banned, ourselves, or connected already over another connection
+    pub fn connected(&mut self, address: SocketAddr, peer_id: PeerId) -> Result<()> {
+        if peer_id == self.own {
+            if let Some(candidate) = self.candidates.get_mut(&address) {
+                candidate.status = Status::Own;
+            }
+            anyhow::bail!("connected to ourselves");
+        }
+
+        if let Some(reason) = self.banned.get(&address.ip()) {
+            anyhow::bail!("peer is banned: {reason}");
+        }
+
+        anyhow::ensure!(
+            !self.connected.contains_key(&address) && !self.connected.values().any(|connected| *connected == peer_id),
+            "already connected to peer {peer_id}",
+        );
+
+        self.connected.insert(address, peer_id);
+        Ok(())
+    }
+
+    /// Connection registered with [`Swarm::connected`] ended
+    pub fn disconnected(&mut self, address: SocketAddr) {
+        self.connected.remove(&address);
+    }
+
+    /// Refuses the IP from now on, `false` if it was banned already
+    pub fn ban(&mut self, ip: IpAddr, reason: String) -> bool {
+        if self.banned.contains_key(&ip) {
+            return false;
+        }
+
+        self.banned.insert(ip, reason);
+        true
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.contains_key(&ip)
+    }
+
+    /// Number of banned IPs
+    pub fn banned(&self) -> usize {
+        self.banned.len()
+    }
+}
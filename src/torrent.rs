@@ -2,28 +2,39 @@ use std::path::PathBuf;
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use sha1::{Sha1, Digest};
-use crate::{piece::PieceChunked, tracker::{TrackerRequest, TrackerResponse}};
+use crate::{peer_id::PeerId, piece::PieceChunked, validate::validate_bytes, tracker::{TrackerClient, TrackerRequest, TrackerResponse}};
 
 
 /// A Metainfo files(also known as .torrent files)
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Torrent {
     /// The URL of the tracker
     pub announce: String,
 
+    /// Tiers of tracker URLs, tried in order (BEP 12)
+    #[serde(rename = "announce-list", default, skip_serializing_if = "Option::is_none")]
+    pub announce_list: Option<Vec<Vec<String>>>,
+
+    /// Creation time of the torrent, in seconds since UNIX epoch
+    #[serde(rename = "creation date", default, skip_serializing_if = "Option::is_none")]
+    pub creation_date: Option<i64>,
+
+    /// Name and version of the program used to create the torrent
+    #[serde(rename = "created by", default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+
+    /// Free-form textual comment of the author
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+
+    /// Web seed URLs serving the same content over HTTP (BEP 19)
+    #[serde(rename = "url-list", default, skip_serializing_if = "Option::is_none")]
+    pub url_list: Option<UrlList>,
+
     pub info: Info,
 }
 
 impl Torrent {
-    pub fn peer_id(&self) -> [u8; 20] {
-        *b"12345611111111111112"
-    }
-
-    pub fn output_file(&self) -> Result<std::fs::File, std::io::Error> {
-        let name = format!("./downloads/{}", self.info.name);
-        std::fs::File::create(name)
-    }
-
     pub fn info_hash(&self) -> Result<[u8; 20]> {
         let encoded = serde_bencode::to_bytes(&self.info).context("encode info secion")?;
         let mut hasher = Sha1::new();
@@ -34,64 +45,100 @@ impl Torrent {
         Ok(array)
     }
 
+    /// Info hash in RFC 4648 base32, the form used by older magnet links
+    pub fn info_hash_base32(&self) -> Result<String> {
+        const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+        let hash = self.info_hash()?;
+        let mut encoded = String::with_capacity(32);
+        for chunk in hash.chunks(5) {
+            let bits = chunk.iter().fold(0u64, |bits, byte| (bits << 8) | *byte as u64);
+            for shift in (0..8).rev() {
+                encoded.push(ALPHABET[(bits >> (shift * 5)) as usize & 0x1f] as char);
+            }
+        }
+
+        Ok(encoded)
+    }
+
+    /// Every tracker URL of the torrent, `announce-list` tiers in order or just `announce`
+    pub fn trackers(&self) -> Vec<String> {
+        self.tracker_tiers().into_iter().flatten().collect()
+    }
+
+    /// Non-empty `announce-list` tiers, or a single tier with `announce`
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        match &self.announce_list {
+            Some(tiers) if tiers.iter().any(|tier| !tier.is_empty()) => {
+                tiers.iter().filter(|tier| !tier.is_empty()).cloned().collect()
+            }
+            _ => vec![vec![self.announce.clone()]],
+        }
+    }
+
     pub fn file_length(&self) -> usize {
         match &self.info.keys {
             Keys::SingleFile { length } => *length,
-            Keys::MultiFile { files: _ } => 0,
+            Keys::MultiFile { files } => files.iter().map(|file| file.length).sum(),
         }
     }
 
-    pub async fn tracker_info(&self) -> Result<TrackerResponse> {
-        let request = self.tracker_request()?;
-
-        let tracker_url = request.url_params(&self.announce)?;
-        let tracker_info = reqwest::
-            get(tracker_url)
-            .await
-            .context("initiate GET request to tracker")?
-            .bytes()
-            .await
-            .context("fetch tracker response")
-            .map(|bytes| serde_bencode::from_bytes::<TrackerResponse>(&bytes))?
-            .context("bencode tracker response")?;
-
-        Ok(tracker_info)
+    /// Client of the `announce` tracker, to be passed to [`Torrent::tracker_info`]
+    pub fn tracker_client(&self) -> TrackerClient {
+        TrackerClient::new(self.announce.clone())
     }
 
-    fn tracker_request(&self) -> Result<TrackerRequest> {
+    /// Announces once to `tracker`, which sends back the `tracker id` it got on later calls
+    pub async fn tracker_info(&self, tracker: &mut TrackerClient, peer_id: PeerId) -> Result<TrackerResponse> {
+        let request = self.tracker_request(peer_id)?;
+        tracker.announce(&request).await
+    }
+
+    fn tracker_request(&self, peer_id: PeerId) -> Result<TrackerRequest> {
         let info_hash_bytes = self.info_hash()?;
         let file_length = self.file_length();
 
         Ok(TrackerRequest {
             info_hash: info_hash_bytes,
-            peer_id: String::from_utf8(self.peer_id().to_vec())?,
+            peer_id: String::from_utf8(peer_id.0.to_vec())?,
             port: 6881,
             uploaded: 0,
             downloaded: 0,
             left: file_length,
             compact: 1,
+            trackerid: None,
+            event: None,
+            numwant: None,
+            key: None,
         })
     }
 
+    /// Private torrents (BEP 27) get their peers from their trackers only
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(1)
+    }
+
+    /// Size of piece at `index`, all pieces have the same size except possibly the last one
+    pub fn piece_size(&self, index: usize) -> usize {
+        let last = index == self.info.pieces.0.len() - 1;
+        let piece_length = self.info.piece_length;
+
+        if last {
+            self.file_length() - (index * piece_length)
+        } else {
+            piece_length
+        }
+    }
+
     pub fn pieces_chunked(&self) -> impl Iterator<Item = PieceChunked> + use<'_> {
         self.info.pieces.0
             .iter()
             .enumerate()
             .map(|(index, hash)| {
-                let last = index == self.info.pieces.0.len() - 1;
-                let piece_length = self.info.piece_length;
-                let file_length = self.file_length();
-
-                let piece_size = if last {
-                    file_length - (index * piece_length)
-                } else {
-                    piece_length
-                };
-
                 PieceChunked::new(
                     index,
                     *hash,
-                    piece_size,
+                    self.piece_size(index),
                 )
             })
     }
@@ -100,13 +147,21 @@ impl Torrent {
 impl TryFrom<PathBuf> for Torrent {
     type Error = anyhow::Error;
 
+    /// Reads and validates a torrent file, any error found by [`validate_bytes`] fails the load
     fn try_from(path: PathBuf) -> Result<Self> {
         let file = std::fs::read(path).context("read torrent file")?;
-        Ok(serde_bencode::from_bytes(&file).context("parse torrent file")?)
+        let (torrent, report) = validate_bytes(&file);
+
+        if !report.is_valid() {
+            let errors = report.errors().map(|error| format!("\n  {error}")).collect::<String>();
+            anyhow::bail!("invalid torrent file:{errors}");
+        }
+
+        torrent.context("parse torrent file")
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Info {
     /// The suggested name to save the file (or directory) as. It is purely advisory
     /// 
@@ -123,12 +178,16 @@ pub struct Info {
     /// Each entry of pieces is the SHA1 hash of piece at corresponding index
     pub pieces: PieceHashes,
 
+    /// When set to 1, peers may only be obtained from trackers listed in the metainfo (BEP 27)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+
     #[serde(flatten)]
     pub keys: Keys,
 }
 
 /// There is also a key `length` or a key `files`, but not both or neither. 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Keys {
     /// If `length` is present then the download represents a single file,
@@ -145,16 +204,24 @@ pub enum Keys {
     },
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// `url-list` is allowed to be either a single URL or a list of them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum UrlList {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct File {
     /// The length of the file, in bytes
-    length: usize,
+    pub length: usize,
 
     /// List of UTF-8 encoded strings corresponding to subdirectory names, the last of which is the actual file name
-    path: Vec<String>
+    pub path: Vec<String>
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PieceHashes(pub Vec<[u8; 20]>);
 
 pub mod piece_hashes {
@@ -175,7 +242,7 @@ pub mod piece_hashes {
         where
             E: de::Error,
         {
-            if v.len() % 20 != 0 {
+            if !v.len().is_multiple_of(20) {
                 return Err(E::custom(format!("length is {}", v.len())));
             }
     
@@ -1,6 +1,12 @@
+use std::net::SocketAddr;
 use anyhow::{Context, Ok, Result};
 use serde::{Deserialize, Serialize};
 pub use peers::Peers;
+use crate::peer_id::PeerId;
+
+pub mod scrape;
+pub mod server;
+pub mod session;
 
 
 /// Note: the info_hash field is not included
@@ -30,10 +36,39 @@ pub struct TrackerRequest {
     /// The compact representation is more commonly used in the wild, 
     /// the non-compact representation is mostly supported for backward-compatibility.
     pub compact: u8,
+
+    /// `tracker id` from a previous response of the same tracker, sent back as is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trackerid: Option<String>,
+
+    /// Lifecycle event, omitted for regular reannounces
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<Event>,
+
+    /// Number of peers the client would like to receive
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numwant: Option<usize>,
+
+    /// Random value that lets the tracker recognise the client if its IP address changes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    /// First announce of a download
+    Started,
+
+    /// Download finished, not sent if the torrent was already complete when started
+    Completed,
+
+    /// Client is shutting down gracefully
+    Stopped,
 }
 
 impl TrackerRequest {
-    pub fn url_params(&self, url: &String) -> Result<String> {
+    pub fn url_params(&self, url: &str) -> Result<String> {
         let url_params = serde_urlencoded::to_string(self).context("encode TrackerRequest into URL query params")?;
         let mut tracker_url = reqwest::Url::parse(url).context("parse tracker URL")?;
         tracker_url.set_query(Some(&url_params));
@@ -43,14 +78,124 @@ impl TrackerRequest {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Tracker refused a request with `failure reason`, or can't serve it at all
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum TrackerError {
+    #[error("tracker refused the request: {0}")]
+    Failure(String),
+
+    /// Tracker has no scrape convention for its announce URL
+    #[error("tracker doesn't support scrape: {0}")]
+    ScrapeUnsupported(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TrackerResponse {
-    /// Indicating how often your client should make a request to the tracker in seconds
+    /// If present, the request failed and no other key is required to be present
+    #[serde(rename = "failure reason", default, skip_serializing_if = "Option::is_none")]
+    pub failure_reason: Option<String>,
+
+    /// Announce succeeded, but the tracker has something to say
+    #[serde(rename = "warning message", default, skip_serializing_if = "Option::is_none")]
+    pub warning_message: Option<String>,
+
+    /// Indicating how often your client should make a request to the tracker in seconds.
+    /// Only missing from failures, [`TrackerClient::announce`] refuses other responses without it
+    #[serde(default)]
     pub interval: usize,
 
+    /// Client must not reannounce more frequently than this, in seconds
+    #[serde(rename = "min interval", default, skip_serializing_if = "Option::is_none")]
+    pub min_interval: Option<usize>,
+
+    /// Should be sent back on next announces to the same tracker
+    #[serde(rename = "tracker id", default, skip_serializing_if = "Option::is_none")]
+    pub tracker_id: Option<String>,
+
+    /// Number of peers with the entire file (seeders)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub complete: Option<usize>,
+
+    /// Number of peers that are still downloading (leechers)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incomplete: Option<usize>,
+
     /// A string, which contains list of peers that your client can connect to.
     /// Each peer is represented using 6 bytes. The first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number.
+    #[serde(default)]
     pub peers: Peers,
+
+    /// Compact IPv6 peers, 16 bytes of address and 2 bytes of port each (BEP 7)
+    #[serde(
+        default,
+        deserialize_with = "peers::deserialize_v6",
+        serialize_with = "peers::serialize_v6",
+        skip_serializing_if = "Peers::is_empty"
+    )]
+    pub peers6: Peers,
+}
+
+impl TrackerResponse {
+    /// Peers of both `peers` and `peers6` lists
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        self.peers.addresses.iter().chain(&self.peers6.addresses).copied().collect()
+    }
+
+    /// Peer id the tracker sent along with an address, only the non-compact form has them
+    pub fn peer_id(&self, address: &SocketAddr) -> Option<PeerId> {
+        self.peers.peer_ids.get(address).copied()
+    }
+}
+
+/// Announces to a single tracker, remembering what it asks to be sent back on next announces
+#[derive(Debug, Clone)]
+pub struct TrackerClient {
+    pub announce: String,
+
+    /// Last `tracker id` received from the tracker
+    pub tracker_id: Option<String>,
+}
+
+impl TrackerClient {
+    pub fn new(announce: String) -> Self {
+        Self { announce, tracker_id: None }
+    }
+
+    /// Sends announce request, a `failure reason` in the response becomes [`TrackerError::Failure`].
+    /// Responses without an `interval` are refused, they would have the client announce in a loop
+    pub async fn announce(&mut self, request: &TrackerRequest) -> Result<TrackerResponse> {
+        let request = TrackerRequest {
+            trackerid: request.trackerid.clone().or_else(|| self.tracker_id.clone()),
+            ..request.clone()
+        };
+
+        let tracker_url = request.url_params(&self.announce)?;
+        let response = reqwest::
+            get(tracker_url)
+            .await
+            .context("initiate GET request to tracker")?
+            .bytes()
+            .await
+            .context("fetch tracker response")
+            .map(|bytes| serde_bencode::from_bytes::<TrackerResponse>(&bytes))?
+            .context("bencode tracker response")?;
+
+        if let Some(reason) = response.failure_reason {
+            return Err(TrackerError::Failure(reason).into());
+        }
+        anyhow::ensure!(response.interval > 0, "tracker response has no interval");
+
+        if response.tracker_id.is_some() {
+            self.tracker_id.clone_from(&response.tracker_id);
+        }
+
+        Ok(response)
+    }
+}
+
+/// Random value for announce keys and UDP transaction ids
+fn random_u32() -> u32 {
+    fastrand::u32(..)
 }
 
 fn urlencode(t: &[u8; 20]) -> String {
@@ -65,37 +210,94 @@ fn urlencode(t: &[u8; 20]) -> String {
 
 pub mod peers {
     use core::fmt;
-    use std::net::{Ipv4Addr, SocketAddrV4};
-    use serde::{de::{self, Visitor}, Deserialize, Deserializer, Serialize, Serializer};
+    use std::{collections::HashMap, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}};
+    use serde::{de::{self, SeqAccess, Visitor}, ser, Deserialize, Deserializer, Serialize, Serializer};
+    use crate::peer_id::PeerId;
+
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct Peers {
+        pub addresses: Vec<SocketAddr>,
 
-    #[derive(Debug, Clone)]
-    pub struct Peers(pub Vec<SocketAddrV4>);
+        /// Peer ids of the non-compact form by address, the compact forms have none
+        pub peer_ids: HashMap<SocketAddr, PeerId>,
+    }
     struct PeersVisitor;
+
+    impl Peers {
+        pub fn is_empty(&self) -> bool {
+            self.addresses.is_empty()
+        }
+
+        /// Splits addresses into the IPv4 list of `peers` and the IPv6 list of `peers6`
+        pub fn split(addresses: impl IntoIterator<Item = SocketAddr>) -> (Peers, Peers) {
+            let (v4, v6): (Vec<_>, Vec<_>) = addresses.into_iter().partition(SocketAddr::is_ipv4);
+            (Peers::from(v4), Peers::from(v6))
+        }
+    }
+
+    impl From<Vec<SocketAddr>> for Peers {
+        fn from(addresses: Vec<SocketAddr>) -> Self {
+            Self { addresses, peer_ids: HashMap::new() }
+        }
+    }
+
+    /// Peer of the non-compact list, `ip` is an IPv4, IPv6 address or a DNS name
+    #[derive(Serialize, Deserialize)]
+    pub(crate) struct DictPeer {
+        pub(crate) ip: String,
+        pub(crate) port: u16,
+
+        #[serde(rename = "peer id", default, skip_serializing_if = "Option::is_none")]
+        pub(crate) peer_id: Option<serde_bytes::ByteBuf>,
+    }
     
     impl<'de> Visitor<'de> for PeersVisitor {
         type Value = Peers;
     
         fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("6 bytes - first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number")
+            formatter.write_str("6 bytes per peer - first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number, or a list of dictionaries with `ip`, `port` and `peer id`")
         }
     
         fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
         where
             E: de::Error,
         {
-            if v.len() % 6 != 0 {
+            if !v.len().is_multiple_of(6) {
                 return Err(E::custom(format!("length is {}", v.len())));
             }
     
-            Ok(Peers(v
+            Ok(Peers::from(v
                 .chunks_exact(6)
                 .map(|slice| {
-                    SocketAddrV4::new(
-                        Ipv4Addr::new(slice[0], slice[1], slice[2], slice[3]),
+                    SocketAddr::new(
+                        IpAddr::V4(Ipv4Addr::new(slice[0], slice[1], slice[2], slice[3])),
                         u16::from_be_bytes([slice[4], slice[5]]),
                     )
                 })
-                .collect()))
+                .collect::<Vec<_>>()))
+        }
+
+        /// Non-compact form. Peers announced by a DNS name are skipped, they can't be dialled without a lookup
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut peers = Peers::default();
+            while let Some(peer) = seq.next_element::<DictPeer>()? {
+                let Ok(ip) = peer.ip.parse::<IpAddr>() else {
+                    continue;
+                };
+
+                let address = SocketAddr::new(ip, peer.port);
+                peers.addresses.push(address);
+
+                // Ids of any other length are not worth refusing the whole list for
+                if let Some(peer_id) = peer.peer_id.and_then(|id| <[u8; 20]>::try_from(id.as_slice()).ok()) {
+                    peers.peer_ids.insert(address, PeerId(peer_id));
+                }
+            }
+
+            Ok(peers)
         }
     }
     
@@ -108,17 +310,78 @@ pub mod peers {
         }
     }
 
+    /// Compact IPv4 form of the `peers` key, IPv6 addresses belong to `peers6`, see [`serialize_v6`]
     impl Serialize for Peers {
         fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where
             S: Serializer,
         {
-            let mut slice = Vec::with_capacity(6 * self.0.len());
-            for peer in &self.0 {
+            let mut slice = Vec::with_capacity(6 * self.addresses.len());
+            for peer in &self.addresses {
+                let IpAddr::V4(ip) = peer.ip() else {
+                    return Err(ser::Error::custom(format!("IPv6 peer {peer} in compact IPv4 list")));
+                };
+
+                slice.extend(ip.octets());
                 slice.extend(peer.port().to_be_bytes());
             }
             serializer.serialize_bytes(&slice)
         }
     }
 
-}
\ No newline at end of file
+    struct Peers6Visitor;
+
+    impl<'de> Visitor<'de> for Peers6Visitor {
+        type Value = Peers;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("18 bytes per peer - first 16 bytes are the peer's IPv6 address and the last 2 bytes are the peer's port number")
+        }
+
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            if !v.len().is_multiple_of(18) {
+                return Err(E::custom(format!("length is {}", v.len())));
+            }
+
+            Ok(Peers::from(v
+                .chunks_exact(18)
+                .map(|slice| {
+                    let ip: [u8; 16] = slice[..16].try_into().expect("always 16 bytes");
+                    SocketAddr::new(
+                        IpAddr::V6(Ipv6Addr::from(ip)),
+                        u16::from_be_bytes([slice[16], slice[17]]),
+                    )
+                })
+                .collect::<Vec<_>>()))
+        }
+    }
+
+    /// Writes the compact IPv6 peer list of the `peers6` key
+    pub fn serialize_v6<S>(peers: &Peers, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut slice = Vec::with_capacity(18 * peers.addresses.len());
+        for peer in &peers.addresses {
+            let IpAddr::V6(ip) = peer.ip() else {
+                return Err(ser::Error::custom(format!("IPv4 peer {peer} in compact IPv6 list")));
+            };
+
+            slice.extend(ip.octets());
+            slice.extend(peer.port().to_be_bytes());
+        }
+        serializer.serialize_bytes(&slice)
+    }
+
+    /// Compact IPv6 peer list of the `peers6` key (BEP 7)
+    pub fn deserialize_v6<'de, D>(deserializer: D) -> Result<Peers, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_bytes(Peers6Visitor)
+    }
+
+}
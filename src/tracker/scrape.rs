@@ -0,0 +1,179 @@
+use std::{collections::HashMap, time::Duration};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio::net::UdpSocket;
+use super::{random_u32, urlencode, TrackerError};
+
+
+/// Magic `protocol_id` of the UDP connect request (BEP 15)
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+
+const UDP_ACTION_CONNECT: u32 = 0;
+const UDP_ACTION_SCRAPE: u32 = 2;
+const UDP_ACTION_ERROR: u32 = 3;
+
+/// Most info hashes a single UDP scrape can carry and still fit into a packet
+const UDP_SCRAPE_MAX: usize = 74;
+
+/// UDP requests are retransmitted after 5, 10 and 20 seconds before giving up
+const UDP_ATTEMPTS: u32 = 3;
+const UDP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Swarm size of a single torrent as reported by a tracker
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScrapeStats {
+    /// Number of peers with the entire file (seeders)
+    #[serde(default)]
+    pub complete: usize,
+
+    /// Number of times the download was completed
+    #[serde(default)]
+    pub downloaded: usize,
+
+    /// Number of peers that are still downloading (leechers)
+    #[serde(default)]
+    pub incomplete: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScrapeResponse {
+    #[serde(rename = "failure reason", default)]
+    failure_reason: Option<String>,
+
+    #[serde(default)]
+    files: HashMap<ByteBuf, ScrapeStats>,
+}
+
+/// Scrape URL of an HTTP tracker: the last path component of the announce URL must start
+/// with `announce`, which is replaced with `scrape`. `None` if the tracker can't be scraped
+pub fn scrape_url(announce: &str) -> Option<String> {
+    let mut url = reqwest::Url::parse(announce).ok()?;
+    let last = url.path_segments()?.next_back()?.to_string();
+    let rest = last.strip_prefix("announce")?;
+
+    url.path_segments_mut().ok()?.pop().push(&format!("scrape{rest}"));
+    Some(url.to_string())
+}
+
+/// Asks the tracker for swarm sizes of `info_hashes` without announcing, over HTTP or UDP
+/// depending on the scheme of the announce URL. Torrents the tracker doesn't know are left out
+pub async fn scrape(announce: &str, info_hashes: &[[u8; 20]]) -> Result<HashMap<[u8; 20], ScrapeStats>> {
+    let url = reqwest::Url::parse(announce).context("parse tracker URL")?;
+
+    match url.scheme() {
+        "http" | "https" => scrape_http(announce, info_hashes).await,
+        "udp" => {
+            let mut stats = HashMap::new();
+            for chunk in info_hashes.chunks(UDP_SCRAPE_MAX) {
+                stats.extend(scrape_udp(&url, chunk).await?);
+            }
+            Ok(stats)
+        }
+        scheme => Err(TrackerError::ScrapeUnsupported(format!("unknown scheme {scheme:?}")).into()),
+    }
+}
+
+async fn scrape_http(announce: &str, info_hashes: &[[u8; 20]]) -> Result<HashMap<[u8; 20], ScrapeStats>> {
+    let url = scrape_url(announce)
+        .ok_or_else(|| TrackerError::ScrapeUnsupported(String::from("announce URL doesn't end with `announce`")))?;
+
+    let query = info_hashes.iter().map(|info_hash| format!("info_hash={}", urlencode(info_hash))).collect::<Vec<_>>().join("&");
+    let separator = if url.contains('?') { '&' } else { '?' };
+
+    let response = reqwest::
+        get(format!("{url}{separator}{query}"))
+        .await
+        .context("initiate GET request to tracker")?
+        .bytes()
+        .await
+        .context("fetch scrape response")
+        .map(|bytes| serde_bencode::from_bytes::<ScrapeResponse>(&bytes))?
+        .context("bencode scrape response")?;
+
+    if let Some(reason) = response.failure_reason {
+        return Err(TrackerError::Failure(reason).into());
+    }
+
+    Ok(response.files
+        .into_iter()
+        .filter_map(|(info_hash, stats)| Some((<[u8; 20]>::try_from(info_hash.as_slice()).ok()?, stats)))
+        .collect())
+}
+
+async fn scrape_udp(url: &reqwest::Url, info_hashes: &[[u8; 20]]) -> Result<HashMap<[u8; 20], ScrapeStats>> {
+    let host = url.host_str().context("tracker URL has no host")?;
+    let port = url.port().context("UDP tracker URL has no port")?;
+
+    let address = tokio::net::lookup_host((host.trim_matches(['[', ']']), port))
+        .await
+        .context(format!("resolve tracker {host}"))?
+        .next()
+        .context(format!("tracker {host} has no addresses"))?;
+
+    let local = if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(local).await.context("bind UDP socket")?;
+    socket.connect(address).await.context("connect UDP socket")?;
+
+    let mut connect = Vec::with_capacity(16);
+    connect.extend(UDP_PROTOCOL_ID.to_be_bytes());
+    connect.extend(UDP_ACTION_CONNECT.to_be_bytes());
+    let response = udp_request(&socket, connect, UDP_ACTION_CONNECT, 8).await?;
+    let connection_id = &response[..8];
+
+    let mut request = Vec::with_capacity(16 + 20 * info_hashes.len());
+    request.extend(connection_id);
+    request.extend(UDP_ACTION_SCRAPE.to_be_bytes());
+    for info_hash in info_hashes {
+        request.extend(info_hash);
+    }
+    let response = udp_request(&socket, request, UDP_ACTION_SCRAPE, 12 * info_hashes.len()).await?;
+
+    let number = |bytes: &[u8]| u32::from_be_bytes(bytes.try_into().expect("always 4 bytes")) as usize;
+    Ok(info_hashes
+        .iter()
+        .zip(response.chunks_exact(12))
+        .map(|(info_hash, entry)| {
+            let stats = ScrapeStats {
+                complete: number(&entry[0..4]),
+                downloaded: number(&entry[4..8]),
+                incomplete: number(&entry[8..12]),
+            };
+            (*info_hash, stats)
+        })
+        .collect())
+}
+
+/// Sends a UDP tracker request, inserting the transaction id after the 12 bytes of connection id
+/// and action, and retransmits it until a reply arrives. Returns the payload after action and transaction id
+async fn udp_request(socket: &UdpSocket, mut request: Vec<u8>, action: u32, payload_length: usize) -> Result<Vec<u8>> {
+    let transaction_id = random_u32().to_be_bytes();
+    request.splice(12..12, transaction_id);
+
+    let mut buffer = vec![0; 8 + payload_length.max(1024)];
+    for attempt in 0..UDP_ATTEMPTS {
+        socket.send(&request).await.context("send UDP tracker request")?;
+
+        let timeout = UDP_TIMEOUT * 2u32.pow(attempt);
+        let Ok(received) = tokio::time::timeout(timeout, socket.recv(&mut buffer)).await else {
+            continue;
+        };
+        let received = received.context("receive UDP tracker response")?;
+
+        if received < 8 || buffer[4..8] != transaction_id {
+            continue;
+        }
+
+        let reply_action = u32::from_be_bytes(buffer[..4].try_into().expect("always 4 bytes"));
+        if reply_action == UDP_ACTION_ERROR {
+            return Err(TrackerError::Failure(String::from_utf8_lossy(&buffer[8..received]).into_owned()).into());
+        }
+
+        anyhow::ensure!(reply_action == action, "tracker replied with action {reply_action} instead of {action}");
+        anyhow::ensure!(received >= 8 + payload_length, "tracker response is {received} bytes, expected {}", 8 + payload_length);
+
+        return Ok(buffer[8..received].to_vec());
+    }
+
+    anyhow::bail!("tracker didn't reply after {UDP_ATTEMPTS} attempts")
+}
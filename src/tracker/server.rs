@@ -0,0 +1,339 @@
+use std::{collections::{HashMap, HashSet}, net::{IpAddr, SocketAddr}, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}, task::JoinHandle};
+use super::{peers::{self, DictPeer}, scrape::ScrapeStats, Peers};
+
+
+/// Longest request head accepted, announces are well below this
+const REQUEST_MAX: usize = 8 * 1024;
+
+/// How long a client gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Peers returned when the client doesn't send `numwant`, and the most it can ask for
+const NUMWANT_DEFAULT: usize = 50;
+const NUMWANT_MAX: usize = 200;
+
+/// Wait before accepting again after accepting a connection failed
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// How often clients are asked to reannounce. Peers that miss two announces are dropped
+    pub interval: Duration,
+
+    /// Torrents the tracker serves, any torrent when `None`
+    pub whitelist: Option<HashSet<[u8; 20]>>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self { interval: Duration::from_secs(30 * 60), whitelist: None }
+    }
+}
+
+struct SwarmPeer {
+    address: SocketAddr,
+    seeding: bool,
+    last_seen: Instant,
+}
+
+/// Peers of a single torrent, keyed by peer id
+#[derive(Default)]
+struct Swarm {
+    peers: HashMap<[u8; 20], SwarmPeer>,
+
+    /// Number of `completed` events received
+    downloaded: usize,
+}
+
+impl Swarm {
+    fn stats(&self) -> ScrapeStats {
+        let complete = self.peers.values().filter(|peer| peer.seeding).count();
+        ScrapeStats { complete, downloaded: self.downloaded, incomplete: self.peers.len() - complete }
+    }
+}
+
+/// Announce parameters the server uses, taken from the query string
+struct Announce {
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    port: u16,
+    left: usize,
+    event: Option<String>,
+    compact: bool,
+    no_peer_id: bool,
+    numwant: usize,
+}
+
+#[derive(Serialize)]
+struct AnnounceResponse {
+    interval: u64,
+
+    #[serde(rename = "min interval")]
+    min_interval: u64,
+
+    complete: usize,
+    incomplete: usize,
+    peers: PeerList,
+
+    #[serde(serialize_with = "peers::serialize_v6", skip_serializing_if = "Peers::is_empty")]
+    peers6: Peers,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum PeerList {
+    Compact(Peers),
+    Dict(Vec<DictPeer>),
+}
+
+#[derive(Serialize)]
+struct ScrapeResponse {
+    files: HashMap<ByteBuf, ScrapeStats>,
+}
+
+#[derive(Serialize)]
+struct FailureResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: String,
+}
+
+/// HTTP tracker keeping swarms in memory. Handles `/announce` and `/scrape`, compact replies
+/// carry IPv6 peers in `peers6`
+pub struct TrackerServer {
+    options: ServerOptions,
+    swarms: Mutex<HashMap<[u8; 20], Swarm>>,
+}
+
+impl TrackerServer {
+    pub fn new(options: ServerOptions) -> Self {
+        Self { options, swarms: Mutex::new(HashMap::new()) }
+    }
+
+    /// Accepts connections until the future is dropped, dropping expired peers in the background
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        let server = self.clone();
+        let _expiry = AbortOnDrop(tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(server.options.interval);
+            loop {
+                ticks.tick().await;
+                server.expire();
+            }
+        }));
+
+        loop {
+            let (stream, remote) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(error) => {
+                    // Such as running out of file descriptors, which retrying right away won't fix
+                    tracing::warn!("accepting tracker connection failed: {error}");
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(error) = server.handle(stream, remote).await {
+                    tracing::warn!(%remote, "tracker request failed: {error:#}");
+                }
+            });
+        }
+    }
+
+    /// Drops peers that haven't announced for two intervals, and swarms left empty
+    pub fn expire(&self) {
+        let timeout = self.options.interval * 2;
+        let mut swarms = self.swarms.lock().expect("swarms lock is poisoned");
+
+        for swarm in swarms.values_mut() {
+            swarm.peers.retain(|_, peer| peer.last_seen.elapsed() < timeout);
+        }
+        swarms.retain(|_, swarm| !swarm.peers.is_empty() || swarm.downloaded > 0);
+    }
+
+    async fn handle(&self, mut stream: TcpStream, remote: SocketAddr) -> Result<()> {
+        let mut head = Vec::new();
+        let mut buffer = [0; 1024];
+        while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+            anyhow::ensure!(head.len() < REQUEST_MAX, "request is too long");
+
+            let read = tokio::time::timeout(REQUEST_TIMEOUT, stream.read(&mut buffer))
+                .await
+                .context("request timed out")?
+                .context("read request")?;
+            anyhow::ensure!(read > 0, "connection closed before the request was complete");
+            head.extend(&buffer[..read]);
+        }
+
+        let head = String::from_utf8_lossy(&head);
+        let target = match head.lines().next().unwrap_or_default().split(' ').collect::<Vec<_>>()[..] {
+            ["GET", target, _] => target.to_string(),
+            _ => return respond(&mut stream, "405 Method Not Allowed", b"").await,
+        };
+
+        let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+        let query = parse_query(query);
+
+        let body = if path.ends_with("/announce") {
+            self.announce(&query, remote.ip().to_canonical())
+        } else if path.ends_with("/scrape") {
+            self.scrape(&query)
+        } else {
+            return respond(&mut stream, "404 Not Found", b"").await;
+        };
+
+        respond(&mut stream, "200 OK", &body).await
+    }
+
+    /// Registers the peer and replies with other peers of the swarm, in no particular order
+    pub fn announce(&self, query: &[(String, Vec<u8>)], ip: IpAddr) -> Vec<u8> {
+        let announce = match parse_announce(query) {
+            Ok(announce) => announce,
+            Err(reason) => return failure(reason),
+        };
+
+        if !self.serves(&announce.info_hash) {
+            return failure("torrent is not served by this tracker");
+        }
+
+        let mut swarms = self.swarms.lock().expect("swarms lock is poisoned");
+        let swarm = swarms.entry(announce.info_hash).or_default();
+
+        let timeout = self.options.interval * 2;
+        swarm.peers.retain(|_, peer| peer.last_seen.elapsed() < timeout);
+
+        match announce.event.as_deref() {
+            Some("stopped") => {
+                swarm.peers.remove(&announce.peer_id);
+            }
+            event => {
+                if event == Some("completed") {
+                    swarm.downloaded += 1;
+                }
+
+                swarm.peers.insert(announce.peer_id, SwarmPeer {
+                    address: SocketAddr::new(ip, announce.port),
+                    seeding: announce.left == 0,
+                    last_seen: Instant::now(),
+                });
+            }
+        }
+
+        let others = swarm.peers
+            .iter()
+            .filter(|(peer_id, _)| **peer_id != announce.peer_id)
+            .take(announce.numwant);
+
+        let (peers, peers6) = if announce.compact {
+            let (v4, v6) = Peers::split(others.map(|(_, peer)| peer.address));
+            (PeerList::Compact(v4), v6)
+        } else {
+            let peers = others
+                .map(|(peer_id, peer)| DictPeer {
+                    ip: peer.address.ip().to_string(),
+                    port: peer.address.port(),
+                    peer_id: (!announce.no_peer_id).then(|| ByteBuf::from(peer_id.to_vec())),
+                })
+                .collect();
+            (PeerList::Dict(peers), Peers::default())
+        };
+
+        let stats = swarm.stats();
+        let response = AnnounceResponse {
+            interval: self.options.interval.as_secs(),
+            min_interval: self.options.interval.as_secs() / 2,
+            complete: stats.complete,
+            incomplete: stats.incomplete,
+            peers,
+            peers6,
+        };
+
+        serde_bencode::to_bytes(&response).expect("announce response is always serializable")
+    }
+
+    /// Stats of every requested torrent, or of all torrents when none is requested
+    pub fn scrape(&self, query: &[(String, Vec<u8>)]) -> Vec<u8> {
+        let requested = query
+            .iter()
+            .filter(|(key, _)| key == "info_hash")
+            .map(|(_, value)| <[u8; 20]>::try_from(value.as_slice()))
+            .collect::<Result<HashSet<_>, _>>();
+
+        let Ok(requested) = requested else {
+            return failure("info_hash must be 20 bytes");
+        };
+
+        let swarms = self.swarms.lock().expect("swarms lock is poisoned");
+        let files = swarms
+            .iter()
+            .filter(|(info_hash, _)| requested.is_empty() || requested.contains(*info_hash))
+            .filter(|(info_hash, _)| self.serves(info_hash))
+            .map(|(info_hash, swarm)| (ByteBuf::from(info_hash.to_vec()), swarm.stats()))
+            .collect();
+
+        serde_bencode::to_bytes(&ScrapeResponse { files }).expect("scrape response is always serializable")
+    }
+
+    fn serves(&self, info_hash: &[u8; 20]) -> bool {
+        self.options.whitelist.as_ref().is_none_or(|whitelist| whitelist.contains(info_hash))
+    }
+}
+
+/// Splits the query string into keys and raw values. Values are kept as bytes, `info_hash` and `peer_id` are binary
+fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let key = percent_encoding::percent_decode_str(key).decode_utf8_lossy().into_owned();
+            (key, percent_encoding::percent_decode_str(value).collect())
+        })
+        .collect()
+}
+
+fn parse_announce(query: &[(String, Vec<u8>)]) -> Result<Announce, String> {
+    let get = |name: &str| query.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_slice());
+    let text = |name: &str| get(name).map(|value| String::from_utf8_lossy(value).into_owned());
+    let number = |name: &str| -> Result<Option<usize>, String> {
+        text(name).map(|value| value.parse().map_err(|_| format!("{name} must be a number"))).transpose()
+    };
+
+    let info_hash = get("info_hash").and_then(|value| value.try_into().ok()).ok_or("info_hash must be 20 bytes")?;
+    let peer_id = get("peer_id").and_then(|value| value.try_into().ok()).ok_or("peer_id must be 20 bytes")?;
+    let port = text("port").and_then(|port| port.parse().ok()).ok_or("port is missing or invalid")?;
+
+    Ok(Announce {
+        info_hash,
+        peer_id,
+        port,
+        left: number("left")?.unwrap_or_default(),
+        event: text("event").filter(|event| !event.is_empty()),
+        compact: text("compact").as_deref() == Some("1"),
+        no_peer_id: text("no_peer_id").as_deref() == Some("1"),
+        numwant: number("numwant")?.unwrap_or(NUMWANT_DEFAULT).min(NUMWANT_MAX),
+    })
+}
+
+fn failure(reason: impl Into<String>) -> Vec<u8> {
+    serde_bencode::to_bytes(&FailureResponse { failure_reason: reason.into() }).expect("failure response is always serializable")
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &[u8]) -> Result<()> {
+    let head = format!("HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+    stream.write_all(head.as_bytes()).await.context("write response")?;
+    stream.write_all(body).await.context("write response")?;
+    stream.shutdown().await.context("close connection")
+}
+
+/// Stops the background task once whoever spawned it is gone
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
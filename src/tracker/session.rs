@@ -0,0 +1,184 @@
+use std::{collections::VecDeque, net::SocketAddr, sync::Arc, time::Duration};
+use anyhow::{Context, Result};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::Instrument;
+use crate::{peer_id::PeerId, torrent::Torrent, transfer::Transfer};
+use super::{random_u32, Event, TrackerClient, TrackerRequest};
+
+
+/// Wait until the next regular announce before the first response
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// Wait before retrying a failed announce, doubled after every failure up to the interval
+const RETRY_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long shutdown waits for the `stopped` announce
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Peers asked for on every announce
+pub const NUMWANT: usize = 50;
+
+/// Peers from an announce, with the peer ids of those the tracker sent in the non-compact form
+pub type AnnouncedPeers = Vec<(SocketAddr, Option<PeerId>)>;
+
+/// Announces a torrent to its trackers for as long as it is active: `started` first,
+/// then periodic reannounces with current transfer counters, `completed` once the
+/// download finishes and `stopped` on shutdown
+///
+/// Trackers are tried tier by tier as in BEP 12, a tracker that answers is moved to
+/// the front of its tier
+pub struct TrackerSession {
+    tiers: Vec<Vec<TrackerClient>>,
+    info_hash: [u8; 20],
+    peer_id: String,
+    port: u16,
+    key: String,
+    numwant: usize,
+    transfer: Arc<Transfer>,
+
+    /// Wait until the next regular announce, from the last successful response
+    interval: Duration,
+}
+
+impl TrackerSession {
+    pub fn new(torrent: &Torrent, peer_id: PeerId, transfer: Arc<Transfer>, port: u16) -> Result<Self> {
+        let tiers = torrent
+            .tracker_tiers()
+            .into_iter()
+            .map(|tier| tier.into_iter().map(TrackerClient::new).collect())
+            .collect();
+
+        Ok(Self {
+            tiers,
+            info_hash: torrent.info_hash()?,
+            peer_id: String::from_utf8(peer_id.0.to_vec()).context("peer id must be UTF-8")?,
+            port,
+            key: format!("{:08x}", random_u32()),
+            numwant: NUMWANT,
+            transfer,
+            interval: DEFAULT_INTERVAL,
+        })
+    }
+
+    pub fn numwant(mut self, numwant: usize) -> Self {
+        self.numwant = numwant;
+        self
+    }
+
+    /// Announces to the first tracker that answers, returns the peers it gave
+    pub async fn announce(&mut self, event: Option<Event>) -> Result<AnnouncedPeers> {
+        let request = TrackerRequest {
+            info_hash: self.info_hash,
+            peer_id: self.peer_id.clone(),
+            port: self.port,
+            uploaded: self.transfer.uploaded(),
+            downloaded: self.transfer.downloaded(),
+            left: self.transfer.left(),
+            compact: 1,
+            trackerid: None,
+            event,
+            numwant: (event != Some(Event::Stopped)).then_some(self.numwant),
+            key: Some(self.key.clone()),
+        };
+
+        let mut last_error = None;
+        for tier in &mut self.tiers {
+            for position in 0..tier.len() {
+                match tier[position].announce(&request).await {
+                    Ok(response) => {
+                        if let Some(warning) = &response.warning_message {
+                            tracing::warn!(tracker = %tier[position].announce, "tracker warning: {warning}");
+                        }
+
+                        let interval = Duration::from_secs(response.interval as u64);
+                        let min_interval = Duration::from_secs(response.min_interval.unwrap_or_default() as u64);
+                        self.interval = interval.max(min_interval);
+
+                        let tracker = tier.remove(position);
+                        tracing::debug!(tracker = %tracker.announce, ?event, peers = response.addresses().len(), interval = self.interval.as_secs(), "announced");
+                        tier.insert(0, tracker);
+
+                        let peers = response.addresses().into_iter().map(|address| (address, response.peer_id(&address)));
+                        return Ok(peers.collect());
+                    }
+                    Err(error) => last_error = Some(error.context(format!("announce to {}", tier[position].announce))),
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("torrent has no trackers")))
+    }
+
+    /// Runs the session in the background. The outcome of every announce, apart from
+    /// `stopped`, is sent into `announces`
+    pub fn spawn(mut self, announces: mpsc::UnboundedSender<Result<AnnouncedPeers>>) -> TrackerHandle {
+        let (commands, mut received) = mpsc::unbounded_channel();
+
+        let task = tokio::spawn(async move {
+            // Events go out in order, each one until it gets through
+            let mut pending = VecDeque::from([Event::Started]);
+            let mut failures = 0;
+
+            'announce: loop {
+                let result = self.announce(pending.front().copied()).await;
+                let wait = if result.is_ok() {
+                    pending.pop_front();
+                    failures = 0;
+                    if pending.is_empty() { self.interval } else { Duration::ZERO }
+                } else {
+                    failures += 1;
+                    (RETRY_INTERVAL * 2u32.saturating_pow(failures - 1)).min(self.interval)
+                };
+                let _ = announces.send(result);
+
+                let sleep = tokio::time::sleep(wait);
+                tokio::pin!(sleep);
+                loop {
+                    tokio::select! {
+                        _ = &mut sleep => break,
+                        command = received.recv() => match command {
+                            Some(Event::Completed) => {
+                                if !pending.contains(&Event::Completed) {
+                                    pending.push_back(Event::Completed);
+                                }
+                                // Right away, unless an earlier event waits for its retry
+                                if pending.len() == 1 {
+                                    break;
+                                }
+                            }
+                            Some(Event::Stopped) | None => break 'announce,
+                            Some(Event::Started) => {}
+                        },
+                    }
+                }
+            }
+
+            let stopped = tokio::time::timeout(STOP_TIMEOUT, self.announce(Some(Event::Stopped))).await;
+            if let Ok(Err(error)) = stopped {
+                tracing::warn!("{error:#}");
+            }
+        }
+        .instrument(tracing::Span::current()));
+
+        TrackerHandle { commands, task }
+    }
+}
+
+/// Controls a [`TrackerSession`] running in the background
+pub struct TrackerHandle {
+    commands: mpsc::UnboundedSender<Event>,
+    task: JoinHandle<()>,
+}
+
+impl TrackerHandle {
+    /// Sends `completed` right away instead of waiting for the next regular announce
+    pub fn completed(&self) {
+        let _ = self.commands.send(Event::Completed);
+    }
+
+    /// Sends `stopped` and waits for the session to finish
+    pub async fn stop(self) -> Result<()> {
+        let _ = self.commands.send(Event::Stopped);
+        self.task.await.context("tracker session panicked")
+    }
+}
@@ -0,0 +1,94 @@
+use std::{collections::VecDeque, sync::atomic::{AtomicUsize, Ordering}, time::Instant};
+
+
+/// Samples rates are averaged over, taken about once a second
+const RATE_SAMPLES: usize = 5;
+
+/// Byte counters of a torrent, shared between peer connections and the tracker session
+#[derive(Debug, Default)]
+pub struct Transfer {
+    uploaded: AtomicUsize,
+    downloaded: AtomicUsize,
+    left: AtomicUsize,
+}
+
+impl Transfer {
+    pub fn new(left: usize) -> Self {
+        Self { left: AtomicUsize::new(left), ..Default::default() }
+    }
+
+    pub fn uploaded(&self) -> usize {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn downloaded(&self) -> usize {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of selected pieces that are not verified yet
+    pub fn left(&self) -> usize {
+        self.left.load(Ordering::Relaxed)
+    }
+
+    pub fn add_uploaded(&self, bytes: usize) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts every received block, including blocks of pieces that later fail verification
+    pub fn add_downloaded(&self, bytes: usize) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn set_left(&self, bytes: usize) {
+        self.left.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Called once a piece of `bytes` is verified
+    pub fn add_verified(&self, bytes: usize) {
+        let _ = self.left.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| Some(left.saturating_sub(bytes)));
+    }
+}
+
+/// Download and upload rates of a [`Transfer`], averaged over its last few samples
+#[derive(Debug, Default)]
+pub struct Rates {
+    /// Time, downloaded and uploaded bytes
+    samples: VecDeque<(Instant, usize, usize)>,
+}
+
+impl Rates {
+    pub fn sample(&mut self, transfer: &Transfer) {
+        if self.samples.len() == RATE_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((Instant::now(), transfer.downloaded(), transfer.uploaded()));
+    }
+
+    /// Forgets the samples, rates are zero until two new ones are taken
+    pub fn reset(&mut self) {
+        self.samples.clear();
+    }
+
+    /// Bytes per second
+    pub fn download(&self) -> f64 {
+        self.rate(|&(_, downloaded, _)| downloaded)
+    }
+
+    /// Bytes per second
+    pub fn upload(&self) -> f64 {
+        self.rate(|&(_, _, uploaded)| uploaded)
+    }
+
+    fn rate(&self, bytes: impl Fn(&(Instant, usize, usize)) -> usize) -> f64 {
+        let (Some(first), Some(last)) = (self.samples.front(), self.samples.back()) else {
+            return 0.0;
+        };
+
+        let seconds = last.0.duration_since(first.0).as_secs_f64();
+        if seconds == 0.0 {
+            return 0.0;
+        }
+
+        (bytes(last) - bytes(first)) as f64 / seconds
+    }
+}
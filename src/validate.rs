@@ -0,0 +1,258 @@
+use std::{collections::HashMap, fmt, time::{SystemTime, UNIX_EPOCH}};
+use serde::Serialize;
+use serde_bencode::value::Value;
+use crate::torrent::{Keys, Torrent, UrlList};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Torrent is usable, but something about it is unusual or will be changed on download
+    Warning,
+
+    /// Torrent breaks BEP 3 and can't be used
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    pub severity: Severity,
+
+    /// Key the problem is about, e.g. `info.piece length`
+    pub field: String,
+
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+
+        write!(f, "{severity}: {}: {}", self.field, self.message)
+    }
+}
+
+/// Everything found wrong with a metainfo file
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_valid(&self) -> bool {
+        self.errors().next().is_none()
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &Problem> {
+        self.problems.iter().filter(|problem| problem.severity == Severity::Error)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Problem> {
+        self.problems.iter().filter(|problem| problem.severity == Severity::Warning)
+    }
+
+    fn error(&mut self, field: &str, message: impl Into<String>) {
+        self.problems.push(Problem { severity: Severity::Error, field: field.to_string(), message: message.into() });
+    }
+
+    fn warning(&mut self, field: &str, message: impl Into<String>) {
+        self.problems.push(Problem { severity: Severity::Warning, field: field.to_string(), message: message.into() });
+    }
+}
+
+/// Validates raw metainfo. Structure is checked on the bencoded value first, so that
+/// problems `serde` can't see (like both `length` and `files`) are reported too.
+/// The torrent is returned if it could be parsed at all, even if it has errors
+pub fn validate_bytes(bytes: &[u8]) -> (Option<Torrent>, Report) {
+    let mut report = Report::default();
+
+    let value = match serde_bencode::from_bytes::<Value>(bytes) {
+        Ok(value) => value,
+        Err(error) => {
+            report.error("", format!("not valid bencode: {error}"));
+            return (None, report);
+        }
+    };
+
+    check_structure(&value, &mut report);
+
+    let torrent = match serde_bencode::from_bytes::<Torrent>(bytes) {
+        Ok(torrent) => torrent,
+        Err(error) => {
+            if report.is_valid() {
+                report.error("", format!("can't be parsed: {error}"));
+            }
+            return (None, report);
+        }
+    };
+
+    report.problems.extend(torrent.validate().problems);
+
+    (Some(torrent), report)
+}
+
+fn check_structure(value: &Value, report: &mut Report) {
+    let Some(root) = as_dict(value) else {
+        report.error("", "metainfo must be a dictionary");
+        return;
+    };
+
+    if !matches!(root.get(b"announce".as_slice()), Some(Value::Bytes(_))) {
+        report.error("announce", "missing or not a string");
+    }
+
+    let Some(info) = root.get(b"info".as_slice()).and_then(as_dict) else {
+        report.error("info", "missing or not a dictionary");
+        return;
+    };
+
+    if !matches!(info.get(b"name".as_slice()), Some(Value::Bytes(_))) {
+        report.error("info.name", "missing or not a string");
+    }
+
+    match info.get(b"piece length".as_slice()) {
+        Some(Value::Int(length)) if *length < 0 => report.error("info.piece length", format!("is negative ({length})")),
+        Some(Value::Int(_)) => {}
+        _ => report.error("info.piece length", "missing or not an integer"),
+    }
+
+    match info.get(b"pieces".as_slice()) {
+        Some(Value::Bytes(pieces)) if pieces.len() % 20 != 0 => {
+            report.error("info.pieces", format!("length {} is not a multiple of 20", pieces.len()))
+        }
+        Some(Value::Bytes(_)) => {}
+        _ => report.error("info.pieces", "missing or not a string"),
+    }
+
+    match (info.get(b"length".as_slice()), info.get(b"files".as_slice())) {
+        (Some(_), Some(_)) => report.error("info", "has both `length` and `files`"),
+        (None, None) => report.error("info", "has neither `length` nor `files`"),
+        (Some(length), None) => check_length("info.length", length, report),
+        (None, Some(Value::List(files))) => {
+            for (index, file) in files.iter().enumerate() {
+                let field = format!("info.files[{index}]");
+                let Some(file) = as_dict(file) else {
+                    report.error(&field, "not a dictionary");
+                    continue;
+                };
+
+                match file.get(b"length".as_slice()) {
+                    Some(length) => check_length(&format!("{field}.length"), length, report),
+                    None => report.error(&format!("{field}.length"), "missing"),
+                }
+
+                match file.get(b"path".as_slice()) {
+                    Some(Value::List(parts)) if parts.iter().all(|part| matches!(part, Value::Bytes(_))) => {}
+                    _ => report.error(&format!("{field}.path"), "missing or not a list of strings"),
+                }
+            }
+        }
+        (None, Some(_)) => report.error("info.files", "not a list"),
+    }
+}
+
+fn check_length(field: &str, value: &Value, report: &mut Report) {
+    match value {
+        Value::Int(length) if *length < 0 => report.error(field, format!("is negative ({length})")),
+        Value::Int(_) => {}
+        _ => report.error(field, "not an integer"),
+    }
+}
+
+fn as_dict(value: &Value) -> Option<&HashMap<Vec<u8>, Value>> {
+    match value {
+        Value::Dict(dict) => Some(dict),
+        _ => None,
+    }
+}
+
+fn check_url(field: &str, url: &str, report: &mut Report) {
+    match reqwest::Url::parse(url) {
+        Ok(url) if ["http", "https", "udp"].contains(&url.scheme()) => {}
+        Ok(url) => report.warning(field, format!("unsupported URL scheme {:?}", url.scheme())),
+        Err(error) => report.warning(field, format!("invalid URL {url:?}: {error}")),
+    }
+}
+
+impl Torrent {
+    /// Checks BEP 3 invariants of already parsed metainfo
+    pub fn validate(&self) -> Report {
+        let mut report = Report::default();
+        let info = &self.info;
+
+        if info.name.is_empty() {
+            report.error("info.name", "is empty");
+        }
+
+        if let Keys::MultiFile { files } = &info.keys {
+            if files.is_empty() {
+                report.error("info.files", "is empty");
+            }
+        }
+
+        let total_length = self.file_length();
+        if total_length == 0 {
+            report.error("info", "total length is zero");
+        }
+
+        if info.piece_length == 0 {
+            report.error("info.piece length", "is zero");
+        } else {
+            if !info.piece_length.is_power_of_two() {
+                report.warning("info.piece length", format!("{} is not a power of two", info.piece_length));
+            }
+            if info.piece_length < 1 << 14 {
+                report.warning("info.piece length", format!("{} is smaller than a 16 KiB block", info.piece_length));
+            }
+
+            let expected = total_length.div_ceil(info.piece_length);
+            if info.pieces.0.len() != expected {
+                report.error(
+                    "info.pieces",
+                    format!("has {} hashes, but total length {total_length} needs {expected}", info.pieces.0.len()),
+                );
+            }
+        }
+
+        if let Some(private) = info.private.filter(|private| *private > 1) {
+            report.warning("info.private", format!("should be 0 or 1, not {private}"));
+        }
+
+        check_url("announce", &self.announce, &mut report);
+        for (tier, trackers) in self.announce_list.iter().flatten().enumerate() {
+            if trackers.is_empty() {
+                report.warning(&format!("announce-list[{tier}]"), "tier is empty");
+            }
+            for (index, tracker) in trackers.iter().enumerate() {
+                check_url(&format!("announce-list[{tier}][{index}]"), tracker, &mut report);
+            }
+        }
+
+        let webseeds = match &self.url_list {
+            Some(UrlList::One(url)) => vec![url.clone()],
+            Some(UrlList::Many(urls)) => urls.clone(),
+            None => Vec::new(),
+        };
+        for (index, url) in webseeds.iter().enumerate() {
+            check_url(&format!("url-list[{index}]"), url, &mut report);
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+        if let Some(date) = self.creation_date.filter(|date| *date < 0 || *date > now) {
+            report.warning("creation date", format!("{date} is not a valid time in the past"));
+        }
+
+        for change in self.path_changes() {
+            let field = match change.file {
+                Some(index) => format!("info.files[{index}].path"),
+                None => String::from("info.name"),
+            };
+            report.warning(&field, format!("unsafe name, will be saved as {:?}", change.sanitized.display().to_string()));
+        }
+
+        report
+    }
+}
@@ -0,0 +1,188 @@
+use std::{os::unix::fs::FileExt, path::{Path, PathBuf}, sync::atomic::{AtomicUsize, Ordering}};
+use anyhow::Result;
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use crate::{files::FileEntry, paths::sanitize_component, torrent::Torrent};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PieceState {
+    /// Data on disk matches the piece hash
+    Valid,
+
+    /// Data is present but its hash doesn't match
+    Bad,
+
+    /// At least one file the piece spans is absent or too short
+    Missing,
+}
+
+/// Hashes `pieces` on all CPU cores. `read` returns piece data or `None` if it's not available
+pub fn hash_pieces<F>(torrent: &Torrent, pieces: &[usize], read: F) -> Result<Vec<(usize, PieceState)>>
+where
+    F: Fn(usize) -> Result<Option<Vec<u8>>> + Sync,
+{
+    in_parallel(pieces, |index| {
+        Ok(match read(index)? {
+            None => PieceState::Missing,
+            Some(data) if <[u8; 20]>::from(Sha1::digest(&data)) == torrent.info.pieces.0[index] => PieceState::Valid,
+            Some(_) => PieceState::Bad,
+        })
+    })
+}
+
+/// Runs `f` for every piece index on a thread per CPU core, results are ordered by piece index
+pub fn in_parallel<T, F>(pieces: &[usize], f: F) -> Result<Vec<(usize, T)>>
+where
+    T: Send,
+    F: Fn(usize) -> Result<T> + Sync,
+{
+    let workers = std::thread::available_parallelism().map_or(1, |n| n.get()).min(pieces.len().max(1));
+    let next = AtomicUsize::new(0);
+
+    let results = std::thread::scope(|scope| {
+        let handles = (0..workers)
+            .map(|_| scope.spawn(|| {
+                let mut results = Vec::new();
+                while let Some(&index) = pieces.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    results.push((index, f(index)?));
+                }
+
+                Ok::<_, anyhow::Error>(results)
+            }))
+            .collect::<Vec<_>>();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("worker thread panicked"))
+            .collect::<Result<Vec<_>>>()
+    })?;
+
+    let mut results = results.into_iter().flatten().collect::<Vec<_>>();
+    results.sort_by_key(|(index, _)| *index);
+
+    Ok(results)
+}
+
+/// Result of checking data on disk against piece hashes of a torrent
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifyReport {
+    pub pieces: usize,
+    pub valid: usize,
+    pub bad: usize,
+    pub missing: usize,
+
+    /// Files that have at least one bad or missing piece
+    pub files: Vec<FileReport>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileReport {
+    pub index: usize,
+    pub path: PathBuf,
+    pub length: usize,
+    pub exists: bool,
+    pub bad_pieces: Vec<usize>,
+    pub missing_pieces: Vec<usize>,
+}
+
+impl VerifyReport {
+    pub fn is_complete(&self) -> bool {
+        self.valid == self.pieces
+    }
+}
+
+/// Checks content of the torrent stored at `path`. For single file torrents `path` is the
+/// file itself, for multi-file torrents it's the directory that holds the files
+pub fn verify(torrent: &Torrent, path: &Path) -> Result<VerifyReport> {
+    let reader = ContentReader::open(torrent, path);
+    let pieces = (0..torrent.info.pieces.0.len()).collect::<Vec<_>>();
+    let states = hash_pieces(torrent, &pieces, |index| Ok(reader.read_piece(torrent, index)))?;
+
+    let count = |wanted: PieceState| states.iter().filter(|(_, state)| *state == wanted).count();
+
+    let files = reader.files
+        .iter()
+        .map(|(file, handle)| {
+            let with_state = |wanted: PieceState| file
+                .pieces(torrent.info.piece_length)
+                .filter(|&index| states[index].1 == wanted)
+                .collect::<Vec<_>>();
+
+            FileReport {
+                index: file.index,
+                path: file.path.clone(),
+                length: file.length,
+                exists: handle.is_some(),
+                bad_pieces: with_state(PieceState::Bad),
+                missing_pieces: with_state(PieceState::Missing),
+            }
+        })
+        .filter(|report| !report.bad_pieces.is_empty() || !report.missing_pieces.is_empty())
+        .collect();
+
+    Ok(VerifyReport {
+        pieces: pieces.len(),
+        valid: count(PieceState::Valid),
+        bad: count(PieceState::Bad),
+        missing: count(PieceState::Missing),
+        files,
+    })
+}
+
+/// Read-only view over existing content of a torrent, files that don't exist are left out
+pub(crate) struct ContentReader {
+    piece_length: usize,
+    files: Vec<(FileEntry, Option<std::fs::File>)>,
+}
+
+impl ContentReader {
+    pub(crate) fn open(torrent: &Torrent, path: &Path) -> Self {
+        let locations = torrent
+            .files()
+            .into_iter()
+            .map(|file| match file.path.strip_prefix(sanitize_component(&torrent.info.name)) {
+                Ok(relative) if relative.as_os_str().is_empty() => path.to_path_buf(),
+                Ok(relative) => path.join(relative),
+                Err(_) => path.join(&file.path),
+            })
+            .collect();
+
+        Self::at(torrent, locations)
+    }
+
+    /// Reads the files of `torrent` from `locations`, one for every file in order
+    pub(crate) fn at(torrent: &Torrent, locations: Vec<PathBuf>) -> Self {
+        let files = torrent
+            .files()
+            .into_iter()
+            .zip(locations)
+            .map(|(file, location)| {
+                let handle = std::fs::File::open(location).ok();
+                (file, handle)
+            })
+            .collect();
+
+        Self { piece_length: torrent.info.piece_length, files }
+    }
+
+    /// `None` if any part of the piece can't be read
+    pub(crate) fn read_piece(&self, torrent: &Torrent, index: usize) -> Option<Vec<u8>> {
+        let start = index * self.piece_length;
+        let end = start + torrent.piece_size(index);
+        let mut piece = vec![0; end - start];
+
+        for (file, handle) in self.files.iter().filter(|(file, _)| file.offset < end && start < file.end()) {
+            let from = start.max(file.offset);
+            let to = end.min(file.end());
+
+            handle
+                .as_ref()?
+                .read_exact_at(&mut piece[from - start..to - start], (from - file.offset) as u64)
+                .ok()?;
+        }
+
+        Some(piece)
+    }
+}
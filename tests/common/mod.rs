@@ -0,0 +1,70 @@
+// Every test crate uses a different part of the helpers
+#![allow(dead_code)]
+
+use std::{future::Future, net::SocketAddr, path::Path};
+use bittorrent::{
+    create::{create_torrent, CreateOptions},
+    torrent::Torrent,
+};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}};
+
+
+/// Nothing listens there, announces fail right away
+pub const UNREACHABLE_TRACKER: &str = "http://127.0.0.1:1/announce";
+
+/// Peer id of the peers from [`fake_peer`]
+pub const FAKE_PEER_ID: &[u8; 20] = b"-TR3000-000000000000";
+
+/// Writes `length` pseudo-random bytes, the same ones for the same length
+pub fn write_random(path: &Path, length: usize) -> Vec<u8> {
+    let mut rng = fastrand::Rng::with_seed(length as u64);
+    let data = (0..length).map(|_| rng.u8(..)).collect::<Vec<_>>();
+    std::fs::write(path, &data).unwrap();
+
+    data
+}
+
+/// Metainfo of the file or directory at `path`, announced to [`UNREACHABLE_TRACKER`]
+pub fn torrent_of(path: &Path, piece_length: usize) -> Torrent {
+    let trackers = vec![String::from(UNREACHABLE_TRACKER)];
+    create_torrent(path, &CreateOptions { trackers, piece_length: Some(piece_length), ..Default::default() }).unwrap()
+}
+
+/// Peer accepting a single connection: answers the handshake with [`FAKE_PEER_ID`] and
+/// announces every piece, then follows `script`
+pub async fn fake_peer<F, Fut>(torrent: &Torrent, script: F) -> SocketAddr
+where
+    F: FnOnce(TcpStream) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let info_hash = torrent.info_hash().unwrap();
+    let pieces = torrent.info.pieces.0.len();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut handshake = [0; 68];
+        stream.read_exact(&mut handshake).await.unwrap();
+
+        let mut reply = vec![19];
+        reply.extend(b"BitTorrent protocol");
+        reply.extend([0; 8]);
+        reply.extend(info_hash);
+        reply.extend(FAKE_PEER_ID);
+
+        // Spare bits at the end stay clear
+        let mut bitfield = vec![0xff; pieces.div_ceil(8)];
+        if !pieces.is_multiple_of(8) {
+            *bitfield.last_mut().unwrap() = 0xff << (8 - pieces % 8);
+        }
+        reply.extend((bitfield.len() as u32 + 1).to_be_bytes());
+        reply.push(5);
+        reply.extend(bitfield);
+        stream.write_all(&reply).await.unwrap();
+
+        script(stream).await;
+    });
+
+    address
+}
@@ -0,0 +1,136 @@
+use bittorrent::{
+    create::{auto_piece_length, create_torrent, CreateOptions},
+    torrent::{Keys, Torrent, UrlList},
+    verify::verify,
+};
+use sha1::{Digest, Sha1};
+
+
+fn options(trackers: &[&str]) -> CreateOptions {
+    CreateOptions { trackers: trackers.iter().map(|tracker| tracker.to_string()).collect(), ..Default::default() }
+}
+
+#[test]
+fn trackers_go_into_announce_and_announce_list() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("release.bin");
+    std::fs::write(&path, b"release").unwrap();
+
+    let torrent = create_torrent(&path, &options(&["http://one/announce"])).unwrap();
+    assert_eq!(torrent.announce, "http://one/announce");
+    assert_eq!(torrent.announce_list, Some(vec![vec![String::from("http://one/announce")]]));
+    assert!(torrent.creation_date.is_some());
+    assert!(torrent.created_by.as_deref().is_some_and(|created_by| created_by.starts_with("bittorrent/")));
+
+    let torrent = create_torrent(&path, &options(&["http://one/announce", "udp://two:80"])).unwrap();
+    assert_eq!(torrent.trackers(), ["http://one/announce", "udp://two:80"]);
+    assert_eq!(torrent.tracker_tiers().len(), 2);
+
+    assert!(create_torrent(&path, &options(&[])).is_err());
+}
+
+#[test]
+fn options_are_carried_into_the_metainfo() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("release.bin");
+    std::fs::write(&path, vec![5; 40_000]).unwrap();
+
+    let options = CreateOptions {
+        piece_length: Some(1 << 15),
+        private: true,
+        comment: Some(String::from("nightly")),
+        webseeds: vec![String::from("https://mirror/release.bin")],
+        ..options(&["http://one/announce"])
+    };
+    let torrent = create_torrent(&path, &options).unwrap();
+
+    assert_eq!(torrent.info.name, "release.bin");
+    assert_eq!(torrent.info.keys, Keys::SingleFile { length: 40_000 });
+    assert_eq!((torrent.info.piece_length, torrent.info.pieces.0.len()), (1 << 15, 2));
+    assert_eq!(torrent.info.private, Some(1));
+    assert_eq!(torrent.comment.as_deref(), Some("nightly"));
+    assert_eq!(torrent.url_list, Some(UrlList::Many(vec![String::from("https://mirror/release.bin")])));
+    assert!(verify(&torrent, &path).unwrap().is_complete());
+
+    let invalid = CreateOptions { piece_length: Some(3 << 14), ..options };
+    assert!(create_torrent(&path, &invalid).is_err());
+}
+
+#[test]
+fn automatic_piece_length_is_a_bounded_power_of_two() {
+    assert_eq!(auto_piece_length(0), 1 << 14);
+    assert_eq!(auto_piece_length(1 << 20), 1 << 14);
+    assert_eq!(auto_piece_length(1500 << 20), 1 << 20);
+    assert_eq!(auto_piece_length(1 << 40), 1 << 24);
+}
+
+#[test]
+fn directories_become_sorted_multi_file_torrents_that_load_back() {
+    let directory = tempfile::tempdir().unwrap();
+    let pack = directory.path().join("pack");
+    std::fs::create_dir_all(pack.join("sub")).unwrap();
+    std::fs::write(pack.join("b.txt"), b"bravo").unwrap();
+    std::fs::write(pack.join("a.txt"), b"alpha").unwrap();
+    std::fs::write(pack.join("sub/c.txt"), b"charlie").unwrap();
+
+    let torrent = create_torrent(&pack, &options(&["http://one/announce"])).unwrap();
+    let Keys::MultiFile { files } = &torrent.info.keys else {
+        panic!("expected a multi-file torrent");
+    };
+    let files = files.iter().map(|file| (file.path.join("/"), file.length)).collect::<Vec<_>>();
+    assert_eq!(files, [(String::from("a.txt"), 5), (String::from("b.txt"), 5), (String::from("sub/c.txt"), 7)]);
+
+    let path = directory.path().join("pack.torrent");
+    std::fs::write(&path, serde_bencode::to_bytes(&torrent).unwrap()).unwrap();
+    let loaded = Torrent::try_from(path).unwrap();
+    assert_eq!(loaded.info_hash().unwrap(), torrent.info_hash().unwrap());
+}
+
+#[test]
+fn symbolic_links_are_skipped() {
+    let directory = tempfile::tempdir().unwrap();
+    let pack = directory.path().join("pack");
+    std::fs::create_dir_all(pack.join("a")).unwrap();
+    std::fs::write(pack.join("a/file.txt"), b"content").unwrap();
+
+    // A link back up would make the walk go around forever
+    std::os::unix::fs::symlink("..", pack.join("a/loop")).unwrap();
+    std::os::unix::fs::symlink("file.txt", pack.join("a/link.txt")).unwrap();
+
+    let torrent = create_torrent(&pack, &options(&["http://one/announce"])).unwrap();
+    let Keys::MultiFile { files } = &torrent.info.keys else {
+        panic!("expected a multi-file torrent");
+    };
+    assert_eq!(files.iter().map(|file| file.path.join("/")).collect::<Vec<_>>(), ["a/file.txt"]);
+}
+
+#[test]
+fn empty_content_is_refused() {
+    let directory = tempfile::tempdir().unwrap();
+    std::fs::create_dir(directory.path().join("empty")).unwrap();
+
+    assert!(create_torrent(&directory.path().join("empty"), &options(&["http://one/announce"])).is_err());
+}
+
+#[test]
+fn files_are_hashed_under_their_own_names() {
+    let directory = tempfile::tempdir().unwrap();
+    let pack = directory.path().join("pack");
+    std::fs::create_dir(&pack).unwrap();
+
+    // Names that are stored under other paths when downloaded, `readme` would become `readme_3`
+    let names = ["README", "a:b", "dot.", "readme", "readme_3"];
+    for name in names {
+        std::fs::write(pack.join(name), name.repeat(1000)).unwrap();
+    }
+
+    let torrent = create_torrent(&pack, &CreateOptions { piece_length: Some(1 << 14), ..options(&["http://one/announce"]) }).unwrap();
+    let Keys::MultiFile { files } = &torrent.info.keys else {
+        panic!("expected a multi-file torrent");
+    };
+    assert_eq!(files.iter().map(|file| file.path.join("/")).collect::<Vec<_>>(), names);
+
+    let content = names.iter().map(|name| name.repeat(1000)).collect::<String>();
+    let hashes = content.as_bytes().chunks(1 << 14).map(|piece| <[u8; 20]>::from(Sha1::digest(piece))).collect::<Vec<_>>();
+    assert_eq!(torrent.info.pieces.0, hashes);
+}
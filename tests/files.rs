@@ -0,0 +1,139 @@
+use std::collections::BTreeSet;
+use bittorrent::{
+    files::{FileSelection, FileSelector},
+    storage::{DiskStorage, Storage},
+    torrent::{File, Info, Keys, PieceHashes, Torrent},
+};
+
+
+/// Multi-file torrent named `pack` with 8 byte pieces, hashes are never checked here
+fn torrent(files: &[(&str, usize)]) -> Torrent {
+    let files = files
+        .iter()
+        .map(|(path, length)| File { length: *length, path: path.split('/').map(String::from).collect() })
+        .collect::<Vec<_>>();
+    let total_length = files.iter().map(|file| file.length).sum::<usize>();
+
+    Torrent {
+        announce: String::from("http://127.0.0.1:1/announce"),
+        announce_list: None,
+        creation_date: None,
+        created_by: None,
+        comment: None,
+        url_list: None,
+        info: Info {
+            name: String::from("pack"),
+            piece_length: 8,
+            pieces: PieceHashes(vec![[0; 20]; total_length.div_ceil(8)]),
+            private: None,
+            keys: Keys::MultiFile { files },
+        },
+    }
+}
+
+fn selection(selectors: &[&str]) -> FileSelection {
+    FileSelection(selectors.iter().map(|selector| selector.parse().unwrap()).collect())
+}
+
+/// Torrent paths of the files the selection picks
+fn selected(torrent: &Torrent, selectors: &[&str]) -> Vec<String> {
+    let selection = selection(selectors);
+    torrent.files().into_iter().filter(|file| selection.selects(file)).map(|file| file.torrent_path).collect()
+}
+
+#[test]
+fn numbers_select_by_index_and_everything_else_is_a_glob() {
+    assert!(matches!("2".parse::<FileSelector>().unwrap(), FileSelector::Index(2)));
+    assert!(matches!("2.txt".parse::<FileSelector>().unwrap(), FileSelector::Glob(_)));
+
+    let torrent = torrent(&[("a.txt", 1), ("b.txt", 1), ("c.bin", 1)]);
+    assert_eq!(selected(&torrent, &["1"]), ["b.txt"]);
+    assert_eq!(selected(&torrent, &["0", "*.bin"]), ["a.txt", "c.bin"]);
+    assert_eq!(selected(&torrent, &["7"]), Vec::<String>::new());
+    assert_eq!(selected(&torrent, &[]), ["a.txt", "b.txt", "c.bin"]);
+}
+
+#[test]
+fn globs_without_a_slash_match_the_file_name() {
+    let torrent = torrent(&[("top.txt", 1), ("docs/guide.txt", 1), ("docs/deep/notes.txt", 1), ("docs/image.png", 1)]);
+
+    assert_eq!(selected(&torrent, &["*.txt"]), ["top.txt", "docs/guide.txt", "docs/deep/notes.txt"]);
+    assert_eq!(selected(&torrent, &["????.txt"]), Vec::<String>::new());
+    assert_eq!(selected(&torrent, &["?????.txt"]), ["docs/guide.txt", "docs/deep/notes.txt"]);
+    assert_eq!(selected(&torrent, &["docs"]), Vec::<String>::new());
+}
+
+#[test]
+fn globs_with_a_slash_match_the_whole_path() {
+    let torrent = torrent(&[("top.txt", 1), ("docs/guide.txt", 1), ("docs/deep/notes.txt", 1)]);
+
+    // `*` stays within a directory, `**` crosses them
+    assert_eq!(selected(&torrent, &["docs/*.txt"]), ["docs/guide.txt"]);
+    assert_eq!(selected(&torrent, &["docs/**.txt"]), ["docs/guide.txt", "docs/deep/notes.txt"]);
+    assert_eq!(selected(&torrent, &["*/*/*"]), ["docs/deep/notes.txt"]);
+    assert_eq!(selected(&torrent, &["deep/notes.txt"]), Vec::<String>::new());
+}
+
+#[test]
+fn glob_patterns_escape_regex_syntax() {
+    let torrent = torrent(&[("a+b.txt", 1), ("aab.txt", 1), ("a.b", 1), ("axb", 1)]);
+
+    assert_eq!(selected(&torrent, &["a+b.txt"]), ["a+b.txt"]);
+    assert_eq!(selected(&torrent, &["a.b"]), ["a.b"]);
+    assert_eq!(selected(&torrent, &["(a"]), Vec::<String>::new());
+}
+
+#[test]
+fn wanted_pieces_include_boundary_pieces() {
+    // a: bytes 0..10, b: 10..30, c: 30..35, pieces of 8 bytes
+    let torrent = torrent(&[("a", 10), ("b", 20), ("c", 5)]);
+
+    assert_eq!(torrent.wanted_pieces(&selection(&["b"])), BTreeSet::from([1, 2, 3]));
+    assert_eq!(torrent.wanted_pieces(&selection(&["a"])), BTreeSet::from([0, 1]));
+    assert_eq!(torrent.wanted_pieces(&selection(&["c"])), BTreeSet::from([3, 4]));
+    assert_eq!(torrent.wanted_pieces(&selection(&[])), BTreeSet::from([0, 1, 2, 3, 4]));
+}
+
+#[test]
+fn unselected_bytes_of_boundary_pieces_go_into_the_part_file() {
+    let directory = tempfile::tempdir().unwrap();
+    let torrent = torrent(&[("a", 10), ("sub/b", 20), ("c", 5)]);
+    let storage = DiskStorage::create(&torrent, directory.path(), &selection(&["sub/b"])).unwrap();
+
+    // Unselected files are never created, pieces 1 and 3 each get a slot in the part-file
+    let pack = directory.path().join("pack");
+    let part_file = directory.path().join("pack.parts");
+    assert!(!pack.join("a").exists());
+    assert!(!pack.join("c").exists());
+    assert_eq!(std::fs::metadata(pack.join("sub/b")).unwrap().len(), 20);
+    assert_eq!(std::fs::metadata(&part_file).unwrap().len(), 16);
+
+    let data = (0..35).collect::<Vec<u8>>();
+    for index in 1..4 {
+        storage.write_block(index, 0, &data[index * 8..(index * 8 + 8).min(35)]).unwrap();
+    }
+    storage.flush().unwrap();
+
+    assert_eq!(std::fs::read(pack.join("sub/b")).unwrap(), &data[10..30]);
+    let parts = std::fs::read(&part_file).unwrap();
+    // Slot 0 holds the head of piece 1 that falls into a, slot 1 the tail of piece 3 in c
+    assert_eq!(&parts[..2], &data[8..10]);
+    assert_eq!(&parts[14..], &data[30..32]);
+    assert!(parts[2..14].iter().all(|&byte| byte == 0), "bytes of sub/b only go into sub/b");
+
+    // Blocks read back across file boundaries, pieces outside the selection can't be stored
+    assert_eq!(storage.read_block(1, 0, 8).unwrap(), &data[8..16]);
+    assert_eq!(storage.read_block(3, 4, 4).unwrap(), &data[28..32]);
+    assert!(storage.write_block(0, 0, &data[..8]).is_err());
+    assert!(storage.write_block(4, 0, &data[32..]).is_err());
+}
+
+#[test]
+fn selecting_every_file_needs_no_part_file() {
+    let directory = tempfile::tempdir().unwrap();
+    let torrent = torrent(&[("a", 10), ("b", 20)]);
+    let storage = DiskStorage::create(&torrent, directory.path(), &FileSelection::default()).unwrap();
+
+    assert!(!directory.path().join("pack.parts").exists());
+    assert_eq!(storage.paths().count(), 2);
+}
@@ -0,0 +1,69 @@
+use std::process::Command;
+use bittorrent::{
+    create::{create_torrent, CreateOptions},
+    torrent::{Info, Keys, PieceHashes, Torrent},
+};
+
+
+fn torrent() -> Torrent {
+    Torrent {
+        announce: String::from("http://tracker/announce"),
+        announce_list: None,
+        creation_date: None,
+        created_by: None,
+        comment: None,
+        url_list: None,
+        info: Info {
+            name: String::from("fixed.bin"),
+            piece_length: 1 << 14,
+            pieces: PieceHashes(vec![[7; 20]]),
+            private: None,
+            keys: Keys::SingleFile { length: 100 },
+        },
+    }
+}
+
+#[test]
+fn info_hash_is_the_sha1_of_the_bencoded_info() {
+    // Worked out independently from `d6:lengthi100e4:name9:fixed.bin12:piece lengthi16384e6:pieces20:...e`
+    assert_eq!(hex::encode(torrent().info_hash().unwrap()), "0a5988b14800b4ca0e551bff6f97a04d45debd5a");
+}
+
+#[test]
+fn base32_info_hash_follows_rfc_4648() {
+    let base32 = torrent().info_hash_base32().unwrap();
+
+    assert_eq!(base32, "BJMYRMKIAC2MUDSVDP7W7F5AJVC55PK2");
+    assert_eq!(base32.len(), 32);
+}
+
+#[test]
+fn info_command_prints_json() {
+    let directory = tempfile::tempdir().unwrap();
+    let pack = directory.path().join("pack");
+    std::fs::create_dir_all(pack.join("sub")).unwrap();
+    std::fs::write(pack.join("a.bin"), vec![1; 20_000]).unwrap();
+    std::fs::write(pack.join("sub/b.bin"), vec![2; 30_000]).unwrap();
+
+    let trackers = vec![String::from("http://one/announce"), String::from("udp://two:80")];
+    let torrent = create_torrent(&pack, &CreateOptions { trackers, piece_length: Some(1 << 14), ..Default::default() }).unwrap();
+    let path = directory.path().join("pack.torrent");
+    std::fs::write(&path, serde_bencode::to_bytes(&torrent).unwrap()).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_bittorrent")).arg("info").arg(&path).arg("--json").output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let info = serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap();
+    assert_eq!(info["info_hash"], hex::encode(torrent.info_hash().unwrap()));
+    assert_eq!(info["info_hash_base32"], torrent.info_hash_base32().unwrap());
+    assert_eq!(info["name"], "pack");
+    assert_eq!((info["total_size"].as_u64(), info["piece_length"].as_u64(), info["piece_count"].as_u64()), (Some(50_000), Some(1 << 14), Some(4)));
+    assert_eq!(info["trackers"], serde_json::json!(["http://one/announce", "udp://two:80"]));
+    assert_eq!(
+        info["files"],
+        serde_json::json!([
+            { "path": "a.bin", "size": 20_000, "offset": 0 },
+            { "path": "sub/b.bin", "size": 30_000, "offset": 20_000 },
+        ]),
+    );
+}
@@ -0,0 +1,51 @@
+use std::{sync::Arc, time::{Duration, Instant}};
+use bittorrent::limit::{Limits, RateLimiter, Throttled};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+
+#[test]
+fn unlimited_bucket_never_waits() {
+    let limiter = RateLimiter::new(None);
+    limiter.consume(1 << 30);
+    assert_eq!(limiter.delay(), Duration::ZERO);
+}
+
+#[test]
+fn debt_is_paid_back_at_the_rate() {
+    let limiter = RateLimiter::new(Some(1000));
+    limiter.consume(1000);
+    assert_eq!(limiter.delay(), Duration::ZERO);
+
+    limiter.consume(500);
+    let delay = limiter.delay();
+    assert!(delay > Duration::from_millis(400) && delay <= Duration::from_millis(500), "{delay:?}");
+
+    limiter.set_rate(None);
+    assert_eq!(limiter.delay(), Duration::ZERO);
+    assert_eq!(limiter.rate(), None);
+}
+
+#[tokio::test]
+async fn throttled_writes_follow_the_slowest_limit() {
+    let session = Arc::new(RateLimiter::new(Some(64 * 1024)));
+    let torrent = Arc::new(RateLimiter::new(Some(8 * 1024)));
+    let limits = Limits { download: Vec::new(), upload: vec![session, torrent] };
+
+    let (client, mut server) = tokio::io::duplex(64 * 1024);
+    let mut client = Throttled::new(client, limits);
+
+    let started = Instant::now();
+    let data = vec![7; 16 * 1024];
+    let writer = tokio::spawn(async move {
+        for chunk in data.chunks(1024) {
+            client.write_all(chunk).await.unwrap();
+        }
+    });
+
+    let mut received = vec![0; 16 * 1024];
+    server.read_exact(&mut received).await.unwrap();
+    writer.await.unwrap();
+
+    // One second worth of tokens goes out right away, the other 8 KiB take about a second
+    assert!(started.elapsed() >= Duration::from_millis(750), "{:?}", started.elapsed());
+}
@@ -0,0 +1,125 @@
+use std::{io::Write, net::SocketAddr, process::Command, sync::{Arc, Mutex}};
+use bittorrent::{
+    handshake::Handshake,
+    peer_id::PeerId,
+    session::{AddTorrentOptions, Session, SessionOptions},
+};
+use tokio::net::TcpStream;
+
+mod common;
+
+
+/// Log output kept in memory
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Captured {
+    fn lines(&self) -> Vec<String> {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).lines().map(str::to_string).collect()
+    }
+}
+
+// Spawned tasks run on the test thread, where the subscriber is set
+#[tokio::test(flavor = "current_thread")]
+async fn peer_events_are_logged_in_torrent_and_peer_spans() {
+    let captured = Captured::default();
+    let writer = captured.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _default = tracing::subscriber::set_default(subscriber);
+
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("spans.bin");
+    common::write_random(&path, 20_000);
+    let torrent = common::torrent_of(&path, 1 << 14);
+
+    let options = SessionOptions {
+        listen: SocketAddr::from(([127, 0, 0, 1], 0)),
+        download_directory: directory.path().to_path_buf(),
+        lsd: None,
+        ..Default::default()
+    };
+    let session = Session::new(options).await.unwrap();
+    let handle = session.add(torrent.clone(), AddTorrentOptions::default()).unwrap();
+    handle.finished().await.unwrap();
+
+    let mut stream = TcpStream::connect(session.listen_address()).await.unwrap();
+    let local = stream.local_addr().unwrap();
+    Handshake::new(&torrent, PeerId::generate()).unwrap().establish(&mut stream).await.unwrap();
+    while handle.status().peers == 0 {
+        tokio::task::yield_now().await;
+    }
+    session.shutdown().await;
+
+    let lines = captured.lines();
+    let torrent_span = format!("torrent{{torrent=spans.bin info_hash={}}}", hex::encode(handle.info_hash()));
+    let checked = lines.iter().find(|line| line.contains("storage checked")).unwrap();
+    assert!(checked.contains(&format!(" INFO {torrent_span}: ")), "{checked}");
+
+    let connected = lines.iter().find(|line| line.contains("peer connected")).unwrap();
+    assert!(connected.contains(&format!(" DEBUG {torrent_span}:peer{{address={local} incoming=true}}: ")), "{connected}");
+}
+
+/// Runs `download` of a torrent whose content is complete already, returns stdout and stderr
+fn download(arguments: &[&str]) -> (String, String) {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("cli.bin");
+    common::write_random(&path, 20_000);
+    let torrent = common::torrent_of(&path, 1 << 14);
+    let torrent_path = directory.path().join("cli.torrent");
+    std::fs::write(&torrent_path, serde_bencode::to_bytes(&torrent).unwrap()).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_bittorrent"))
+        .arg("download")
+        .arg(&torrent_path)
+        .arg("--output")
+        .arg(directory.path())
+        .args(["--port", "0", "--no-lsd"])
+        .args(arguments)
+        .env_remove("RUST_LOG")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    (String::from_utf8(output.stdout).unwrap(), String::from_utf8(output.stderr).unwrap())
+}
+
+#[test]
+fn json_logs_go_to_stderr() {
+    let (stdout, stderr) = download(&["-v", "--log-format", "json"]);
+
+    let logs = stderr.lines().map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()).collect::<Vec<_>>();
+    let checked = logs.iter().find(|log| log["fields"]["message"] == "storage checked").unwrap();
+    assert_eq!(checked["level"], "INFO");
+    assert_eq!(checked["fields"]["verified"], 2);
+    assert_eq!(checked["span"]["name"], "torrent");
+    assert_eq!(checked["span"]["torrent"], "cli.bin");
+
+    assert!(!stdout.contains("storage checked"), "{stdout}");
+}
+
+#[test]
+fn verbosity_flags_choose_the_level() {
+    let (_, stderr) = download(&["-v"]);
+    assert!(stderr.contains("storage checked"), "{stderr}");
+
+    let (_, stderr) = download(&[]);
+    assert!(!stderr.contains("storage checked"), "{stderr}");
+
+    let (_, stderr) = download(&["-q"]);
+    assert!(stderr.is_empty(), "{stderr}");
+}
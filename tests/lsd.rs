@@ -0,0 +1,109 @@
+use std::{net::{Ipv4Addr, SocketAddr}, time::Duration};
+use bittorrent::{
+    lsd::{Lsd, LsdAnnounce, LsdOptions, IPV4_GROUP},
+    session::{AddTorrentOptions, Session, SessionOptions},
+};
+
+mod common;
+
+
+/// Loopback only, on a port of its own so that tests running at once don't hear each other
+fn loopback() -> LsdOptions {
+    LsdOptions { interface: Ipv4Addr::LOCALHOST, ipv6: false, port: fastrand::u16(20_000..60_000) }
+}
+
+#[test]
+fn announces_round_trip_and_garbage_is_rejected() {
+    let announce = LsdAnnounce { port: 6881, info_hashes: vec![[0xab; 20], [0x01; 20]], cookie: Some(String::from("c0ffee")) };
+    let bytes = announce.to_bytes(SocketAddr::from((IPV4_GROUP, 6771)));
+
+    let text = String::from_utf8(bytes.clone()).unwrap();
+    assert!(text.starts_with("BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\nInfohash: abab"), "{text}");
+    assert!(text.ends_with("\r\n\r\n\r\n"), "{text:?}");
+    assert_eq!(LsdAnnounce::parse(&bytes).unwrap(), announce);
+
+    let other = b"BT-SEARCH * HTTP/1.1\r\nhost: x\r\nPORT: 51413\r\ninfohash: ABABABABABABABABABABABABABABABABABABABAB\r\nInfohash: short\r\n\r\n\r\n";
+    let parsed = LsdAnnounce::parse(other).unwrap();
+    assert_eq!((parsed.port, parsed.info_hashes, parsed.cookie), (51413, vec![[0xab; 20]], None));
+
+    assert!(LsdAnnounce::parse(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n\r\n").is_err());
+    assert!(LsdAnnounce::parse(b"BT-SEARCH * HTTP/1.1\r\nPort: 0\r\nInfohash: abababababababababababababababababababab\r\n\r\n\r\n").is_err());
+    assert!(LsdAnnounce::parse(b"BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\n\r\n\r\n").is_err());
+}
+
+#[tokio::test]
+async fn members_hear_each_other_but_not_themselves() {
+    let options = loopback();
+    let first = Lsd::bind(&options).unwrap();
+    let second = Lsd::bind(&options).unwrap();
+
+    first.announce(7000, &[[7; 20]]).await.unwrap();
+    let (address, announce) = tokio::time::timeout(Duration::from_secs(5), second.recv()).await.unwrap().unwrap();
+    assert_eq!(address, SocketAddr::from(([127, 0, 0, 1], 7000)));
+    assert_eq!(announce.info_hashes, [[7; 20]]);
+
+    assert!(tokio::time::timeout(Duration::from_millis(300), first.recv()).await.is_err());
+}
+
+#[tokio::test]
+async fn session_announces_torrents_and_adds_peers_from_the_local_network() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("lsd.bin");
+    std::fs::write(&path, vec![3; 20_000]).unwrap();
+    let torrent = common::torrent_of(&path, 1 << 14);
+    let info_hash = torrent.info_hash().unwrap();
+
+    let lsd = loopback();
+    let other = Lsd::bind(&lsd).unwrap();
+    let options = SessionOptions {
+        listen: SocketAddr::from(([127, 0, 0, 1], 0)),
+        download_directory: directory.path().to_path_buf(),
+        lsd: Some(lsd),
+        ..Default::default()
+    };
+    let session = Session::new(options).await.unwrap();
+    let handle = session.add(torrent, AddTorrentOptions::default()).unwrap();
+
+    let (address, announce) = tokio::time::timeout(Duration::from_secs(5), other.recv()).await.unwrap().unwrap();
+    assert_eq!(address, session.listen_address());
+    assert_eq!(announce.info_hashes, [info_hash]);
+
+    other.announce(4321, &[info_hash, [9; 20]]).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while handle.status().known_peers == 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(handle.status().known_peers, 1);
+
+    session.shutdown().await;
+}
+
+#[tokio::test]
+async fn private_torrents_stay_off_the_local_network() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("private.bin");
+    std::fs::write(&path, vec![4; 20_000]).unwrap();
+    let mut torrent = common::torrent_of(&path, 1 << 14);
+    torrent.info.private = Some(1);
+    let info_hash = torrent.info_hash().unwrap();
+
+    let lsd = loopback();
+    let other = Lsd::bind(&lsd).unwrap();
+    let options = SessionOptions {
+        listen: SocketAddr::from(([127, 0, 0, 1], 0)),
+        download_directory: directory.path().to_path_buf(),
+        lsd: Some(lsd),
+        ..Default::default()
+    };
+    let session = Session::new(options).await.unwrap();
+    let handle = session.add(torrent, AddTorrentOptions::default()).unwrap();
+
+    other.announce(4321, &[info_hash]).await.unwrap();
+    assert!(tokio::time::timeout(Duration::from_secs(2), other.recv()).await.is_err());
+    assert_eq!(handle.status().known_peers, 0);
+
+    session.shutdown().await;
+}
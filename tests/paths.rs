@@ -0,0 +1,207 @@
+use std::path::{Component, Path, PathBuf};
+use bittorrent::{
+    paths::{component_issues, sanitize_component, PathIssue, PathPolicy},
+    torrent::{File, Info, Keys, PieceHashes, Torrent},
+};
+
+
+fn torrent(name: &str, files: &[&[&str]]) -> Torrent {
+    let files = files
+        .iter()
+        .map(|path| File { length: 1, path: path.iter().map(|part| part.to_string()).collect() })
+        .collect::<Vec<_>>();
+
+    Torrent {
+        announce: String::from("http://127.0.0.1:1/announce"),
+        announce_list: None,
+        creation_date: None,
+        created_by: None,
+        comment: None,
+        url_list: None,
+        info: Info {
+            name: name.to_string(),
+            piece_length: 1 << 14,
+            pieces: PieceHashes(vec![[0; 20]]),
+            private: None,
+            keys: Keys::MultiFile { files },
+        },
+    }
+}
+
+/// Safe paths joined with `/`, checked to stay inside the download directory
+fn safe_paths(torrent: &Torrent) -> Vec<String> {
+    torrent
+        .safe_paths()
+        .into_iter()
+        .map(|(path, _)| {
+            assert!(path.components().all(|component| matches!(component, Component::Normal(_))), "{}", path.display());
+            path.iter().map(|part| part.to_str().unwrap()).collect::<Vec<_>>().join("/")
+        })
+        .collect()
+}
+
+#[test]
+fn relative_components_become_placeholders() {
+    assert_eq!(sanitize_component(".."), "_");
+    assert_eq!(sanitize_component("."), "_");
+    assert_eq!(sanitize_component(""), "_");
+    assert_eq!(component_issues(".."), [PathIssue::Relative]);
+    assert_eq!(component_issues(""), [PathIssue::Empty]);
+}
+
+#[test]
+fn separators_never_reach_the_path() {
+    assert_eq!(sanitize_component("a/b"), "a_b");
+    assert_eq!(sanitize_component("a\\b"), "a_b");
+    assert_eq!(sanitize_component("/etc/passwd"), "_etc_passwd");
+    assert_eq!(sanitize_component("..\\..\\evil.exe"), ".._.._evil.exe");
+    assert_eq!(component_issues("/etc/passwd"), [PathIssue::Separator]);
+}
+
+#[test]
+fn control_and_reserved_characters_are_replaced() {
+    assert_eq!(sanitize_component("a\0b\nc"), "a_b_c");
+    assert_eq!(sanitize_component("what?<>:\"|*"), "what_______");
+    assert_eq!(component_issues("a\0b"), [PathIssue::Control]);
+    assert_eq!(component_issues("a:b"), [PathIssue::Reserved]);
+}
+
+#[test]
+fn reserved_windows_names_are_prefixed() {
+    assert_eq!(sanitize_component("CON"), "_CON");
+    assert_eq!(sanitize_component("con.txt"), "_con.txt");
+    assert_eq!(sanitize_component("Lpt9.tar.gz"), "_Lpt9.tar.gz");
+    assert_eq!(sanitize_component("CONSOLE"), "CONSOLE");
+    assert_eq!(sanitize_component("COM10"), "COM10");
+    assert_eq!(component_issues("nul.txt"), [PathIssue::Reserved]);
+    assert!(component_issues("console.txt").is_empty());
+}
+
+#[test]
+fn trailing_dots_and_spaces_are_trimmed() {
+    assert_eq!(sanitize_component("name. "), "name");
+    assert_eq!(sanitize_component("file..."), "file");
+    assert_eq!(sanitize_component(" ."), "_");
+    assert_eq!(sanitize_component("aux ."), "_aux");
+    assert_eq!(sanitize_component(".hidden"), ".hidden");
+    assert_eq!(component_issues("name "), [PathIssue::Reserved]);
+}
+
+#[test]
+fn long_names_are_shortened_keeping_the_extension() {
+    let long = format!("{}.txt", "x".repeat(300));
+    let sanitized = sanitize_component(&long);
+    assert_eq!(sanitized.len(), 255);
+    assert!(sanitized.ends_with("x.txt"));
+    assert_eq!(component_issues(&long), [PathIssue::TooLong]);
+
+    // Cut on a character boundary
+    let sanitized = sanitize_component(&"é".repeat(200));
+    assert!(sanitized.len() <= 255);
+    assert!(sanitized.chars().all(|c| c == 'é'));
+
+    // Long "extensions" are not kept
+    let sanitized = sanitize_component(&format!("a.{}", "y".repeat(300)));
+    assert_eq!(sanitized, format!("a.{}", "y".repeat(253)));
+}
+
+#[test]
+fn ordinary_names_are_left_alone() {
+    for name in ["file.txt", "Ünïcödé 日本", "a b", "-dash", "v1.2.3.tar.gz"] {
+        assert_eq!(sanitize_component(name), name);
+        assert!(component_issues(name).is_empty(), "{name}");
+    }
+}
+
+#[test]
+fn traversal_stays_inside_the_download_directory() {
+    let torrent = torrent("..", &[&["..", "..", "etc", "passwd"], &["/", "abs"], &["/etc/shadow"], &[".", "x"], &["", "y"], &[]]);
+
+    assert_eq!(safe_paths(&torrent), ["_/_/_/etc/passwd", "_/_/abs", "_/_etc_shadow", "_/_/x", "_/y", "_/__5"]);
+
+    let issues = torrent.safe_paths().into_iter().map(|(_, issues)| issues).collect::<Vec<_>>();
+    assert_eq!(issues[0], [PathIssue::Relative]);
+    assert_eq!(issues[1], [PathIssue::Separator]);
+    assert_eq!(issues[4], [PathIssue::Empty]);
+    assert_eq!(issues[5], [PathIssue::Empty, PathIssue::Duplicate]);
+}
+
+#[test]
+fn reserved_names_are_renamed_and_reported() {
+    let torrent = torrent("CON", &[&["aux", "nul.txt"], &["ok.txt"]]);
+
+    assert_eq!(safe_paths(&torrent), ["_CON/_aux/_nul.txt", "_CON/ok.txt"]);
+
+    let changes = torrent.path_changes();
+    assert_eq!(changes.len(), 2);
+    assert_eq!((changes[0].file, changes[0].sanitized.clone()), (None, PathBuf::from("_CON")));
+    assert_eq!((changes[1].file, changes[1].original.as_str()), (Some(0), "aux/nul.txt"));
+    assert_eq!(changes[1].issues, [PathIssue::Reserved]);
+}
+
+#[test]
+fn paths_differing_only_in_case_get_their_own_files() {
+    let torrent = torrent("pack", &[&["Readme.md"], &["README.md"], &["Dir", "a"], &["dir", "a"], &["dir", "b"]]);
+
+    assert_eq!(safe_paths(&torrent), ["pack/Readme.md", "pack/README_1.md", "pack/Dir/a", "pack/dir/a_3", "pack/dir/b"]);
+}
+
+#[test]
+fn files_and_directories_never_share_a_path() {
+    // A file where an earlier file has a directory, and a directory where an earlier one is a file
+    let torrent = torrent("pack", &[&["x", "inner"], &["x"], &["y"], &["y", "inner"]]);
+
+    assert_eq!(safe_paths(&torrent), ["pack/x/inner", "pack/x_1", "pack/y", "pack/y_3/inner"]);
+}
+
+#[test]
+fn files_of_a_renamed_directory_stay_together() {
+    let torrent = torrent("pack", &[&["A"], &["A", "x"], &["a", "y"], &["A", "z", "w"]]);
+
+    assert_eq!(safe_paths(&torrent), ["pack/A", "pack/A_1/x", "pack/A_1/y", "pack/A_1/z/w"]);
+}
+
+#[test]
+fn renamed_duplicates_skip_names_that_are_taken() {
+    let suffixed = torrent("n", &[&["x_2"], &["x"], &["x"]]);
+    assert_eq!(safe_paths(&suffixed), ["n/x_2", "n/x", "n/x_3"]);
+
+    let extensions = torrent("n", &[&["a_2.txt"], &["A_3.TXT"], &["a.txt"], &["a.txt"], &["a_2.txt", "f"]]);
+    assert_eq!(safe_paths(&extensions), ["n/a_2.txt", "n/A_3.TXT", "n/a.txt", "n/a_4.txt", "n/a_2_4.txt/f"]);
+}
+
+#[test]
+fn no_two_files_end_up_at_the_same_path() {
+    let names = ["a", "A", "a_1", "a_2", "A_2", "a.", "a ", "a?"];
+    let mut rng = fastrand::Rng::with_seed(7);
+
+    for _ in 0..256 {
+        let paths = (0..rng.usize(1..12))
+            .map(|_| (0..rng.usize(1..3)).map(|_| names[rng.usize(..names.len())]).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let paths = paths.iter().map(|path| path.as_slice()).collect::<Vec<_>>();
+
+        let safe = safe_paths(&torrent("pack", &paths)).into_iter().map(|path| path.to_lowercase()).collect::<Vec<_>>();
+        for (index, path) in safe.iter().enumerate() {
+            for other in &safe[..index] {
+                assert_ne!(path, other, "{paths:?}");
+                assert!(!Path::new(path).starts_with(other) && !Path::new(other).starts_with(path), "{paths:?}");
+            }
+        }
+    }
+}
+
+#[test]
+fn strict_loading_rejects_unsafe_paths() {
+    let directory = tempfile::tempdir().unwrap();
+    let mut torrent = torrent("pack", &[&["..", "evil"]]);
+    torrent.info.pieces = PieceHashes(vec![[0; 20]]);
+    let path = directory.path().join("evil.torrent");
+    std::fs::write(&path, serde_bencode::to_bytes(&torrent).unwrap()).unwrap();
+
+    let error = Torrent::load(&path, PathPolicy::Reject).unwrap_err();
+    assert!(format!("{error:#}").contains("unsafe paths"), "{error:#}");
+
+    let (_, changes) = Torrent::load(&path, PathPolicy::Rewrite).unwrap();
+    assert_eq!(changes[0].sanitized, PathBuf::from("pack/_/evil"));
+}
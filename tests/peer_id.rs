@@ -0,0 +1,46 @@
+use bittorrent::peer_id::{default_prefix, Client, PeerId};
+
+
+#[test]
+fn generated_ids_start_with_prefix_and_differ() {
+    let first = PeerId::generate();
+    let second = PeerId::generate();
+
+    assert!(first.0.starts_with(default_prefix().as_bytes()));
+    assert!(first.0.is_ascii());
+    assert_ne!(first, second);
+}
+
+#[test]
+fn custom_prefix_is_kept() {
+    let peer_id = PeerId::with_prefix("-XX0100-").unwrap();
+    assert!(peer_id.0.starts_with(b"-XX0100-"));
+
+    assert!(PeerId::with_prefix("a prefix that is longer than twenty bytes").is_err());
+}
+
+#[test]
+fn own_id_is_recognised() {
+    let client = PeerId::generate().client().unwrap();
+    assert_eq!(client.name, "bittorrent");
+}
+
+#[test]
+fn known_clients_are_recognised() {
+    let client = |id: &[u8; 20]| PeerId(*id).client();
+
+    assert_eq!(
+        client(b"-qB4250-abcdefghijkl"),
+        Some(Client { name: String::from("qBittorrent"), version: String::from("4.2.5") }),
+    );
+    assert_eq!(
+        client(b"-TR300Z-abcdefghijkl"),
+        Some(Client { name: String::from("Transmission"), version: String::from("3.0.0.35") }),
+    );
+    assert_eq!(
+        client(b"M7-10-3--abcdefghijk"),
+        Some(Client { name: String::from("BitTorrent"), version: String::from("7.10.3") }),
+    );
+    assert_eq!(client(b"-ZZ1000-abcdefghijkl").map(|client| client.name), Some(String::from("ZZ")));
+    assert_eq!(client(b"12345611111111111112"), None);
+}
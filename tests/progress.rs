@@ -0,0 +1,69 @@
+use std::time::Duration;
+use bittorrent::{
+    progress::{format_bytes, format_duration, summary, ProgressView},
+    session::{PeerStatus, TorrentState, TorrentStatus},
+};
+
+
+fn status() -> TorrentStatus {
+    TorrentStatus {
+        state: TorrentState::Downloading,
+        wanted: 200 * 1024,
+        done: 50 * 1024,
+        downloaded: 60 * 1024,
+        uploaded: 0,
+        download_rate: 1536.0,
+        upload_rate: 0.0,
+        eta: Some(Duration::from_secs(100)),
+        peers: 1,
+        known_peers: 4,
+    }
+}
+
+fn peer() -> PeerStatus {
+    PeerStatus {
+        address: "127.0.0.1:6881".parse().unwrap(),
+        client: None,
+        downloaded: 60 * 1024,
+        uploaded: 0,
+        download_rate: 1536.0,
+        upload_rate: 0.0,
+    }
+}
+
+#[test]
+fn sizes_and_durations_are_readable() {
+    assert_eq!(format_bytes(0.0), "0 B");
+    assert_eq!(format_bytes(1023.0), "1023 B");
+    assert_eq!(format_bytes(1536.0), "1.5 KiB");
+    assert_eq!(format_bytes(3.0 * 1024.0 * 1024.0 * 1024.0), "3.0 GiB");
+
+    assert_eq!(format_duration(Duration::from_secs(12)), "12s");
+    assert_eq!(format_duration(Duration::from_secs(185)), "3m05s");
+    assert_eq!(format_duration(Duration::from_secs(7620)), "2h07m");
+}
+
+#[test]
+fn summary_shows_progress_rates_and_peers() {
+    assert_eq!(
+        summary("pack", &status()),
+        "pack: downloading 25.0% of 200.0 KiB, down 1.5 KiB/s, up 0 B/s, ETA 1m40s, peers: 1 connected, 4 known",
+    );
+}
+
+#[test]
+fn plain_view_prints_lines_and_interactive_view_redraws() {
+    let mut out = Vec::new();
+    let mut view = ProgressView::new(&mut out, false);
+    view.update("pack", &status(), &[peer()]).unwrap();
+    view.message("Piece 3 verified").unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), format!("{}\nPiece 3 verified\n", summary("pack", &status())));
+
+    let mut out = Vec::new();
+    let mut view = ProgressView::new(&mut out, true);
+    view.update("pack", &status(), &[peer()]).unwrap();
+    view.message("Piece 3 verified").unwrap();
+
+    let drawn = format!("{}\n  127.0.0.1:6881: down 1.5 KiB/s, up 0 B/s\n", summary("pack", &status()));
+    assert_eq!(String::from_utf8(out).unwrap(), format!("{drawn}\x1b[2A\x1b[JPiece 3 verified\n{drawn}"));
+}
@@ -0,0 +1,100 @@
+use std::collections::BTreeSet;
+use bittorrent::{
+    files::FileSelection,
+    storage::{DiskStorage, Storage},
+    torrent::Torrent,
+};
+
+mod common;
+
+
+const PIECE_LENGTH: usize = 1 << 14;
+
+/// Torrent of a five piece file with distinct content in every piece
+fn torrent(directory: &std::path::Path) -> (Torrent, Vec<u8>) {
+    let data = (0..5 * PIECE_LENGTH).map(|offset| (offset / PIECE_LENGTH * 31 + offset % 251) as u8).collect::<Vec<_>>();
+    let path = directory.join("resumed.bin");
+    std::fs::write(&path, &data).unwrap();
+
+    let torrent = common::torrent_of(&path, PIECE_LENGTH);
+
+    (torrent, data)
+}
+
+fn verified(storage: &DiskStorage) -> BTreeSet<usize> {
+    let bitfield = storage.restore(&(0..5).collect()).unwrap();
+    (0..5).filter(|&index| bitfield.has(index)).collect()
+}
+
+#[test]
+fn interrupted_downloads_resume_without_a_recheck_after_a_clean_shutdown() {
+    let source = tempfile::tempdir().unwrap();
+    let downloads = tempfile::tempdir().unwrap();
+    let (torrent, data) = torrent(source.path());
+
+    let storage = DiskStorage::create(&torrent, downloads.path(), &FileSelection::default()).unwrap();
+    assert!(verified(&storage).is_empty());
+
+    for index in 0..2 {
+        storage.write_block(index, 0, &data[index * PIECE_LENGTH..(index + 1) * PIECE_LENGTH]).unwrap();
+        storage.piece_verified(index).unwrap();
+    }
+
+    // Piece 4 never arrived: it is only in the result if the resume data is trusted as is
+    storage.piece_verified(4).unwrap();
+
+    // Half of piece 2 was in flight when the download was stopped
+    storage.write_block(2, 0, &data[2 * PIECE_LENGTH..2 * PIECE_LENGTH + PIECE_LENGTH / 2]).unwrap();
+    storage.flush().unwrap();
+    drop(storage);
+
+    let storage = DiskStorage::create(&torrent, downloads.path(), &FileSelection::default()).unwrap();
+    assert!(!storage.is_fresh());
+    assert_eq!(verified(&storage), BTreeSet::from([0, 1, 4]));
+}
+
+#[test]
+fn downloads_that_were_not_flushed_are_rechecked() {
+    let source = tempfile::tempdir().unwrap();
+    let downloads = tempfile::tempdir().unwrap();
+    let (torrent, data) = torrent(source.path());
+
+    let storage = DiskStorage::create(&torrent, downloads.path(), &FileSelection::default()).unwrap();
+    verified(&storage);
+    storage.write_block(0, 0, &data[..PIECE_LENGTH]).unwrap();
+    storage.piece_verified(0).unwrap();
+    storage.piece_verified(4).unwrap();
+    storage.flush().unwrap();
+
+    // Written after the flush and never flushed again, as if the process was killed
+    storage.write_block(3, 0, &data[3 * PIECE_LENGTH..4 * PIECE_LENGTH]).unwrap();
+    storage.piece_verified(3).unwrap();
+    drop(storage);
+
+    let storage = DiskStorage::create(&torrent, downloads.path(), &FileSelection::default()).unwrap();
+    assert_eq!(verified(&storage), BTreeSet::from([0, 3]));
+}
+
+#[test]
+fn files_changed_after_a_clean_shutdown_are_rechecked() {
+    let source = tempfile::tempdir().unwrap();
+    let downloads = tempfile::tempdir().unwrap();
+    let (torrent, data) = torrent(source.path());
+
+    let storage = DiskStorage::create(&torrent, downloads.path(), &FileSelection::default()).unwrap();
+    verified(&storage);
+    storage.write_block(1, 0, &data[PIECE_LENGTH..2 * PIECE_LENGTH]).unwrap();
+    storage.piece_verified(1).unwrap();
+    storage.piece_verified(2).unwrap();
+    storage.flush().unwrap();
+    drop(storage);
+
+    // Another program appends to the file
+    let path = downloads.path().join("resumed.bin");
+    let mut content = std::fs::read(&path).unwrap();
+    content.push(0);
+    std::fs::write(&path, content).unwrap();
+
+    let storage = DiskStorage::create(&torrent, downloads.path(), &FileSelection::default()).unwrap();
+    assert_eq!(verified(&storage), BTreeSet::from([1]));
+}
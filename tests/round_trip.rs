@@ -0,0 +1,143 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use bittorrent::torrent::{File, Info, Keys, PieceHashes, Torrent, UrlList};
+use bittorrent::tracker::{Peers, TrackerResponse};
+use fastrand::Rng;
+use serde::{de::DeserializeOwned, Serialize};
+
+
+/// Every property is checked against this many generated values, each from its own seed
+const CASES: u64 = 256;
+
+fn round_trip<T: Serialize + DeserializeOwned>(value: &T) -> T {
+    let encoded = serde_bencode::to_bytes(value).expect("value is serializable");
+    serde_bencode::from_bytes(&encoded).expect("encoded value is readable")
+}
+
+fn string(rng: &mut Rng) -> String {
+    const ALPHABET: &[char] = &['a', 'Z', '0', ' ', '.', '/', '-', 'é', 'ж', '日', '🦀'];
+    (0..rng.usize(..16)).map(|_| ALPHABET[rng.usize(..ALPHABET.len())]).collect()
+}
+
+fn strings(rng: &mut Rng, max: usize) -> Vec<String> {
+    (0..rng.usize(..max)).map(|_| string(rng)).collect()
+}
+
+fn option<T>(rng: &mut Rng, generate: impl FnOnce(&mut Rng) -> T) -> Option<T> {
+    rng.bool().then(|| generate(rng))
+}
+
+fn peers_v4(rng: &mut Rng) -> Peers {
+    Peers::from((0..rng.usize(..32)).map(|_| SocketAddr::new(Ipv4Addr::from(rng.u32(..)).into(), rng.u16(..))).collect::<Vec<_>>())
+}
+
+fn peers_v6(rng: &mut Rng) -> Peers {
+    Peers::from((0..rng.usize(..32)).map(|_| SocketAddr::new(Ipv6Addr::from(rng.u128(..)).into(), rng.u16(..))).collect::<Vec<_>>())
+}
+
+fn piece_hashes(rng: &mut Rng) -> PieceHashes {
+    PieceHashes((0..rng.usize(..64)).map(|_| {
+        let mut hash = [0; 20];
+        rng.fill(&mut hash);
+        hash
+    }).collect())
+}
+
+fn tracker_response(rng: &mut Rng) -> TrackerResponse {
+    TrackerResponse {
+        failure_reason: option(rng, string),
+        warning_message: option(rng, string),
+        interval: rng.usize(..100_000),
+        min_interval: option(rng, |rng| rng.usize(..100_000)),
+        tracker_id: option(rng, string),
+        complete: option(rng, |rng| rng.usize(..100_000)),
+        incomplete: option(rng, |rng| rng.usize(..100_000)),
+        peers: peers_v4(rng),
+        peers6: peers_v6(rng),
+    }
+}
+
+fn torrent(rng: &mut Rng) -> Torrent {
+    let keys = if rng.bool() {
+        Keys::SingleFile { length: rng.usize(..1 << 40) }
+    } else {
+        let files = (0..rng.usize(1..8))
+            .map(|_| File { length: rng.usize(..1 << 32), path: strings(rng, 4) })
+            .collect();
+        Keys::MultiFile { files }
+    };
+
+    Torrent {
+        announce: string(rng),
+        announce_list: option(rng, |rng| (0..rng.usize(..4)).map(|_| strings(rng, 4)).collect()),
+        creation_date: option(rng, |rng| rng.i64(..)),
+        created_by: option(rng, string),
+        comment: option(rng, string),
+        url_list: option(rng, |rng| if rng.bool() { UrlList::One(string(rng)) } else { UrlList::Many(strings(rng, 4)) }),
+        info: Info {
+            name: string(rng),
+            piece_length: 1 << rng.u32(14..25),
+            pieces: piece_hashes(rng),
+            private: option(rng, |rng| rng.u8(..2)),
+            keys,
+        },
+    }
+}
+
+#[test]
+fn compact_peers_are_six_bytes_each() {
+    let peers = Peers::from(vec!["127.0.0.1:6881".parse().unwrap(), "10.0.0.2:80".parse().unwrap()]);
+
+    let encoded = serde_bencode::to_bytes(&peers).unwrap();
+    assert_eq!(encoded, b"12:\x7f\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x00\x50");
+}
+
+#[test]
+fn ipv6_peers_are_not_written_into_compact_ipv4_list() {
+    let peers = Peers::from(vec!["[::1]:6881".parse().unwrap()]);
+
+    assert!(serde_bencode::to_bytes(&peers).is_err());
+}
+
+#[test]
+fn split_peers_by_address_family() {
+    let addresses = ["127.0.0.1:1", "[::1]:2", "10.0.0.1:3"].map(|address| address.parse::<SocketAddr>().unwrap());
+
+    let (v4, v6) = Peers::split(addresses);
+    assert_eq!(v4.addresses, vec![addresses[0], addresses[2]]);
+    assert_eq!(v6.addresses, vec![addresses[1]]);
+}
+
+#[test]
+fn peers_round_trip() {
+    for seed in 0..CASES {
+        let peers = peers_v4(&mut Rng::with_seed(seed));
+        assert_eq!(round_trip(&peers), peers, "seed {seed}");
+    }
+}
+
+#[test]
+fn piece_hashes_round_trip() {
+    for seed in 0..CASES {
+        let hashes = piece_hashes(&mut Rng::with_seed(seed));
+        assert_eq!(round_trip(&hashes), hashes, "seed {seed}");
+    }
+}
+
+#[test]
+fn tracker_response_round_trip() {
+    for seed in 0..CASES {
+        let response = tracker_response(&mut Rng::with_seed(seed));
+        assert_eq!(round_trip(&response), response, "seed {seed}");
+    }
+}
+
+#[test]
+fn torrent_round_trip() {
+    for seed in 0..CASES {
+        let torrent = torrent(&mut Rng::with_seed(seed));
+        let decoded = round_trip(&torrent);
+
+        assert_eq!(decoded, torrent, "seed {seed}");
+        assert_eq!(decoded.info_hash().unwrap(), torrent.info_hash().unwrap(), "seed {seed}");
+    }
+}
@@ -0,0 +1,270 @@
+use std::{collections::BTreeSet, net::SocketAddr, path::PathBuf};
+use bittorrent::{
+    bitfield::Bitfield,
+    event::EventKind,
+    handshake::Handshake,
+    peer_connection::PeerConnection,
+    peer_id::{default_prefix, PeerId},
+    picker::PiecePicker,
+    session::{AddTorrentOptions, Session, SessionOptions, TorrentState},
+    storage::MemoryStorage,
+    torrent::Torrent,
+    transfer::Transfer,
+};
+use tempfile::TempDir;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}};
+
+mod common;
+
+
+/// Directory holding a single file of `length` pseudo-random bytes and its torrent
+fn content(name: &str, length: usize) -> (TempDir, Torrent, Vec<u8>) {
+    let directory = tempfile::tempdir().unwrap();
+    let data = common::write_random(&directory.path().join(name), length);
+    let torrent = common::torrent_of(&directory.path().join(name), 1 << 14);
+
+    (directory, torrent, data)
+}
+
+/// Connects like another client would, with a peer id of its own rather than the session's
+async fn connect(torrent: &Torrent, address: SocketAddr) -> PeerConnection<'_> {
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let handshake = Handshake::new(torrent, PeerId::generate()).unwrap();
+    let reply = handshake.establish(&mut stream).await.unwrap();
+    PeerConnection::accept(torrent, stream, PeerId(reply.peer_id))
+}
+
+fn options(directory: PathBuf) -> SessionOptions {
+    SessionOptions {
+        listen: SocketAddr::from(([127, 0, 0, 1], 0)),
+        download_directory: directory,
+        lsd: None,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn complete_torrent_seeds_to_incoming_peers() {
+    let (directory, torrent, data) = content("seed.bin", 100_000);
+
+    let session = Session::new(options(directory.path().to_path_buf())).await.unwrap();
+    let mut events = session.subscribe();
+    let handle = session.add(torrent.clone(), AddTorrentOptions::default()).unwrap();
+    handle.finished().await.unwrap();
+
+    let status = handle.status();
+    assert_eq!(status.state, TorrentState::Seeding);
+    assert_eq!((status.done, status.wanted), (data.len(), data.len()));
+    assert_eq!(status.eta, Some(std::time::Duration::ZERO));
+
+    let pieces = torrent.info.pieces.0.len();
+    let picker = PiecePicker::new((0..pieces).collect::<BTreeSet<_>>(), Bitfield::new(pieces));
+    let storage = MemoryStorage::new(&torrent);
+    let transfer = Transfer::new(data.len());
+
+    let mut peer = connect(&torrent, session.listen_address()).await;
+    peer.recv_bitfield().await.unwrap();
+    peer.request_unchoke().await.unwrap();
+    peer.download(&storage, &picker, &transfer).await.unwrap();
+
+    assert!(picker.is_complete());
+    assert_eq!(transfer.left(), 0);
+    assert_eq!(storage.into_bytes(), data);
+
+    let mut kinds = Vec::new();
+    while let Ok(event) = events.try_recv() {
+        assert_eq!(event.info_hash, handle.info_hash());
+        kinds.push(event.kind);
+    }
+
+    assert_eq!(kinds[..4], [
+        EventKind::StateChanged(TorrentState::Checking),
+        EventKind::Checked { verified: pieces, wanted: pieces },
+        EventKind::Finished,
+        EventKind::StateChanged(TorrentState::Seeding),
+    ]);
+    assert!(kinds.iter().any(|kind| matches!(kind, EventKind::PeerConnected { client: Some(client), .. } if client.name == "bittorrent")));
+    assert_eq!(handle.status().peers, 1);
+
+    session.shutdown().await;
+}
+
+#[tokio::test]
+async fn torrents_are_added_once_and_can_be_paused() {
+    let (directory, torrent, _) = content("pause.bin", 20_000);
+
+    let session = Session::new(options(directory.path().to_path_buf())).await.unwrap();
+    let handle = session.add(torrent.clone(), AddTorrentOptions { paused: true, ..Default::default() }).unwrap();
+    assert_eq!(handle.state(), TorrentState::Paused);
+    assert!(session.add(torrent, AddTorrentOptions::default()).is_err());
+
+    handle.resume();
+    handle.finished().await.unwrap();
+    assert_eq!(handle.state(), TorrentState::Seeding);
+
+    assert!(session.remove(&handle.info_hash()));
+    assert!(session.torrents().is_empty());
+
+    session.shutdown().await;
+}
+
+#[tokio::test]
+async fn connections_to_ourselves_are_dropped() {
+    let (directory, torrent, _) = content("self.bin", 40_000);
+
+    let session = Session::new(options(directory.path().join("downloads"))).await.unwrap();
+    let mut events = session.subscribe();
+    let handle = session.add(torrent, AddTorrentOptions::default()).unwrap();
+    assert_eq!(handle.add_peers([session.listen_address()]), 1);
+    assert_eq!(handle.add_peers([session.listen_address()]), 0);
+
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    while let Ok(event) = events.try_recv() {
+        assert!(!matches!(event.kind, EventKind::PeerConnected { .. }), "{:?}", event.kind);
+    }
+    assert_eq!((handle.status().peers, handle.status().known_peers), (0, 1));
+
+    session.shutdown().await;
+}
+
+#[tokio::test]
+async fn misbehaving_peers_are_banned() {
+    let (directory, torrent, _) = content("ban.bin", 40_000);
+
+    // Unchokes, then sends a block nobody asked for
+    let address = common::fake_peer(&torrent, |mut stream| async move {
+        stream.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+        stream.write_all(&[0, 0, 0, 13, 7, 0, 0, 0, 2, 0, 0, 0, 1, 1, 2, 3, 4]).await.unwrap();
+
+        let mut rest = Vec::new();
+        let _ = stream.read_to_end(&mut rest).await;
+    })
+    .await;
+
+    let session = Session::new(options(directory.path().join("downloads"))).await.unwrap();
+    let mut events = session.subscribe();
+    let handle = session.add(torrent, AddTorrentOptions::default()).unwrap();
+    handle.add_peers([address]);
+
+    let reason = tokio::time::timeout(std::time::Duration::from_secs(10), async {
+        loop {
+            if let EventKind::PeerBanned { address: banned, reason } = events.recv().await.unwrap().kind {
+                assert_eq!(banned, address);
+                break reason;
+            }
+        }
+    })
+    .await
+    .unwrap();
+
+    assert!(reason.contains("not requested"), "{reason}");
+    assert_eq!(handle.add_peers([SocketAddr::from(([127, 0, 0, 1], 1))]), 0);
+
+    session.shutdown().await;
+}
+
+#[tokio::test]
+async fn connections_from_banned_ips_are_dropped() {
+    let (directory, torrent, _) = content("drop.bin", 40_000);
+
+    let address = common::fake_peer(&torrent, |mut stream| async move {
+        stream.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+        stream.write_all(&[0, 0, 0, 13, 7, 0, 0, 0, 2, 0, 0, 0, 1, 1, 2, 3, 4]).await.unwrap();
+
+        let mut rest = Vec::new();
+        let _ = stream.read_to_end(&mut rest).await;
+    })
+    .await;
+
+    let session = Session::new(options(directory.path().join("downloads"))).await.unwrap();
+    let mut events = session.subscribe();
+    let handle = session.add(torrent.clone(), AddTorrentOptions::default()).unwrap();
+
+    // Another connection from the same IP, idle until it is dropped
+    while handle.state() != TorrentState::Downloading {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    let _peer = connect(&torrent, session.listen_address()).await;
+    while handle.status().peers == 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    handle.add_peers([address]);
+    tokio::time::timeout(std::time::Duration::from_secs(10), async {
+        while !matches!(events.recv().await.unwrap().kind, EventKind::PeerBanned { .. }) {}
+        while handle.status().peers > 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    session.shutdown().await;
+}
+
+#[tokio::test]
+async fn every_session_has_its_own_peer_id() {
+    let first = Session::new(options(PathBuf::from("unused"))).await.unwrap();
+    let second = Session::new(SessionOptions { peer_id_prefix: Some(String::from("-XX0100-")), ..options(PathBuf::from("unused")) })
+        .await
+        .unwrap();
+
+    assert_ne!(first.peer_id(), second.peer_id());
+    assert!(first.peer_id().0.starts_with(default_prefix().as_bytes()));
+    assert!(second.peer_id().0.starts_with(b"-XX0100-"));
+}
+
+/// HTTP tracker taking a second to answer every announce
+async fn slow_tracker() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/announce", listener.local_addr().unwrap());
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buffer = [0; 1024];
+                let _ = stream.read(&mut buffer).await;
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+
+                let body = b"d8:intervali900e5:peers0:e";
+                let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(body).await;
+            });
+        }
+    });
+
+    url
+}
+
+#[tokio::test]
+async fn resumed_torrents_wait_for_the_previous_run_to_stop() {
+    let (directory, mut torrent, _) = content("restart.bin", 40_000);
+    let tracker = slow_tracker().await;
+    torrent.announce_list = Some(vec![vec![tracker.clone()]]);
+    torrent.announce = tracker;
+
+    let session = Session::new(options(directory.path().to_path_buf())).await.unwrap();
+    let handle = session.add(torrent.clone(), AddTorrentOptions::default()).unwrap();
+    handle.finished().await.unwrap();
+
+    // The first run is still announcing when it's told to stop, the second one waits for it
+    let mut events = session.subscribe();
+    handle.pause();
+    handle.resume();
+    tokio::time::timeout(std::time::Duration::from_secs(10), async {
+        while events.recv().await.unwrap().kind != EventKind::StateChanged(TorrentState::Seeding) {}
+    })
+    .await
+    .unwrap();
+
+    // Long enough for a stale end of the first run to spoil the second one
+    tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+    assert_eq!(handle.state(), TorrentState::Seeding);
+
+    let mut peer = connect(&torrent, session.listen_address()).await;
+    peer.recv_bitfield().await.unwrap();
+
+    session.shutdown().await;
+}
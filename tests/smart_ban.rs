@@ -0,0 +1,180 @@
+use std::{collections::BTreeSet, net::SocketAddr, sync::Arc};
+use bittorrent::{
+    bitfield::Bitfield,
+    peer_connection::{Misbehavior, PeerConnection},
+    peer_id::PeerId,
+    picker::PiecePicker,
+    smart_ban::{block_digest, SmartBan},
+    storage::MemoryStorage,
+    transfer::Transfer,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+mod common;
+
+
+fn peer(host: u8) -> SocketAddr {
+    SocketAddr::from(([10, 0, 0, host], 6881))
+}
+
+#[test]
+fn senders_of_blocks_that_differ_from_the_good_piece_are_convicted() {
+    let smart_ban = SmartBan::new();
+    let good = [(0, block_digest(b"first")), (5, block_digest(b"second"))];
+
+    // Piece 3 came from two peers, the second one sent a corrupt block
+    smart_ban.piece_failed(3, vec![(0, peer(1), good[0].1), (5, peer(2), block_digest(b"garbage"))]);
+    assert_eq!((smart_ban.strikes(peer(1).ip()), smart_ban.strikes(peer(2).ip())), (1, 1));
+    assert_eq!(smart_ban.convicted(peer(2).ip()), None);
+
+    smart_ban.piece_passed(3, &good);
+    assert_eq!(smart_ban.strikes(peer(1).ip()), 0);
+    assert_eq!(smart_ban.convicted(peer(1).ip()), None);
+    assert_eq!(smart_ban.take_convicted(), [(peer(2), String::from("sent corrupt data for piece 3"))]);
+    assert!(smart_ban.take_convicted().is_empty());
+
+    // Passing again has nothing to compare against
+    smart_ban.piece_passed(3, &good);
+    assert!(smart_ban.take_convicted().is_empty());
+}
+
+#[test]
+fn repeat_offenders_are_convicted_without_a_good_piece() {
+    let smart_ban = SmartBan::new();
+    for index in 0..3 {
+        assert_eq!(smart_ban.convicted(peer(1).ip()), None);
+        smart_ban.piece_failed(index, vec![(0, peer(1), block_digest(b"garbage"))]);
+    }
+
+    let reason = smart_ban.convicted(peer(1).ip()).unwrap();
+    assert_eq!(reason, "sent 3 pieces that failed the hash check");
+    assert_eq!(smart_ban.take_convicted(), [(peer(1), reason)]);
+}
+
+#[test]
+fn blocks_of_the_oldest_failed_pieces_are_forgotten() {
+    let smart_ban = SmartBan::new();
+    for index in 0..65 {
+        smart_ban.piece_failed(index, vec![(0, peer(index as u8 + 1), block_digest(b"garbage"))]);
+    }
+
+    smart_ban.piece_passed(0, &[(0, block_digest(b"good"))]);
+    assert!(smart_ban.take_convicted().is_empty());
+    smart_ban.piece_passed(64, &[(0, block_digest(b"good"))]);
+    assert_eq!(smart_ban.take_convicted(), [(peer(65), String::from("sent corrupt data for piece 64"))]);
+}
+
+#[test]
+fn failed_pieces_are_retried_by_another_peer_first() {
+    let (first, second) = (peer(1).ip(), peer(2).ip());
+    let all = Bitfield(vec![0xe0]);
+    let picker = PiecePicker::new(BTreeSet::from([0, 1, 2]), Bitfield::new(3));
+
+    assert_eq!(picker.pick(first, &all), Some(0));
+    picker.failed(0, first);
+    assert_eq!(picker.pick(first, &all), Some(1));
+    assert_eq!(picker.pick(second, &all), Some(0));
+
+    // Back to the peer that failed it once there is nothing else left
+    picker.release(0);
+    assert_eq!(picker.pick(first, &all), Some(2));
+    assert_eq!(picker.pick(first, &all), Some(0));
+    assert_eq!(picker.pick(second, &all), None);
+}
+
+#[tokio::test]
+async fn downloads_from_peers_sending_corrupt_blocks_fail() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("corrupt.bin");
+    common::write_random(&path, 5 * (1 << 14));
+    let torrent = common::torrent_of(&path, 1 << 14);
+    let pieces = torrent.info.pieces.0.len();
+
+    // Unchokes, then answers each request with zeros
+    let address = common::fake_peer(&torrent, |mut stream| async move {
+        stream.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+
+        loop {
+            let mut length = [0; 4];
+            if stream.read_exact(&mut length).await.is_err() {
+                break;
+            }
+            let mut message = vec![0; u32::from_be_bytes(length) as usize];
+            stream.read_exact(&mut message).await.unwrap();
+            if message.first() != Some(&6) {
+                continue;
+            }
+
+            let block_length = u32::from_be_bytes(message[9..13].try_into().unwrap());
+            let mut piece = (9 + block_length).to_be_bytes().to_vec();
+            piece.push(7);
+            piece.extend(&message[1..9]);
+            piece.extend(vec![0; block_length as usize]);
+            if stream.write_all(&piece).await.is_err() {
+                break;
+            }
+        }
+    })
+    .await;
+    let smart_ban = Arc::new(SmartBan::new());
+    let picker = PiecePicker::new((0..pieces).collect::<BTreeSet<_>>(), Bitfield::new(pieces));
+    let storage = MemoryStorage::new(&torrent);
+    let transfer = Transfer::new(5 * (1 << 14));
+
+    let mut peer = PeerConnection::new(&torrent, &address, PeerId::generate()).await.unwrap().with_smart_ban(smart_ban.clone());
+    peer.recv_bitfield().await.unwrap();
+    peer.request_unchoke().await.unwrap();
+
+    let error = peer.download(&storage, &picker, &transfer).await.unwrap_err();
+    assert!(error.downcast_ref::<Misbehavior>().is_some(), "{error:#}");
+    assert_eq!(smart_ban.strikes(address.ip()), 3);
+    assert_eq!(smart_ban.take_convicted().len(), 1);
+    assert!(!picker.is_complete());
+}
+
+#[tokio::test]
+async fn blocks_longer_than_requested_are_misbehavior() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("oversized.bin");
+    common::write_random(&path, 2 * (1 << 14));
+    let torrent = common::torrent_of(&path, 1 << 14);
+    let pieces = torrent.info.pieces.0.len();
+
+    // Unchokes, then answers the first request with more bytes than asked for
+    let address = common::fake_peer(&torrent, |mut stream| async move {
+        stream.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+
+        loop {
+            let mut length = [0; 4];
+            stream.read_exact(&mut length).await.unwrap();
+            let mut message = vec![0; u32::from_be_bytes(length) as usize];
+            stream.read_exact(&mut message).await.unwrap();
+            if message.first() == Some(&6) {
+                let block_length = u32::from_be_bytes(message[9..13].try_into().unwrap()) + 16;
+                let mut piece = (9 + block_length).to_be_bytes().to_vec();
+                piece.push(7);
+                piece.extend(&message[1..9]);
+                piece.extend(vec![0; block_length as usize]);
+                let _ = stream.write_all(&piece).await;
+                break;
+            }
+        }
+
+        let mut rest = Vec::new();
+        let _ = stream.read_to_end(&mut rest).await;
+    })
+    .await;
+
+    let picker = PiecePicker::new((0..pieces).collect::<BTreeSet<_>>(), Bitfield::new(pieces));
+    let storage = MemoryStorage::new(&torrent);
+    let transfer = Transfer::new(2 * (1 << 14));
+
+    let mut peer = PeerConnection::new(&torrent, &address, PeerId::generate()).await.unwrap();
+    peer.recv_bitfield().await.unwrap();
+    peer.request_unchoke().await.unwrap();
+
+    let error = peer.download(&storage, &picker, &transfer).await.unwrap_err();
+    assert!(error.downcast_ref::<Misbehavior>().is_some(), "{error:#}");
+    assert!(error.to_string().contains("requested"), "{error:#}");
+    assert_eq!(storage.into_bytes(), vec![0; 2 * (1 << 14)]);
+}
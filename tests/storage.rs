@@ -0,0 +1,84 @@
+use std::collections::BTreeSet;
+use bittorrent::{
+    files::FileSelection,
+    storage::{missing_pieces, DiskStorage, MemoryStorage, Storage},
+    torrent::Torrent,
+};
+
+mod common;
+
+
+const PIECE_LENGTH: usize = 1 << 14;
+
+/// Torrent of two and a half pieces with distinct bytes, and that content
+fn torrent() -> (Torrent, Vec<u8>) {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("memory.bin");
+    let data = (0..PIECE_LENGTH * 5 / 2).map(|offset| (offset % 253) as u8).collect::<Vec<_>>();
+    std::fs::write(&path, &data).unwrap();
+
+    let torrent = common::torrent_of(&path, PIECE_LENGTH);
+
+    (torrent, data)
+}
+
+#[test]
+fn blocks_read_back_as_written() {
+    let (torrent, data) = torrent();
+    let storage = MemoryStorage::new(&torrent);
+    assert_eq!(storage.layout().total_length, data.len());
+    assert_eq!(storage.layout().piece_size(2), PIECE_LENGTH / 2);
+
+    storage.write_block(1, 100, &data[PIECE_LENGTH + 100..PIECE_LENGTH + 200]).unwrap();
+    assert_eq!(storage.read_block(1, 100, 100).unwrap(), &data[PIECE_LENGTH + 100..PIECE_LENGTH + 200]);
+    assert_eq!(storage.read_block(1, 0, 100).unwrap(), vec![0; 100], "untouched bytes are zero");
+
+    // The last, shorter piece ends exactly at the end of the content
+    storage.write_block(2, 0, &data[2 * PIECE_LENGTH..]).unwrap();
+    assert_eq!(storage.read_block(2, 0, PIECE_LENGTH / 2).unwrap(), &data[2 * PIECE_LENGTH..]);
+    storage.flush().unwrap();
+}
+
+#[test]
+fn blocks_beyond_the_content_are_refused() {
+    let (torrent, _) = torrent();
+    let storage = MemoryStorage::new(&torrent);
+
+    assert!(storage.read_block(2, PIECE_LENGTH / 2 - 10, 11).is_err());
+    assert!(storage.write_block(2, PIECE_LENGTH / 2, &[1]).is_err());
+    assert!(storage.write_block(3, 0, &[1]).is_err());
+}
+
+#[test]
+fn blocks_never_spill_into_the_next_piece() {
+    let (torrent, _) = torrent();
+    let directory = tempfile::tempdir().unwrap();
+    let memory = MemoryStorage::new(&torrent);
+    let disk = DiskStorage::create(&torrent, directory.path(), &FileSelection::default()).unwrap();
+
+    for storage in [&memory as &dyn Storage, &disk] {
+        assert!(storage.write_block(0, PIECE_LENGTH - 10, &[1; 11]).is_err());
+        assert!(storage.write_block(0, 0, &[1; PIECE_LENGTH + 1]).is_err());
+        assert!(storage.read_block(1, 1, PIECE_LENGTH).is_err());
+        storage.write_block(0, PIECE_LENGTH - 10, &[1; 10]).unwrap();
+    }
+}
+
+#[test]
+fn only_pieces_matching_their_hash_are_restored() {
+    let (torrent, data) = torrent();
+    let storage = MemoryStorage::new(&torrent);
+
+    storage.write_block(0, 0, &data[..PIECE_LENGTH]).unwrap();
+    storage.write_block(2, 0, &data[2 * PIECE_LENGTH..]).unwrap();
+    storage.write_block(1, 0, &data[PIECE_LENGTH..2 * PIECE_LENGTH - 1]).unwrap();
+
+    assert!(storage.verify_piece(0).unwrap());
+    assert!(!storage.verify_piece(1).unwrap());
+    assert!(storage.verify_piece(2).unwrap());
+    assert_eq!(missing_pieces(&storage, &BTreeSet::from([0, 1, 2])).unwrap(), BTreeSet::from([1]));
+    assert_eq!(missing_pieces(&storage, &BTreeSet::from([2])).unwrap(), BTreeSet::new());
+
+    storage.write_block(1, PIECE_LENGTH - 1, &data[2 * PIECE_LENGTH - 1..2 * PIECE_LENGTH]).unwrap();
+    assert_eq!(storage.into_bytes(), data);
+}
@@ -0,0 +1,116 @@
+use std::{net::SocketAddr, time::Duration};
+use bittorrent::{peer_id::PeerId, swarm::{PeerSource, Swarm}};
+
+
+fn address(port: u16) -> SocketAddr {
+    SocketAddr::from(([10, 0, 0, 1], port))
+}
+
+#[tokio::test(start_paused = true)]
+async fn addresses_are_deduplicated_and_preferred_sources_dialled_first() {
+    let mut swarm = Swarm::new(PeerId::generate());
+    assert!(swarm.add(address(1), PeerSource::Tracker, None));
+    assert!(swarm.add(address(2), PeerSource::Tracker, None));
+    assert!(!swarm.add(address(1), PeerSource::Tracker, None));
+    assert!(swarm.add(address(3), PeerSource::Manual, None));
+    assert_eq!(swarm.len(), 3);
+
+    assert_eq!(swarm.dial(), Some(address(3)));
+    assert_eq!(swarm.connections(), 1);
+
+    // Tracker addresses are told apart by the time they were added only, either comes next
+    let next = swarm.dial().unwrap();
+    assert!(next == address(1) || next == address(2));
+    swarm.dial().unwrap();
+    assert_eq!(swarm.dial(), None);
+    assert_eq!(swarm.connections(), 3);
+}
+
+#[tokio::test(start_paused = true)]
+async fn failed_addresses_back_off_until_given_up() {
+    let mut swarm = Swarm::new(PeerId::generate());
+    swarm.add(address(1), PeerSource::Tracker, None);
+
+    let mut backoff = Duration::from_secs(15);
+    for _ in 0..4 {
+        assert_eq!(swarm.dial(), Some(address(1)));
+        swarm.dialled(address(1), true);
+        assert_eq!(swarm.dial(), None);
+
+        tokio::time::advance(backoff - Duration::from_secs(1)).await;
+        assert_eq!(swarm.dial(), None);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        backoff *= 2;
+    }
+
+    // Success resets the backoff
+    assert_eq!(swarm.dial(), Some(address(1)));
+    swarm.dialled(address(1), false);
+    tokio::time::advance(Duration::from_secs(15)).await;
+    assert_eq!(swarm.dial(), Some(address(1)));
+
+    for _ in 0..8 {
+        swarm.dialled(address(1), true);
+        tokio::time::advance(Duration::from_secs(60 * 60)).await;
+        swarm.dial();
+    }
+    assert!(swarm.is_empty());
+
+    // Trackers returning it again don't start over, adding it by hand does
+    assert!(!swarm.add(address(1), PeerSource::Tracker, None));
+    assert_eq!(swarm.dial(), None);
+    assert!(swarm.add(address(1), PeerSource::Manual, None));
+    assert_eq!(swarm.dial(), Some(address(1)));
+}
+
+#[tokio::test(start_paused = true)]
+async fn connections_are_deduplicated_by_peer_id() {
+    let own = PeerId::generate();
+    let peer = PeerId::generate();
+    let mut swarm = Swarm::new(own);
+
+    swarm.connected(address(1), peer).unwrap();
+    assert!(swarm.connected(address(2), peer).is_err());
+    swarm.disconnected(address(1));
+    swarm.connected(address(2), peer).unwrap();
+
+    // Our own address is never dialled again
+    swarm.add(address(3), PeerSource::Tracker, None);
+    assert_eq!(swarm.dial(), Some(address(3)));
+    assert!(swarm.connected(address(3), own).is_err());
+    swarm.dialled(address(3), true);
+    tokio::time::advance(Duration::from_secs(60 * 60)).await;
+    assert_eq!(swarm.dial(), None);
+}
+
+#[tokio::test(start_paused = true)]
+async fn addresses_of_known_peer_ids_are_not_dialled() {
+    let own = PeerId::generate();
+    let peer = PeerId::generate();
+    let mut swarm = Swarm::new(own);
+    swarm.connected(address(1), peer).unwrap();
+
+    swarm.add(address(2), PeerSource::Tracker, Some(peer));
+    swarm.add(address(3), PeerSource::Tracker, Some(own));
+    assert_eq!(swarm.dial(), None);
+    assert_eq!(swarm.next_dial(), None);
+
+    swarm.disconnected(address(1));
+    assert_eq!(swarm.dial(), Some(address(2)));
+    assert_eq!(swarm.dial(), None);
+}
+
+#[tokio::test(start_paused = true)]
+async fn banned_ips_are_refused_on_every_port() {
+    let mut swarm = Swarm::new(PeerId::generate());
+    swarm.add(address(1), PeerSource::Tracker, None);
+
+    assert!(swarm.ban(address(1).ip(), String::from("sent garbage")));
+    assert!(!swarm.ban(address(1).ip(), String::from("sent garbage")));
+    assert_eq!(swarm.banned(), 1);
+
+    assert_eq!(swarm.dial(), None);
+    assert_eq!(swarm.next_dial(), None);
+    assert!(!swarm.add(address(2), PeerSource::Manual, None));
+    assert!(swarm.connected(address(2), PeerId::generate()).is_err());
+}
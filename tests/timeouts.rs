@@ -0,0 +1,115 @@
+use std::{collections::BTreeSet, time::Duration};
+use bittorrent::{
+    bitfield::Bitfield,
+    peer_connection::{Misbehavior, PeerConnection, PeerTimeouts},
+    peer_id::PeerId,
+    picker::PiecePicker,
+    storage::MemoryStorage,
+    torrent::Torrent,
+    transfer::Transfer,
+};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+mod common;
+
+
+fn torrent() -> Torrent {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("timeouts.bin");
+    std::fs::write(&path, vec![1; 40_000]).unwrap();
+
+    common::torrent_of(&path, 1 << 14)
+}
+
+fn timeouts() -> PeerTimeouts {
+    PeerTimeouts {
+        handshake: Duration::from_millis(200),
+        request: Duration::from_millis(300),
+        idle: Duration::from_millis(600),
+        keep_alive: Duration::from_millis(100),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn handshake_times_out() {
+    let torrent = torrent();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let _silent = tokio::spawn(async move {
+        let (_stream, _) = listener.accept().await.unwrap();
+        tokio::time::sleep(Duration::from_secs(10)).await;
+    });
+
+    let error = PeerConnection::connect(&torrent, &address, PeerId::generate(), timeouts()).await.err().unwrap();
+    assert!(error.to_string().contains("handshake"), "{error:#}");
+}
+
+#[tokio::test]
+async fn keep_alives_are_sent_and_idle_peers_dropped() {
+    let torrent = torrent();
+    let (sender, received) = tokio::sync::oneshot::channel();
+    let address = common::fake_peer(&torrent, |mut stream| async move {
+        // Interested message, then nothing but keep-alives until the connection is dropped
+        let mut bytes = Vec::new();
+        let _ = stream.read_to_end(&mut bytes).await;
+        let _ = sender.send(bytes);
+    }).await;
+
+    let mut peer = PeerConnection::connect(&torrent, &address, PeerId::generate(), timeouts()).await.unwrap();
+    peer.recv_bitfield().await.unwrap();
+
+    let error = peer.request_unchoke().await.unwrap_err();
+    assert!(error.to_string().contains("sent nothing"), "{error:#}");
+    drop(peer);
+
+    let bytes = received.await.unwrap();
+    assert_eq!(bytes[..5], [0, 0, 0, 1, 2]);
+    assert!(bytes.len() >= 9 && bytes[5..].iter().all(|&byte| byte == 0), "{bytes:?}");
+}
+
+#[tokio::test]
+async fn snubbing_peers_are_dropped_and_pieces_released() {
+    let torrent = torrent();
+    let address = common::fake_peer(&torrent, |mut stream| async move {
+        // Unchoke, then swallow requests without answering
+        stream.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+        let mut bytes = Vec::new();
+        let _ = stream.read_to_end(&mut bytes).await;
+    }).await;
+
+    let pieces = torrent.info.pieces.0.len();
+    let picker = PiecePicker::new((0..pieces).collect::<BTreeSet<_>>(), Bitfield::new(pieces));
+    let storage = MemoryStorage::new(&torrent);
+    let transfer = Transfer::new(40_000);
+
+    let mut peer = PeerConnection::connect(&torrent, &address, PeerId::generate(), timeouts()).await.unwrap();
+    peer.recv_bitfield().await.unwrap();
+    peer.request_unchoke().await.unwrap();
+
+    let error = peer.download(&storage, &picker, &transfer).await.unwrap_err();
+    assert!(error.to_string().contains("snubbing"), "{error:#}");
+    drop(peer);
+
+    assert_eq!(picker.pick(address.ip(), &Bitfield(vec![0xff])), Some(0));
+}
+
+#[tokio::test]
+async fn unexpected_messages_are_errors() {
+    let torrent = torrent();
+    let address = common::fake_peer(&torrent, |mut stream| async move {
+        // A `have` where an unchoke is expected, then a short piece
+        stream.write_all(&[0, 0, 0, 5, 4, 0, 0, 0, 1]).await.unwrap();
+        stream.write_all(&[0, 0, 0, 3, 7, 0, 0]).await.unwrap();
+        let mut bytes = Vec::new();
+        let _ = stream.read_to_end(&mut bytes).await;
+    }).await;
+
+    let mut peer = PeerConnection::connect(&torrent, &address, PeerId::generate(), timeouts()).await.unwrap();
+    peer.recv_bitfield().await.unwrap();
+
+    let error = peer.recv_unchoke().await.unwrap_err();
+    assert!(error.to_string().contains("expected unchoke"), "{error:#}");
+    let error = peer.recv_piece().await.unwrap_err();
+    assert!(error.downcast_ref::<Misbehavior>().is_some(), "{error:#}");
+}
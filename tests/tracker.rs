@@ -0,0 +1,391 @@
+use std::{collections::HashMap, net::Ipv6Addr, sync::{Arc, Mutex}, time::Duration};
+use bittorrent::{
+    peer_id::PeerId,
+    torrent::{Info, Keys, PieceHashes, Torrent},
+    tracker::{
+        scrape::{scrape, scrape_url, ScrapeStats},
+        server::{ServerOptions, TrackerServer},
+        session::TrackerSession,
+        Event, TrackerClient, TrackerError, TrackerRequest, TrackerResponse,
+    },
+    transfer::Transfer,
+};
+use serde_bytes::ByteBuf;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, UdpSocket}, sync::mpsc};
+
+
+/// HTTP tracker answering requests with `bodies` in turn. Returns its announce URL
+/// and the request targets it received
+async fn fake_tracker(bodies: Vec<&'static [u8]>) -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/announce", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+
+    let received = requests.clone();
+    tokio::spawn(async move {
+        for body in bodies {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                stream.read_exact(&mut byte).await.unwrap();
+                head.push(byte[0]);
+            }
+
+            let target = String::from_utf8_lossy(&head).split(' ').nth(1).unwrap().to_string();
+            received.lock().unwrap().push(target);
+
+            let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+            stream.write_all(response.as_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+        }
+    });
+
+    (url, requests)
+}
+
+fn torrent(announce: &str) -> Torrent {
+    Torrent {
+        announce: announce.to_string(),
+        announce_list: None,
+        creation_date: None,
+        created_by: None,
+        comment: None,
+        url_list: None,
+        info: Info {
+            name: String::from("tracked.bin"),
+            piece_length: 1 << 14,
+            pieces: PieceHashes(vec![[0; 20]]),
+            private: None,
+            keys: Keys::SingleFile { length: 100 },
+        },
+    }
+}
+
+fn request() -> TrackerRequest {
+    TrackerRequest {
+        info_hash: [1; 20],
+        peer_id: String::from("-BT0100-000000000000"),
+        port: 6881,
+        uploaded: 0,
+        downloaded: 0,
+        left: 100,
+        compact: 1,
+        trackerid: None,
+        event: None,
+        numwant: None,
+        key: None,
+    }
+}
+
+#[tokio::test]
+async fn failure_reasons_become_typed_errors() {
+    let (url, _) = fake_tracker(vec![b"d14:failure reason17:torrent not founde"]).await;
+
+    let error = TrackerClient::new(url).announce(&request()).await.unwrap_err();
+    match error.downcast_ref::<TrackerError>() {
+        Some(TrackerError::Failure(reason)) => assert_eq!(reason, "torrent not found"),
+        _ => panic!("expected a tracker failure, got {error:#}"),
+    }
+}
+
+#[tokio::test]
+async fn every_response_field_is_parsed() {
+    let body = b"d8:completei3e10:incompletei4e8:intervali900e12:min intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe110:tracker id3:abc15:warning message4:slowe";
+    let (url, _) = fake_tracker(vec![body]).await;
+
+    let response = TrackerClient::new(url).announce(&request()).await.unwrap();
+    assert_eq!((response.interval, response.min_interval), (900, Some(60)));
+    assert_eq!((response.complete, response.incomplete), (Some(3), Some(4)));
+    assert_eq!(response.tracker_id.as_deref(), Some("abc"));
+    assert_eq!(response.warning_message.as_deref(), Some("slow"));
+    assert_eq!(response.addresses(), ["127.0.0.1:6881".parse().unwrap()]);
+}
+
+#[tokio::test]
+async fn tracker_id_is_sent_back_on_later_announces() {
+    let (url, requests) = fake_tracker(vec![
+        b"d8:intervali900e5:peers0:10:tracker id5:firste",
+        b"d8:intervali900e5:peers0:e",
+        b"d8:intervali900e5:peers0:e",
+    ])
+    .await;
+
+    // Through the same client, whether or not later responses repeat it
+    let torrent = torrent(&url);
+    let mut tracker = torrent.tracker_client();
+    torrent.tracker_info(&mut tracker, PeerId::generate()).await.unwrap();
+    torrent.tracker_info(&mut tracker, PeerId::generate()).await.unwrap();
+    torrent.tracker_info(&mut tracker, PeerId::generate()).await.unwrap();
+
+    let requests = requests.lock().unwrap();
+    assert!(!requests[0].contains("trackerid"));
+    assert!(requests[1].contains("trackerid=first"), "{}", requests[1]);
+    assert!(requests[2].contains("trackerid=first"), "{}", requests[2]);
+}
+
+#[tokio::test]
+async fn responses_without_an_interval_are_refused() {
+    let (url, _) = fake_tracker(vec![b"d5:peers0:e", b"d8:intervali0e5:peers0:e"]).await;
+    let mut tracker = TrackerClient::new(url);
+
+    for _ in 0..2 {
+        let error = tracker.announce(&request()).await.unwrap_err();
+        assert_eq!(error.to_string(), "tracker response has no interval");
+    }
+}
+
+fn parse(body: &[u8]) -> TrackerResponse {
+    serde_bencode::from_bytes(body).unwrap()
+}
+
+#[test]
+fn compact_peers_are_six_bytes_each() {
+    let response = parse(b"d8:intervali900e5:peers12:\x7f\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x00\x50e");
+
+    assert_eq!(response.addresses(), ["127.0.0.1:6881".parse().unwrap(), "10.0.0.2:80".parse().unwrap()]);
+    assert!(response.peers.peer_ids.is_empty());
+    assert!(serde_bencode::from_bytes::<TrackerResponse>(b"d8:intervali900e5:peers5:\x7f\x00\x00\x01\x1ae").is_err());
+}
+
+#[test]
+fn dictionary_peers_keep_their_peer_ids() {
+    let body = b"d8:intervali900e5:peersl\
+        d2:ip9:127.0.0.17:peer id20:-XX0000-aaaaaaaaaaaa4:porti6881ee\
+        d2:ip3:::14:porti6882ee\
+        d2:ip11:example.com7:peer id20:-XX0000-bbbbbbbbbbbb4:porti6883ee\
+        d2:ip8:10.0.0.27:peer id3:abc4:porti80ee\
+        ee";
+    let response = parse(body);
+
+    // Names can't be dialled without a lookup, ids of the wrong length are dropped
+    let addresses = ["127.0.0.1:6881".parse().unwrap(), "[::1]:6882".parse().unwrap(), "10.0.0.2:80".parse().unwrap()];
+    assert_eq!(response.addresses(), addresses);
+    assert_eq!(response.peer_id(&addresses[0]), Some(PeerId(*b"-XX0000-aaaaaaaaaaaa")));
+    assert_eq!(response.peer_id(&addresses[1]), None);
+    assert_eq!(response.peer_id(&addresses[2]), None);
+}
+
+#[test]
+fn peers6_are_eighteen_bytes_each() {
+    let mut body = b"d8:intervali900e5:peers0:6:peers618:".to_vec();
+    body.extend(Ipv6Addr::LOCALHOST.octets());
+    body.extend([0x1a, 0xe1, b'e']);
+    let response = parse(&body);
+
+    assert_eq!(response.addresses(), ["[::1]:6881".parse().unwrap()]);
+    assert!(serde_bencode::from_bytes::<TrackerResponse>(b"d8:intervali900e6:peers66:\0\0\0\0\0\0e").is_err());
+}
+
+/// Tracker server on a free port, returns its announce URL
+async fn tracker_server(options: ServerOptions) -> (Arc<TrackerServer>, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/announce", listener.local_addr().unwrap());
+    let server = Arc::new(TrackerServer::new(options));
+    tokio::spawn(server.clone().serve(listener));
+
+    (server, url)
+}
+
+/// Value of `key` in a request target
+fn query_value<'a>(target: &'a str, key: &str) -> Option<&'a str> {
+    let (_, query) = target.split_once('?')?;
+    query.split('&').find_map(|pair| pair.strip_prefix(key)?.strip_prefix('='))
+}
+
+#[tokio::test]
+async fn session_announces_started_reannounces_completed_and_stopped() {
+    let body: &'static [u8] = b"d8:intervali1e5:peers6:\x7f\x00\x00\x01\x1a\xe1e";
+    let (url, requests) = fake_tracker(vec![body; 4]).await;
+
+    let transfer = Arc::new(Transfer::new(100));
+    let (announces, mut results) = mpsc::unbounded_channel();
+    let handle = TrackerSession::new(&torrent(&url), PeerId::generate(), transfer.clone(), 6881).unwrap().numwant(7).spawn(announces);
+
+    assert_eq!(results.recv().await.unwrap().unwrap(), [("127.0.0.1:6881".parse().unwrap(), None)]);
+    transfer.add_downloaded(60);
+    transfer.add_uploaded(10);
+    transfer.add_verified(60);
+
+    // Reannounced once the interval from the response is over, without asking earlier
+    let started = std::time::Instant::now();
+    results.recv().await.unwrap().unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(900), "{:?}", started.elapsed());
+
+    transfer.add_verified(40);
+    handle.completed();
+    results.recv().await.unwrap().unwrap();
+    handle.stop().await.unwrap();
+
+    let requests = requests.lock().unwrap();
+    let events = requests.iter().map(|target| query_value(target, "event")).collect::<Vec<_>>();
+    assert_eq!(events, [Some("started"), None, Some("completed"), Some("stopped")]);
+
+    let counters = |target: &str| ["uploaded", "downloaded", "left"].map(|key| query_value(target, key).unwrap().to_string());
+    assert_eq!(counters(&requests[0]), ["0", "0", "100"]);
+    assert_eq!(counters(&requests[1]), ["10", "60", "40"]);
+    assert_eq!(counters(&requests[2]), ["10", "60", "0"]);
+
+    // Same key on every announce, no peers asked for when stopping
+    assert!(requests.iter().all(|target| query_value(target, "key") == query_value(&requests[0], "key")));
+    assert_eq!(query_value(&requests[0], "numwant"), Some("7"));
+    assert_eq!(query_value(&requests[3], "numwant"), None);
+}
+
+#[tokio::test(start_paused = true)]
+async fn completed_waits_until_started_gets_through() {
+    let body: &'static [u8] = b"d8:intervali900e5:peers0:e";
+    let (url, requests) = fake_tracker(vec![b"d14:failure reason4:downe", body, body, body]).await;
+
+    let transfer = Arc::new(Transfer::new(100));
+    let (announces, mut results) = mpsc::unbounded_channel();
+    let handle = TrackerSession::new(&torrent(&url), PeerId::generate(), transfer.clone(), 6881).unwrap().spawn(announces);
+
+    assert!(results.recv().await.unwrap().is_err());
+    transfer.add_verified(100);
+    handle.completed();
+    results.recv().await.unwrap().unwrap();
+    results.recv().await.unwrap().unwrap();
+    handle.stop().await.unwrap();
+
+    let requests = requests.lock().unwrap();
+    let events = requests.iter().map(|target| query_value(target, "event")).collect::<Vec<_>>();
+    assert_eq!(events, [Some("started"), Some("started"), Some("completed"), Some("stopped")]);
+}
+
+#[tokio::test]
+async fn session_moves_on_to_the_next_tracker_of_a_tier() {
+    let (_, url) = tracker_server(ServerOptions::default()).await;
+    let mut torrent = torrent("http://127.0.0.1:1/announce");
+    torrent.announce_list = Some(vec![vec![torrent.announce.clone(), url]]);
+
+    let mut session = TrackerSession::new(&torrent, PeerId::generate(), Arc::new(Transfer::new(100)), 6881).unwrap();
+    assert_eq!(session.announce(Some(Event::Started)).await.unwrap(), []);
+}
+
+#[tokio::test]
+async fn session_keeps_the_tracker_swarm_up_to_date() {
+    let (server, url) = tracker_server(ServerOptions::default()).await;
+    let torrent = torrent(&url);
+    let info_hash = torrent.info_hash().unwrap();
+    let stats = |server: &TrackerServer| {
+        let scrape = server.scrape(&[(String::from("info_hash"), info_hash.to_vec())]);
+        let response = serde_bencode::from_bytes::<HashMap<String, HashMap<ByteBuf, ScrapeStats>>>(&scrape).unwrap();
+        response["files"].get(&ByteBuf::from(info_hash.to_vec())).copied().unwrap_or_default()
+    };
+
+    let transfer = Arc::new(Transfer::new(100));
+    let (announces, mut results) = mpsc::unbounded_channel();
+    let handle = TrackerSession::new(&torrent, PeerId::generate(), transfer.clone(), 6881).unwrap().spawn(announces);
+
+    results.recv().await.unwrap().unwrap();
+    assert_eq!(stats(&server), ScrapeStats { complete: 0, downloaded: 0, incomplete: 1 });
+
+    transfer.add_verified(100);
+    handle.completed();
+    results.recv().await.unwrap().unwrap();
+    assert_eq!(stats(&server), ScrapeStats { complete: 1, downloaded: 1, incomplete: 0 });
+
+    handle.stop().await.unwrap();
+    assert_eq!(stats(&server), ScrapeStats { complete: 0, downloaded: 1, incomplete: 0 });
+}
+
+#[test]
+fn scrape_urls_replace_announce() {
+    assert_eq!(scrape_url("http://t.example/announce").as_deref(), Some("http://t.example/scrape"));
+    assert_eq!(scrape_url("http://t.example/x/announce.php?k=1").as_deref(), Some("http://t.example/x/scrape.php?k=1"));
+    assert_eq!(scrape_url("http://t.example/a"), None);
+    assert_eq!(scrape_url("http://t.example/announce/x"), None);
+}
+
+#[tokio::test]
+async fn http_scrape_reports_served_torrents() {
+    let (_, url) = tracker_server(ServerOptions::default()).await;
+    let seeding = torrent(&url);
+    let mut leeching = torrent(&url);
+    leeching.info.name = String::from("other.bin");
+
+    let mut request = request();
+    request.info_hash = seeding.info_hash().unwrap();
+    request.left = 0;
+    TrackerClient::new(url.clone()).announce(&request).await.unwrap();
+    request.info_hash = leeching.info_hash().unwrap();
+    request.left = 100;
+    TrackerClient::new(url.clone()).announce(&request).await.unwrap();
+
+    let unknown = [9; 20];
+    let stats = scrape(&url, &[seeding.info_hash().unwrap(), leeching.info_hash().unwrap(), unknown]).await.unwrap();
+    assert_eq!(stats.len(), 2);
+    assert_eq!(stats[&seeding.info_hash().unwrap()], ScrapeStats { complete: 1, downloaded: 0, incomplete: 0 });
+    assert_eq!(stats[&leeching.info_hash().unwrap()], ScrapeStats { complete: 0, downloaded: 0, incomplete: 1 });
+}
+
+#[tokio::test]
+async fn http_scrape_needs_an_announce_url() {
+    let error = scrape("http://127.0.0.1:1/tracker", &[[1; 20]]).await.unwrap_err();
+    assert!(matches!(error.downcast_ref::<TrackerError>(), Some(TrackerError::ScrapeUnsupported(_))), "{error:#}");
+
+    let error = scrape("wss://127.0.0.1:1/announce", &[[1; 20]]).await.unwrap_err();
+    assert!(matches!(error.downcast_ref::<TrackerError>(), Some(TrackerError::ScrapeUnsupported(_))), "{error:#}");
+}
+
+/// UDP tracker answering a connect and a single scrape, with `error` instead of stats if given
+async fn fake_udp_tracker(error: Option<&'static str>) -> String {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("udp://{}", socket.local_addr().unwrap());
+
+    tokio::spawn(async move {
+        let mut buffer = [0; 2048];
+        let (read, client) = socket.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(read, 16);
+        assert_eq!(buffer[..12], [0, 0, 4, 0x17, 0x27, 0x10, 0x19, 0x80, 0, 0, 0, 0]);
+        let mut reply = vec![0, 0, 0, 0];
+        reply.extend(&buffer[12..16]);
+        reply.extend([7; 8]);
+        socket.send_to(&reply, client).await.unwrap();
+
+        let (read, client) = socket.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(buffer[..12], [7, 7, 7, 7, 7, 7, 7, 7, 0, 0, 0, 2]);
+        let mut reply = Vec::new();
+        match error {
+            Some(message) => {
+                reply.extend(3u32.to_be_bytes());
+                reply.extend(&buffer[12..16]);
+                reply.extend(message.as_bytes());
+            }
+            None => {
+                reply.extend(2u32.to_be_bytes());
+                reply.extend(&buffer[12..16]);
+                // Stats made up from the position of every info hash
+                for (index, _) in buffer[16..read].chunks_exact(20).enumerate() {
+                    for value in [index * 10 + 1, index * 10 + 2, index * 10 + 3] {
+                        reply.extend((value as u32).to_be_bytes());
+                    }
+                }
+            }
+        }
+        socket.send_to(&reply, client).await.unwrap();
+    });
+
+    url
+}
+
+#[tokio::test]
+async fn udp_scrape_connects_then_scrapes() {
+    let url = fake_udp_tracker(None).await;
+
+    let stats = scrape(&url, &[[1; 20], [2; 20]]).await.unwrap();
+    assert_eq!(stats[&[1; 20]], ScrapeStats { complete: 1, downloaded: 2, incomplete: 3 });
+    assert_eq!(stats[&[2; 20]], ScrapeStats { complete: 11, downloaded: 12, incomplete: 13 });
+}
+
+#[tokio::test]
+async fn udp_scrape_errors_become_tracker_failures() {
+    let url = fake_udp_tracker(Some("unknown torrent")).await;
+
+    let error = scrape(&url, &[[1; 20]]).await.unwrap_err();
+    match error.downcast_ref::<TrackerError>() {
+        Some(TrackerError::Failure(reason)) => assert_eq!(reason, "unknown torrent"),
+        _ => panic!("expected a tracker failure, got {error:#}"),
+    }
+}
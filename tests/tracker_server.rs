@@ -0,0 +1,170 @@
+use std::{collections::HashSet, net::{IpAddr, SocketAddr}, sync::Arc, time::Duration};
+use bittorrent::tracker::{
+    server::{ServerOptions, TrackerServer},
+    TrackerResponse,
+};
+use serde_bencode::value::Value;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}};
+
+
+/// Announce query of peer `id` for torrent `[1; 20]`, `extra` pairs are appended
+fn query(id: u8, port: u16, extra: &[(&str, &str)]) -> Vec<(String, Vec<u8>)> {
+    let mut query = vec![
+        (String::from("info_hash"), vec![1; 20]),
+        (String::from("peer_id"), vec![id; 20]),
+        (String::from("port"), port.to_string().into_bytes()),
+        (String::from("left"), b"100".to_vec()),
+        (String::from("compact"), b"1".to_vec()),
+    ];
+    for (key, value) in extra {
+        query.retain(|(known, _)| known != key);
+        query.push((key.to_string(), value.as_bytes().to_vec()));
+    }
+
+    query
+}
+
+fn localhost() -> IpAddr {
+    IpAddr::from([127, 0, 0, 1])
+}
+
+fn failure(body: &[u8]) -> Option<String> {
+    serde_bencode::from_bytes::<TrackerResponse>(body).unwrap().failure_reason
+}
+
+#[test]
+fn compact_announces_list_other_peers_by_address_family() {
+    let server = TrackerServer::new(ServerOptions::default());
+    server.announce(&query(1, 6881, &[]), localhost());
+    server.announce(&query(2, 6882, &[]), "::1".parse().unwrap());
+
+    let response = serde_bencode::from_bytes::<TrackerResponse>(&server.announce(&query(3, 6883, &[]), localhost())).unwrap();
+    assert_eq!(response.interval, 30 * 60);
+    assert_eq!(response.min_interval, Some(15 * 60));
+    assert_eq!((response.complete, response.incomplete), (Some(0), Some(3)));
+    assert_eq!(response.peers.addresses, ["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+    assert_eq!(response.peers6.addresses, ["[::1]:6882".parse::<SocketAddr>().unwrap()]);
+}
+
+#[test]
+fn dictionary_announces_carry_peer_ids_unless_asked_not_to() {
+    let server = TrackerServer::new(ServerOptions::default());
+    server.announce(&query(1, 6881, &[]), localhost());
+
+    let body = server.announce(&query(2, 6882, &[("compact", "0")]), localhost());
+    let response = serde_bencode::from_bytes::<TrackerResponse>(&body).unwrap();
+    let address = "127.0.0.1:6881".parse().unwrap();
+    assert_eq!(response.addresses(), [address]);
+    assert_eq!(response.peer_id(&address).map(|id| id.0), Some([1; 20]));
+
+    let body = server.announce(&query(2, 6882, &[("compact", "0"), ("no_peer_id", "1")]), localhost());
+    let response = serde_bencode::from_bytes::<TrackerResponse>(&body).unwrap();
+    assert_eq!(response.addresses(), [address]);
+    assert_eq!(response.peer_id(&address), None);
+}
+
+#[test]
+fn events_update_the_swarm() {
+    let server = TrackerServer::new(ServerOptions::default());
+    let counts = |body: Vec<u8>| {
+        let response = serde_bencode::from_bytes::<TrackerResponse>(&body).unwrap();
+        (response.complete.unwrap(), response.incomplete.unwrap(), response.addresses().len())
+    };
+
+    assert_eq!(counts(server.announce(&query(1, 6881, &[("event", "started")]), localhost())), (0, 1, 0));
+    assert_eq!(counts(server.announce(&query(1, 6881, &[("event", "completed"), ("left", "0")]), localhost())), (1, 0, 0));
+    assert_eq!(counts(server.announce(&query(2, 6882, &[("numwant", "0")]), localhost())), (1, 1, 0));
+    assert_eq!(counts(server.announce(&query(1, 6881, &[("event", "stopped"), ("left", "0")]), localhost())), (0, 1, 1));
+
+    let scrape = serde_bencode::from_bytes::<Value>(&server.scrape(&[])).unwrap();
+    let Value::Dict(root) = scrape else { panic!("scrape is not a dictionary") };
+    let Some(Value::Dict(files)) = root.get(b"files".as_slice()) else { panic!("scrape has no files") };
+    let Some(Value::Dict(stats)) = files.get([1; 20].as_slice()) else { panic!("torrent is not scraped") };
+    assert_eq!(stats.get(b"downloaded".as_slice()), Some(&Value::Int(1)));
+}
+
+#[test]
+fn peers_that_stop_announcing_expire() {
+    let server = TrackerServer::new(ServerOptions { interval: Duration::from_millis(20), whitelist: None });
+    server.announce(&query(1, 6881, &[]), localhost());
+    std::thread::sleep(Duration::from_millis(50));
+
+    let response = serde_bencode::from_bytes::<TrackerResponse>(&server.announce(&query(2, 6882, &[]), localhost())).unwrap();
+    assert!(response.addresses().is_empty());
+    assert_eq!(response.incomplete, Some(1));
+
+    std::thread::sleep(Duration::from_millis(50));
+    server.expire();
+    assert_eq!(server.scrape(&[]), b"d5:filesdee");
+}
+
+#[test]
+fn invalid_and_unknown_torrents_are_refused() {
+    let whitelist = HashSet::from([[2; 20]]);
+    let server = TrackerServer::new(ServerOptions { whitelist: Some(whitelist), ..Default::default() });
+
+    let body = server.announce(&query(1, 6881, &[]), localhost());
+    assert_eq!(failure(&body).as_deref(), Some("torrent is not served by this tracker"));
+    assert_eq!(server.scrape(&[(String::from("info_hash"), vec![1; 20])]), b"d5:filesdee");
+
+    let mut short = query(1, 6881, &[]);
+    short[0].1.pop();
+    let server = TrackerServer::new(ServerOptions::default());
+    assert_eq!(failure(&server.announce(&short, localhost())).as_deref(), Some("info_hash must be 20 bytes"));
+    assert_eq!(failure(&server.announce(&query(1, 6881, &[("port", "x")]), localhost())).as_deref(), Some("port is missing or invalid"));
+    assert_eq!(failure(&server.announce(&query(1, 6881, &[("numwant", "x")]), localhost())).as_deref(), Some("numwant must be a number"));
+}
+
+/// Response status line and body of a raw HTTP request
+async fn get(address: SocketAddr, request: &str) -> (String, Vec<u8>) {
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+
+    let end = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap();
+    let status = String::from_utf8_lossy(&response[..end]).lines().next().unwrap().to_string();
+    (status, response[end + 4..].to_vec())
+}
+
+#[tokio::test]
+async fn announces_and_scrapes_are_served_over_http() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(Arc::new(TrackerServer::new(ServerOptions::default())).serve(listener));
+
+    let hash = "%01".repeat(20);
+    let announce = format!("GET /announce?info_hash={hash}&peer_id={}&port=6881&left=0 HTTP/1.1\r\n\r\n", "%02".repeat(20));
+    let (status, body) = get(address, &announce).await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(failure(&body), None);
+
+    let (status, body) = get(address, &format!("GET /scrape?info_hash={hash} HTTP/1.1\r\n\r\n")).await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let mut expected = b"d5:filesd20:".to_vec();
+    expected.extend([1; 20]);
+    expected.extend(b"d8:completei1e10:downloadedi0e10:incompletei0eeee");
+    assert_eq!(body, expected);
+
+    assert_eq!(get(address, "GET /other HTTP/1.1\r\n\r\n").await.0, "HTTP/1.1 404 Not Found");
+    assert_eq!(get(address, "POST /announce HTTP/1.1\r\n\r\n").await.0, "HTTP/1.1 405 Method Not Allowed");
+}
+
+#[tokio::test]
+async fn expiry_stops_with_the_server() {
+    let server = Arc::new(TrackerServer::new(ServerOptions::default()));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let serving = tokio::spawn(server.clone().serve(listener));
+    tokio::task::yield_now().await;
+    assert!(Arc::strong_count(&server) > 1);
+
+    // Both the accept loop and the expiry task let go of the server
+    serving.abort();
+    tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        while Arc::strong_count(&server) > 1 {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .unwrap();
+}
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use bittorrent::validate::{validate_bytes, Severity};
+use serde_bencode::value::Value;
+
+
+fn bytes(value: &str) -> Value {
+    Value::Bytes(value.as_bytes().to_vec())
+}
+
+fn dict(entries: Vec<(&str, Value)>) -> HashMap<Vec<u8>, Value> {
+    entries.into_iter().map(|(key, value)| (key.as_bytes().to_vec(), value)).collect()
+}
+
+/// Valid single file info dictionary: 20000 bytes in two pieces of 16 KiB
+fn info() -> HashMap<Vec<u8>, Value> {
+    dict(vec![
+        ("name", bytes("file.bin")),
+        ("piece length", Value::Int(1 << 14)),
+        ("pieces", Value::Bytes(vec![0; 40])),
+        ("length", Value::Int(20_000)),
+    ])
+}
+
+fn metainfo(info: HashMap<Vec<u8>, Value>) -> HashMap<Vec<u8>, Value> {
+    dict(vec![("announce", bytes("http://tracker/announce")), ("info", Value::Dict(info))])
+}
+
+/// Fields and messages of every error found
+fn errors(root: &Value) -> Vec<(String, String)> {
+    let (_, report) = validate_bytes(&serde_bencode::to_bytes(root).unwrap());
+    report.errors().map(|problem| (problem.field.clone(), problem.message.clone())).collect()
+}
+
+fn error_fields(root: HashMap<Vec<u8>, Value>) -> Vec<String> {
+    errors(&Value::Dict(root)).into_iter().map(|(field, _)| field).collect()
+}
+
+fn with_info(change: impl FnOnce(&mut HashMap<Vec<u8>, Value>)) -> HashMap<Vec<u8>, Value> {
+    let mut info = info();
+    change(&mut info);
+    metainfo(info)
+}
+
+#[test]
+fn valid_metainfo_has_no_problems() {
+    let (torrent, report) = validate_bytes(&serde_bencode::to_bytes(&Value::Dict(metainfo(info()))).unwrap());
+
+    assert!(torrent.is_some());
+    assert!(report.is_valid());
+    assert!(report.problems.is_empty(), "{:?}", report.problems);
+}
+
+#[test]
+fn invalid_bencode_and_non_dictionaries_are_errors() {
+    let (torrent, report) = validate_bytes(b"d8:announce");
+    assert!(torrent.is_none());
+    assert!(report.errors().next().unwrap().message.starts_with("not valid bencode"));
+
+    assert_eq!(errors(&Value::List(Vec::new())), [(String::new(), String::from("metainfo must be a dictionary"))]);
+}
+
+#[test]
+fn required_keys_must_be_present_with_the_right_type() {
+    let mut root = metainfo(info());
+    root.remove(b"announce".as_slice());
+    assert_eq!(error_fields(root), ["announce"]);
+
+    assert_eq!(error_fields(dict(vec![("announce", bytes("http://tracker/announce"))])), ["info"]);
+    assert_eq!(error_fields(with_info(|info| { info.insert(b"name".to_vec(), Value::Int(1)); })), ["info.name"]);
+    assert_eq!(error_fields(with_info(|info| { info.remove(b"piece length".as_slice()); })), ["info.piece length"]);
+    assert_eq!(error_fields(with_info(|info| { info.remove(b"pieces".as_slice()); })), ["info.pieces"]);
+}
+
+#[test]
+fn lengths_must_not_be_negative() {
+    let root = with_info(|info| { info.insert(b"piece length".to_vec(), Value::Int(-1)); });
+    assert_eq!(errors(&Value::Dict(root)), [(String::from("info.piece length"), String::from("is negative (-1)"))]);
+
+    let root = with_info(|info| { info.insert(b"length".to_vec(), Value::Int(-5)); });
+    assert_eq!(errors(&Value::Dict(root)), [(String::from("info.length"), String::from("is negative (-5)"))]);
+
+    assert_eq!(error_fields(with_info(|info| { info.insert(b"length".to_vec(), bytes("5")); })), ["info.length"]);
+}
+
+#[test]
+fn pieces_must_be_whole_hashes_one_for_every_piece() {
+    let root = with_info(|info| { info.insert(b"pieces".to_vec(), Value::Bytes(vec![0; 30])); });
+    assert_eq!(errors(&Value::Dict(root)), [(String::from("info.pieces"), String::from("length 30 is not a multiple of 20"))]);
+
+    let root = with_info(|info| { info.insert(b"pieces".to_vec(), Value::Bytes(vec![0; 60])); });
+    assert_eq!(
+        errors(&Value::Dict(root)),
+        [(String::from("info.pieces"), String::from("has 3 hashes, but total length 20000 needs 2"))],
+    );
+
+    let root = with_info(|info| { info.insert(b"piece length".to_vec(), Value::Int(0)); });
+    assert_eq!(errors(&Value::Dict(root)), [(String::from("info.piece length"), String::from("is zero"))]);
+}
+
+#[test]
+fn either_length_or_files_is_required() {
+    let root = with_info(|info| { info.insert(b"files".to_vec(), Value::List(Vec::new())); });
+    assert_eq!(errors(&Value::Dict(root)), [(String::from("info"), String::from("has both `length` and `files`"))]);
+
+    let root = with_info(|info| { info.remove(b"length".as_slice()); });
+    assert_eq!(errors(&Value::Dict(root)), [(String::from("info"), String::from("has neither `length` nor `files`"))]);
+}
+
+#[test]
+fn every_file_needs_a_length_and_a_path() {
+    let files = |files: Value| with_info(|info| {
+        info.remove(b"length".as_slice());
+        info.insert(b"files".to_vec(), files);
+    });
+
+    assert_eq!(error_fields(files(bytes("files"))), ["info.files"]);
+
+    let root = files(Value::List(vec![
+        Value::Int(1),
+        Value::Dict(dict(vec![("path", Value::List(vec![bytes("a")]))])),
+        Value::Dict(dict(vec![("length", Value::Int(1)), ("path", bytes("a/b"))])),
+        Value::Dict(dict(vec![("length", Value::Int(-1)), ("path", Value::List(vec![Value::Int(1)]))])),
+    ]));
+    assert_eq!(
+        error_fields(root),
+        ["info.files[0]", "info.files[1].length", "info.files[2].path", "info.files[3].length", "info.files[3].path"],
+    );
+}
+
+#[test]
+fn parsed_metainfo_must_describe_some_content() {
+    let empty = with_info(|info| {
+        info.remove(b"length".as_slice());
+        info.insert(b"files".to_vec(), Value::List(Vec::new()));
+        info.insert(b"name".to_vec(), bytes(""));
+        info.insert(b"pieces".to_vec(), Value::Bytes(Vec::new()));
+    });
+    assert_eq!(error_fields(empty), ["info.name", "info.files", "info"]);
+}
+
+#[test]
+fn values_serde_can_not_read_are_errors() {
+    let root = with_info(|info| { info.insert(b"private".to_vec(), bytes("yes")); });
+    let errors = errors(&Value::Dict(root));
+
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].1.starts_with("can't be parsed"), "{errors:?}");
+}
+
+#[test]
+fn unusual_values_are_only_warnings() {
+    let mut root = with_info(|info| {
+        info.insert(b"piece length".to_vec(), Value::Int(10_000));
+        info.insert(b"private".to_vec(), Value::Int(2));
+        info.insert(b"name".to_vec(), bytes("../escape"));
+    });
+    root.insert(b"announce".to_vec(), bytes("ftp://tracker"));
+    root.insert(b"creation date".to_vec(), Value::Int(-1));
+
+    let (torrent, report) = validate_bytes(&serde_bencode::to_bytes(&Value::Dict(root)).unwrap());
+    assert!(torrent.is_some());
+    assert!(report.is_valid());
+    assert!(report.problems.iter().all(|problem| problem.severity == Severity::Warning));
+
+    let fields = report.warnings().map(|problem| problem.field.as_str()).collect::<Vec<_>>();
+    assert_eq!(fields, ["info.piece length", "info.piece length", "info.private", "announce", "creation date", "info.name"]);
+}
@@ -0,0 +1,86 @@
+use std::path::{Path, PathBuf};
+use bittorrent::{
+    torrent::Torrent,
+    verify::verify,
+};
+
+mod common;
+
+
+const PIECE_LENGTH: usize = 1 << 14;
+
+/// Directory `pack` with files of 1.5, 0.25 and 2 pieces, so pieces 1 and 2 span file boundaries
+fn pack(directory: &Path) -> (PathBuf, Torrent) {
+    let pack = directory.join("pack");
+    std::fs::create_dir_all(pack.join("sub")).unwrap();
+    std::fs::write(pack.join("a.bin"), vec![1; PIECE_LENGTH * 3 / 2]).unwrap();
+    std::fs::write(pack.join("b.bin"), vec![2; PIECE_LENGTH / 4]).unwrap();
+    std::fs::write(pack.join("sub/c.bin"), vec![3; PIECE_LENGTH * 2]).unwrap();
+
+    let torrent = common::torrent_of(&pack, PIECE_LENGTH);
+
+    (pack, torrent)
+}
+
+#[test]
+fn intact_content_is_complete() {
+    let directory = tempfile::tempdir().unwrap();
+    let (pack, torrent) = pack(directory.path());
+
+    let report = verify(&torrent, &pack).unwrap();
+    assert_eq!((report.pieces, report.valid, report.bad, report.missing), (4, 4, 0, 0));
+    assert!(report.is_complete());
+    assert!(report.files.is_empty());
+}
+
+#[test]
+fn corrupt_bytes_fail_the_pieces_they_are_in() {
+    let directory = tempfile::tempdir().unwrap();
+    let (pack, torrent) = pack(directory.path());
+
+    // Last byte of b.bin sits in piece 1, which also holds the tail of a.bin and the head of sub/c.bin
+    let mut b = std::fs::read(pack.join("b.bin")).unwrap();
+    *b.last_mut().unwrap() = 0;
+    std::fs::write(pack.join("b.bin"), b).unwrap();
+
+    let report = verify(&torrent, &pack).unwrap();
+    assert_eq!((report.valid, report.bad, report.missing), (3, 1, 0));
+
+    let files = report.files.iter().map(|file| (file.index, file.bad_pieces.clone())).collect::<Vec<_>>();
+    assert_eq!(files, [(0, vec![1]), (1, vec![1]), (2, vec![1])]);
+}
+
+#[test]
+fn absent_and_short_files_make_their_pieces_missing() {
+    let directory = tempfile::tempdir().unwrap();
+    let (pack, torrent) = pack(directory.path());
+
+    std::fs::remove_file(pack.join("a.bin")).unwrap();
+    std::fs::write(pack.join("sub/c.bin"), vec![3; PIECE_LENGTH * 3 / 2]).unwrap();
+
+    let report = verify(&torrent, &pack).unwrap();
+    assert_eq!((report.valid, report.bad, report.missing), (1, 0, 3));
+
+    let a = &report.files[0];
+    assert_eq!((a.index, a.exists, a.missing_pieces.clone()), (0, false, vec![0, 1]));
+    let c = report.files.iter().find(|file| file.index == 2).unwrap();
+    assert!(c.exists);
+    assert_eq!(c.missing_pieces, [1, 3]);
+}
+
+#[test]
+fn single_file_torrents_are_verified_at_the_file_itself() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("single.bin");
+    std::fs::write(&path, vec![7; PIECE_LENGTH + 10]).unwrap();
+
+    let torrent = common::torrent_of(&path, PIECE_LENGTH);
+    assert!(verify(&torrent, &path).unwrap().is_complete());
+
+    let report = verify(&torrent, &directory.path().join("elsewhere.bin")).unwrap();
+    assert_eq!((report.missing, report.files[0].exists), (2, false));
+
+    // JSON output mode serializes the same report
+    let json = serde_json::to_value(&report).unwrap();
+    assert_eq!(json["files"][0]["missing_pieces"], serde_json::json!([0, 1]));
+}